@@ -0,0 +1,428 @@
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+
+use crate::components::*;
+use bevy::{
+    core_pipeline::{draw_3d_graph, node, AlphaMask3d, Opaque3d, Transparent3d},
+    prelude::*,
+    render::{
+        camera::{ActiveCamera, CameraTypePlugin, RenderTarget},
+        render_asset::RenderAssets,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotValue},
+        render_phase::RenderPhase,
+        render_resource::{
+            Buffer, BufferDescriptor, BufferUsages, Extent3d, ImageCopyBuffer, ImageDataLayout,
+            MapMode, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::{RenderContext, RenderDevice},
+        RenderApp, RenderStage,
+    },
+    tasks::IoTaskPool,
+};
+use bevy_inspector_egui::Inspectable;
+
+/// Recording of the generation to a sequence of PNG images
+pub struct CapturePlugin;
+
+impl Plugin for CapturePlugin {
+    fn build(&self, app: &mut App) {
+        let capture = FrameCapture::default();
+        let frames = capture.frames.clone();
+        app.add_plugin(CameraTypePlugin::<CaptureCamera>::default())
+            .insert_resource(capture)
+            .add_system(toggle_capture)
+            .add_system(request_frames)
+            .add_system(save_frames);
+
+        // Render the capture camera like the minimap one, and copy its image back after
+        let render_app = app.sub_app_mut(RenderApp);
+        let driver = CaptureCameraDriver::new(&mut render_app.world);
+        render_app
+            .insert_resource(CapturedFrames(frames))
+            .init_resource::<PendingReadback>()
+            .add_system_to_stage(RenderStage::Extract, extract_capture)
+            .add_system_to_stage(RenderStage::Prepare, prepare_readback)
+            .add_system_to_stage(RenderStage::Cleanup, read_back);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(CAPTURE_PASS_DRIVER, driver);
+        graph.add_node(CAPTURE_READBACK, CaptureReadbackNode);
+        graph
+            .add_node_edge(node::MAIN_PASS_DEPENDENCIES, CAPTURE_PASS_DRIVER)
+            .unwrap();
+        graph
+            .add_node_edge(node::CLEAR_PASS_DRIVER, CAPTURE_PASS_DRIVER)
+            .unwrap();
+        graph
+            .add_node_edge(CAPTURE_PASS_DRIVER, CAPTURE_READBACK)
+            .unwrap();
+        graph
+            .add_node_edge(CAPTURE_READBACK, node::MAIN_PASS_DRIVER)
+            .unwrap();
+    }
+}
+
+const CAPTURE_PASS_DRIVER: &str = "capture_pass_driver";
+const CAPTURE_READBACK: &str = "capture_readback";
+
+/// Directory receiving the captured frames
+const CAPTURE_DIRECTORY: &str = "capture";
+
+/// Frame rendered by the capture camera, in RGBA
+struct CapturedFrame {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+/// Recording of the generation, started and stopped with F7
+///
+/// A frame is captured every `interval` collapse steps rather than at a fixed rate,
+/// so that the recording doesn't depend on the generation speed.
+#[derive(Inspectable)]
+pub struct FrameCapture {
+    #[inspectable(label = "steps per frame", min = 1)]
+    pub interval: usize,
+
+    #[inspectable(ignore)]
+    pub recording: bool,
+
+    /// Collapse steps since the last captured frame
+    #[inspectable(ignore)]
+    pub steps: usize,
+
+    /// Frames written so far, numbering the next one
+    #[inspectable(ignore)]
+    pub saved: usize,
+
+    /// Capture the next rendered frame, taken by the render world
+    #[inspectable(ignore)]
+    requested: bool,
+
+    #[inspectable(ignore)]
+    image: Option<Handle<Image>>,
+
+    /// Frames read back by the render world, waiting to be written
+    #[inspectable(ignore)]
+    frames: Arc<Mutex<Vec<CapturedFrame>>>,
+}
+
+impl Default for FrameCapture {
+    fn default() -> Self {
+        Self {
+            interval: 1,
+            recording: false,
+            steps: 0,
+            saved: 0,
+            requested: false,
+            image: None,
+            frames: Default::default(),
+        }
+    }
+}
+
+/// Camera rendering the main view to the capture image
+#[derive(Component, Default)]
+pub struct CaptureCamera;
+
+/// Frames shared with the render world
+struct CapturedFrames(Arc<Mutex<Vec<CapturedFrame>>>);
+
+/// Image to read back this frame, in the render world
+struct ExtractedCapture(Option<Handle<Image>>);
+
+/// Buffer receiving the capture image, in the render world
+#[derive(Default)]
+struct PendingReadback(Option<Readback>);
+
+struct Readback {
+    image: Handle<Image>,
+    buffer: Buffer,
+    size: Extent3d,
+    /// Bytes of a row in the buffer, padded to the copy alignment
+    padded_bytes_per_row: usize,
+}
+
+/// Start recording with F7, or stop early
+fn toggle_capture(
+    mut commands: Commands,
+    keyboard_input: Res<Input<KeyCode>>,
+    windows: Res<Windows>,
+    mut images: ResMut<Assets<Image>>,
+    mut capture: ResMut<FrameCapture>,
+    camera_query: Query<(Entity, &PerspectiveProjection), With<MainCameraTag>>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::F7) {
+        return;
+    }
+    if capture.recording {
+        capture.recording = false;
+        return;
+    }
+    let (window, (camera, projection)) = match (windows.get_primary(), camera_query.get_single()) {
+        (Some(window), Ok(camera)) => (window, camera),
+        _ => return,
+    };
+    if let Err(e) = std::fs::create_dir_all(CAPTURE_DIRECTORY) {
+        warn!("Failed to create {}: {}", CAPTURE_DIRECTORY, e);
+        return;
+    }
+
+    let size = Extent3d {
+        width: window.physical_width(),
+        height: window.physical_height(),
+        ..default()
+    };
+    let mut image = Image {
+        texture_descriptor: TextureDescriptor {
+            label: Some("capture"),
+            size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::COPY_SRC
+                | TextureUsages::COPY_DST
+                | TextureUsages::RENDER_ATTACHMENT,
+        },
+        ..default()
+    };
+    image.resize(size);
+    let image = images.add(image);
+
+    // Following the main camera, with the same view
+    commands.entity(camera).with_children(|camera| {
+        camera
+            .spawn_bundle(PerspectiveCameraBundle::<CaptureCamera> {
+                camera: Camera {
+                    target: RenderTarget::Image(image.clone()),
+                    near: projection.near,
+                    far: projection.far,
+                    ..default()
+                },
+                perspective_projection: projection.clone(),
+                ..PerspectiveCameraBundle::new()
+            })
+            .insert(Name::from("capture_camera"));
+    });
+
+    info!("Recording the generation to {}", CAPTURE_DIRECTORY);
+    *capture = FrameCapture {
+        interval: capture.interval,
+        recording: true,
+        requested: true,
+        image: Some(image),
+        frames: capture.frames.clone(),
+        ..default()
+    };
+}
+
+/// Capture a frame every few collapse steps, and the last one once the generation stops
+fn request_frames(
+    mut commands: Commands,
+    focus: Res<FocusCell>,
+    state: Res<GenerationState>,
+    mut capture: ResMut<FrameCapture>,
+    camera_query: Query<Entity, With<CaptureCamera>>,
+) {
+    if !capture.recording {
+        if capture.image.is_some() {
+            capture.image = None;
+            capture.requested = false;
+            for entity in camera_query.iter() {
+                commands.entity(entity).despawn_recursive();
+            }
+            info!("Stopped recording to {}", CAPTURE_DIRECTORY);
+        }
+        return;
+    }
+
+    if focus.is_changed() && focus.0.is_some() {
+        capture.steps += 1;
+        if capture.steps >= capture.interval {
+            capture.steps = 0;
+            capture.requested = true;
+        }
+    }
+    if state.is_changed() && state.idle {
+        capture.requested = true;
+        capture.recording = false;
+    }
+}
+
+/// Write the frames read back from the render world, numbered in order
+fn save_frames(mut capture: ResMut<FrameCapture>, io_pool: Res<IoTaskPool>) {
+    let frames: Vec<CapturedFrame> = capture.frames.lock().unwrap().drain(..).collect();
+    for frame in frames {
+        let path = format!("{}/frame_{:05}.png", CAPTURE_DIRECTORY, capture.saved);
+        capture.saved += 1;
+        io_pool
+            .spawn(async move {
+                if let Err(e) = image::save_buffer(
+                    &path,
+                    &frame.data,
+                    frame.width,
+                    frame.height,
+                    image::ColorType::Rgba8,
+                ) {
+                    warn!("Failed to save {}: {}", path, e);
+                }
+            })
+            .detach();
+    }
+}
+
+fn extract_capture(
+    mut commands: Commands,
+    active: Res<ActiveCamera<CaptureCamera>>,
+    mut capture: ResMut<FrameCapture>,
+) {
+    let requested = match capture.requested {
+        true => {
+            capture.requested = false;
+            capture.image.clone()
+        }
+        false => None,
+    };
+    commands.insert_resource(ExtractedCapture(requested));
+    if let Some(entity) = active.get() {
+        commands.get_or_spawn(entity).insert_bundle((
+            RenderPhase::<Opaque3d>::default(),
+            RenderPhase::<AlphaMask3d>::default(),
+            RenderPhase::<Transparent3d>::default(),
+        ));
+    }
+}
+
+/// Create the buffer receiving the requested frame
+fn prepare_readback(
+    capture: Res<ExtractedCapture>,
+    images: Res<RenderAssets<Image>>,
+    render_device: Res<RenderDevice>,
+    mut pending: ResMut<PendingReadback>,
+) {
+    pending.0 = None;
+    let image = match &capture.0 {
+        Some(image) => image,
+        None => return,
+    };
+    let gpu_image = match images.get(image) {
+        Some(gpu_image) => gpu_image,
+        None => return,
+    };
+
+    let size = Extent3d {
+        width: gpu_image.size.width as u32,
+        height: gpu_image.size.height as u32,
+        ..default()
+    };
+    let padded_bytes_per_row = RenderDevice::align_copy_bytes_per_row(size.width as usize * 4);
+    let buffer = render_device.create_buffer(&BufferDescriptor {
+        label: Some("capture_readback"),
+        size: (padded_bytes_per_row * size.height as usize) as u64,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    pending.0 = Some(Readback {
+        image: image.clone(),
+        buffer,
+        size,
+        padded_bytes_per_row,
+    });
+}
+
+/// Wait for the copy of the capture image, and hand it to the main world
+fn read_back(
+    render_device: Res<RenderDevice>,
+    mut pending: ResMut<PendingReadback>,
+    frames: Res<CapturedFrames>,
+) {
+    let readback = match pending.0.take() {
+        Some(readback) => readback,
+        None => return,
+    };
+
+    let slice = readback.buffer.slice(..);
+    render_device.map_buffer(&slice, MapMode::Read);
+    let row_bytes = readback.size.width as usize * 4;
+    let mut data = Vec::with_capacity(row_bytes * readback.size.height as usize);
+    for row in slice
+        .get_mapped_range()
+        .chunks(readback.padded_bytes_per_row)
+    {
+        // BGRA to RGBA
+        for pixel in row[..row_bytes].chunks(4) {
+            data.extend_from_slice(&[pixel[2], pixel[1], pixel[0], pixel[3]]);
+        }
+    }
+    readback.buffer.unmap();
+
+    frames.0.lock().unwrap().push(CapturedFrame {
+        width: readback.size.width,
+        height: readback.size.height,
+        data,
+    });
+}
+
+/// Run the 3D graph for the capture camera
+struct CaptureCameraDriver {
+    query: QueryState<Entity, With<CaptureCamera>>,
+}
+
+impl CaptureCameraDriver {
+    pub fn new(render_world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(render_world),
+        }
+    }
+}
+
+impl Node for CaptureCameraDriver {
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        _render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        for camera in self.query.iter_manual(world) {
+            graph.run_sub_graph(draw_3d_graph::NAME, vec![SlotValue::Entity(camera)])?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the rendered capture image to the readback buffer
+struct CaptureReadbackNode;
+
+impl Node for CaptureReadbackNode {
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let readback = match &world.resource::<PendingReadback>().0 {
+            Some(readback) => readback,
+            None => return Ok(()),
+        };
+        if let Some(gpu_image) = world.resource::<RenderAssets<Image>>().get(&readback.image) {
+            render_context.command_encoder.copy_texture_to_buffer(
+                gpu_image.texture.as_image_copy(),
+                ImageCopyBuffer {
+                    buffer: &readback.buffer,
+                    layout: ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: NonZeroU32::new(readback.padded_bytes_per_row as u32),
+                        rows_per_image: None,
+                    },
+                },
+                readback.size,
+            );
+        }
+        Ok(())
+    }
+}
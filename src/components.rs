@@ -1,21 +1,53 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use bevy::{ecs::event::Events, prelude::*};
 use bevy_inspector_egui::{Inspectable, RegisterInspectable};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use serde::{Deserialize, Serialize};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 pub struct ComponentsPlugin;
 
 impl Plugin for ComponentsPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Events::<RulesNeedUpdateEvent>::default())
+            .add_event::<RegenerateEvent>()
+            .add_event::<FinalizeEvent>()
+            .add_event::<FrameMapEvent>()
+            .add_event::<RerollLastEvent>()
+            .add_event::<StampRuleMapEvent>()
+            .add_event::<SetTileEvent>()
+            .add_event::<ClearRulesEvent>()
+            .add_event::<ResizeMapEvent>()
             .init_resource::<ModelAssets>()
             .init_resource::<TileSelection>()
+            .init_resource::<HoverRotation>()
+            .init_resource::<AdjacencyDrag>()
+            .init_resource::<GenerationRng>()
+            .init_resource::<GenerationInfo>()
+            .insert_resource(TileBudget::load())
             .init_resource::<Rules>()
-            .init_resource::<Tuning>()
+            .insert_resource(Tuning::load().unwrap_or_default())
             .init_resource::<GuessHistory>()
+            .init_resource::<FrozenCells>()
+            .init_resource::<RuleEditHistory>()
+            .init_resource::<MapEditHistory>()
+            .init_resource::<BoxSelection>()
+            .init_resource::<FocusCell>()
+            .init_resource::<CursorCell>()
+            .init_resource::<RuleDiagnostics>()
+            .init_resource::<RuleProvenance>()
+            .init_resource::<CollapseStats>()
+            .init_resource::<TileHistogram>()
+            .init_resource::<PropagationProfile>()
+            .init_resource::<BannedTiles>()
+            .init_resource::<GenerationState>()
+            .init_resource::<AutoRetry>()
+            .init_resource::<WaveReplay>()
+            .init_resource::<OrientationMapping>()
+            .init_resource::<UiLayout>()
+            .register_type::<GeneratedTile>()
             .register_inspectable::<Tuning>()
             .register_inspectable::<Coordinates>()
             .register_inspectable::<RuleTileTag>()
@@ -23,6 +55,10 @@ impl Plugin for ComponentsPlugin {
             .register_inspectable::<Orientation>()
             .register_inspectable::<Tile>()
             .register_inspectable::<OptionalTile>();
+
+        // No file system on the web
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_system(save_tuning);
     }
 }
 
@@ -30,11 +66,33 @@ pub struct ModelAssets {
     pub up_cube_mesh: Handle<Mesh>,
     pub up_cube_mat: Handle<StandardMaterial>,
     pub undecided_mesh: Handle<Mesh>,
+    /// Unit column standing on the cell, scaled to the entropy of the undecided cells
+    pub entropy_column_mesh: Handle<Mesh>,
+    /// Grey levels for the entropy of the undecided cells, from dark to light
     pub undecided_mats: Vec<Handle<StandardMaterial>>,
+    /// Perceptually uniform alternative to the grey levels
+    pub undecided_color_mats: Vec<Handle<StandardMaterial>>,
     pub impossible_mesh: Handle<Mesh>,
     pub impossible_mat: Handle<StandardMaterial>,
+    /// Neutral cell, while there are no rules to generate anything
+    pub no_rules_mat: Handle<StandardMaterial>,
+    /// Placeholder of the tiles whose prototype is not in the palette
+    pub missing_mesh: Handle<Mesh>,
+    pub missing_mat: Handle<StandardMaterial>,
     pub pick_mesh: Handle<Mesh>,
     pub pick_mat: Handle<StandardMaterial>,
+    pub focus_mesh: Handle<Mesh>,
+    pub focus_mat: Handle<StandardMaterial>,
+    pub frontier_mat: Handle<StandardMaterial>,
+    pub cursor_mat: Handle<StandardMaterial>,
+    pub grid_mat: Handle<StandardMaterial>,
+    pub cell_pick_mat: Handle<StandardMaterial>,
+    pub font: Handle<Font>,
+
+    /// Translucent variants of the tile materials, created on demand
+    pub ghost_mats: HashMap<Handle<StandardMaterial>, Handle<StandardMaterial>>,
+    /// Pulsing variants of the materials of the animated tiles, created on demand
+    pub animated_mats: HashMap<Handle<StandardMaterial>, Handle<StandardMaterial>>,
 }
 
 impl FromWorld for ModelAssets {
@@ -42,8 +100,21 @@ impl FromWorld for ModelAssets {
         let mut meshes = world.get_resource_mut::<Assets<Mesh>>().unwrap();
         let up_cube_mesh = meshes.add(shape::Cube { size: 0.1 }.into());
         let undecided_mesh = meshes.add(shape::Plane { size: 1.0 }.into());
+        let entropy_column_mesh = meshes.add(
+            shape::Box {
+                min_x: -0.45,
+                max_x: 0.45,
+                min_y: 0.0,
+                max_y: 1.0,
+                min_z: -0.45,
+                max_z: 0.45,
+            }
+            .into(),
+        );
         let impossible_mesh = meshes.add(shape::Plane { size: 1.0 }.into());
+        let missing_mesh = meshes.add(shape::Cube { size: 0.5 }.into());
         let pick_mesh = meshes.add(Mesh::from(shape::Plane { size: 1.0 }));
+        let focus_mesh = meshes.add(shape::Plane { size: 1.0 }.into());
 
         let mut materials = world
             .get_resource_mut::<Assets<StandardMaterial>>()
@@ -51,41 +122,165 @@ impl FromWorld for ModelAssets {
         let up_cube_mat = materials.add(Color::RED.into());
 
         let mut undecided_mats = Vec::new();
+        let mut undecided_color_mats = Vec::new();
         for i in 0..100 {
             let level = i as f32 / 100.0;
             undecided_mats.push(materials.add(Color::rgb(level, level, level).into()));
+            undecided_color_mats.push(materials.add(viridis(level).into()));
         }
         let impossible_mat = materials.add(Color::RED.into());
+        let no_rules_mat = materials.add(Color::rgb(0.3, 0.3, 0.3).into());
+        let missing_mat = materials.add(StandardMaterial {
+            base_color: Color::FUCHSIA,
+            unlit: true,
+            ..Default::default()
+        });
         let pick_mat = materials.add(StandardMaterial {
             base_color: Color::WHITE,
             ..Default::default()
         });
+        let focus_mat = materials.add(StandardMaterial {
+            base_color: Color::rgba(0.0, 1.0, 1.0, 0.5),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..Default::default()
+        });
+        let frontier_mat = materials.add(StandardMaterial {
+            base_color: Color::rgba(1.0, 0.5, 0.0, 0.5),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..Default::default()
+        });
+        let cursor_mat = materials.add(StandardMaterial {
+            base_color: Color::rgba(1.0, 1.0, 0.0, 0.6),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..Default::default()
+        });
+        let grid_mat = materials.add(StandardMaterial {
+            base_color: Color::rgb(0.1, 0.1, 0.1),
+            unlit: true,
+            ..Default::default()
+        });
+        // Invisible, only there for the picking
+        let cell_pick_mat = materials.add(StandardMaterial {
+            base_color: Color::NONE,
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..Default::default()
+        });
+
+        let asset_server = world.get_resource::<AssetServer>().unwrap();
+        let font = asset_server.load("fonts/FiraMono-Medium.ttf");
 
         Self {
             up_cube_mesh,
             up_cube_mat,
             undecided_mesh,
+            entropy_column_mesh,
             undecided_mats,
+            undecided_color_mats,
             impossible_mesh,
             impossible_mat,
+            no_rules_mat,
+            missing_mesh,
+            missing_mat,
             pick_mesh,
             pick_mat,
+            focus_mesh,
+            focus_mat,
+            frontier_mat,
+            cursor_mat,
+            grid_mat,
+            cell_pick_mat,
+            font,
+            ghost_mats: HashMap::new(),
+            animated_mats: HashMap::new(),
         }
     }
 }
 
+/// Approximation of the viridis color map, for `level` between 0 and 1
+fn viridis(level: f32) -> Color {
+    const STOPS: [[f32; 3]; 5] = [
+        [0.267, 0.005, 0.329],
+        [0.231, 0.322, 0.545],
+        [0.129, 0.569, 0.549],
+        [0.369, 0.788, 0.384],
+        [0.992, 0.906, 0.145],
+    ];
+    let position = level.clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let index = (position as usize).min(STOPS.len() - 2);
+    let (from, to) = (Vec3::from(STOPS[index]), Vec3::from(STOPS[index + 1]));
+    let color = from.lerp(to, position - index as f32);
+    Color::rgb(color.x, color.y, color.z)
+}
+
 #[derive(Component, Inspectable, Default)]
 pub struct CameraHoldTag;
 
 #[derive(Component, Inspectable, Default)]
 pub struct RuleMapTag;
 
+/// Model of a palette element, turned with the orientation mapping
+#[derive(Component, Default)]
+pub struct PaletteModelTag;
+
+/// Parent of the rule tiles, inside the editor UI
+#[derive(Component, Default)]
+pub struct RuleMapRootTag;
+
+#[derive(Component, Default)]
+pub struct WorldMapTag;
+
+impl WorldMapTag {
+    /// Transform of the generated map, centered on the origin
+    pub fn transform(width: usize, height: usize, tile_scale: f32) -> Transform {
+        Transform::from_xyz(
+            -((width / 2) as f32) * tile_scale,
+            0.0,
+            -((height / 2) as f32) * tile_scale,
+        )
+        .with_scale(Vec3::splat(tile_scale))
+    }
+}
+
+#[derive(Component, Default)]
+pub struct GridTag;
+
+/// Camera of the 3D view, a child of the `CameraHoldTag` entity
+#[derive(Component, Default)]
+pub struct MainCameraTag;
+
+impl MainCameraTag {
+    /// Angle of the camera below the horizon
+    const PITCH: f32 = 0.3218;
+
+    /// Transform of the camera framing the whole generated map, centered on its holder
+    ///
+    /// The bounding circle of the map fits in the vertical field of view, `fov`, which
+    /// keeps the map framed whichever way the holder spins.
+    pub fn framing(width: usize, height: usize, tile_scale: f32, fov: f32) -> Transform {
+        let radius = Vec2::new(width as f32, height as f32).length() / 2.0 * tile_scale;
+        let distance = radius / (fov / 2.0).sin();
+        let direction = Vec3::new(0.0, Self::PITCH.sin(), Self::PITCH.cos());
+        Transform::from_translation(direction * distance).looking_at(Vec3::ZERO, Vec3::Y)
+    }
+}
+
 /// Variation of the palette elements that are equivalents
+///
+/// Tiles invariant by rotation are assumed to be mirror symmetric too,
+/// tiles without equivalences are chiral.
 #[derive(Component, Inspectable, Clone, Copy, PartialEq, Hash, Eq, Debug)]
 pub enum Equivalences {
     None,
     HalfTurn,
     QuarterTurn,
+    /// Symmetric across its North-South axis
+    Mirror,
+    /// Only placed in the orientation it was authored in, never rotated nor mirrored
+    Fixed,
 }
 
 impl Default for Equivalences {
@@ -94,8 +289,66 @@ impl Default for Equivalences {
     }
 }
 
+/// How the entropy of the undecided cells is shown in the generated map
+#[derive(Inspectable, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub enum EntropyDisplay {
+    /// Flat cells colored by their entropy
+    #[default]
+    Color,
+    /// Colored columns as tall as their entropy
+    Height,
+}
+
+impl EntropyDisplay {
+    /// Height of the column of a cell with every candidate
+    const MAX_COLUMN_HEIGHT: f32 = 2.0;
+
+    /// Height of the column of a cell, flat once resolved
+    pub fn column_height(entropy: usize, max_entropy: usize) -> f32 {
+        let level = entropy.saturating_sub(1) as f32 / max_entropy.saturating_sub(1).max(1) as f32;
+        level.min(1.0) * Self::MAX_COLUMN_HEIGHT
+    }
+}
+
+/// How the next cell to observe is chosen
+#[derive(Inspectable, Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize, Default)]
+pub enum CollapseStrategy {
+    #[default]
+    MinEntropy,
+    Scanline,
+    SpiralFromCenter,
+}
+
+impl CollapseStrategy {
+    /// Observation priority of a cell for the position based strategies, lowest first
+    pub fn priority(&self, coordinates: &Coordinates, width: usize, height: usize) -> f32 {
+        match self {
+            CollapseStrategy::MinEntropy => 0.0,
+            CollapseStrategy::Scanline => (coordinates.y * width as i32 + coordinates.x) as f32,
+            CollapseStrategy::SpiralFromCenter => {
+                let dx = coordinates.x as f32 - (width as f32 - 1.0) / 2.0;
+                let dy = coordinates.y as f32 - (height as f32 - 1.0) / 2.0;
+                let ring = dx.abs().max(dy.abs()).round();
+                let angle = dy.atan2(dx).rem_euclid(std::f32::consts::TAU);
+                ring + angle / std::f32::consts::TAU
+            }
+        }
+    }
+}
+
 #[derive(
-    Inspectable, Clone, Copy, PartialEq, FromPrimitive, Hash, Eq, Debug, Serialize, Deserialize,
+    Inspectable,
+    Clone,
+    Copy,
+    PartialEq,
+    FromPrimitive,
+    Hash,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Serialize,
+    Deserialize,
 )]
 pub enum Orientation {
     North = 0,
@@ -122,6 +375,92 @@ impl From<Orientation> for Quat {
     }
 }
 
+/// World axis the grid North points to
+///
+/// The grid North is `y + 1` in the coordinates, see `Orientation::offset`, and the other
+/// orientations follow clockwise seen from above. From the default camera position,
+/// `PositiveZ` puts the North at the bottom of the screen and `NegativeZ` at the top.
+#[derive(Inspectable, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum AxisMapping {
+    #[default]
+    PositiveZ,
+    /// The whole grid turned around, its cells and models alike
+    NegativeZ,
+}
+
+impl AxisMapping {
+    fn turned(&self) -> bool {
+        *self == AxisMapping::NegativeZ
+    }
+}
+
+/// How the tile models are turned toward the orientations, to match the way they were
+/// authored
+///
+/// The rules only know about the grid: `Orientation::offset` always gives the same
+/// neighbours, and the mapping is applied when drawing.
+#[derive(Inspectable, Clone, Copy, PartialEq)]
+pub struct OrientationMapping {
+    /// Rotation of the North models around the vertical axis
+    #[inspectable(label = "base rotation (degrees)", min = -180.0, max = 180.0)]
+    pub base_rotation: f32,
+
+    /// The models turn the other way from one orientation to the next, and the grids
+    /// are drawn mirrored to stay consistent, for the tilesets of the other handedness
+    #[inspectable(label = "reversed handedness")]
+    pub reversed: bool,
+
+    #[inspectable(label = "north towards")]
+    pub north: AxisMapping,
+}
+
+impl Default for OrientationMapping {
+    fn default() -> Self {
+        Self {
+            base_rotation: 0.0,
+            reversed: false,
+            north: AxisMapping::default(),
+        }
+    }
+}
+
+impl OrientationMapping {
+    /// Rotation of a model facing an orientation
+    pub fn rotation(&self, orientation: Orientation) -> Quat {
+        let rotation = match self.reversed {
+            true => Quat::from(orientation).inverse(),
+            false => Quat::from(orientation),
+        };
+        let turn = match self.north.turned() {
+            true => Quat::from(Orientation::South),
+            false => Quat::IDENTITY,
+        };
+        turn * rotation * Quat::from_rotation_y(self.base_rotation.to_radians())
+    }
+
+    /// Position of a cell in a grid of the given size
+    pub fn translation(&self, coordinates: &Coordinates, width: usize, height: usize) -> Vec3 {
+        let (mut x, mut z) = (coordinates.x, coordinates.y);
+        if self.reversed {
+            x = width as i32 - 1 - x;
+        }
+        if self.north.turned() {
+            x = width as i32 - 1 - x;
+            z = height as i32 - 1 - z;
+        }
+        Vec3::new(x as f32, 0.0, z as f32)
+    }
+
+    /// Grid step moving the cursor by a step along the world x and z axes
+    pub fn grid_step(&self, dx: i32, dz: i32) -> (i32, i32) {
+        let flip = |flipped: bool, d: i32| if flipped { -d } else { d };
+        (
+            flip(self.reversed != self.north.turned(), dx),
+            flip(self.north.turned(), dz),
+        )
+    }
+}
+
 impl Orientation {
     pub fn values() -> [Orientation; 4] {
         [
@@ -142,6 +481,39 @@ impl Orientation {
         ret
     }
 
+    /// Reflection across the North-South axis
+    pub fn mirrored(&self) -> Self {
+        match self {
+            Orientation::East => Orientation::West,
+            Orientation::West => Orientation::East,
+            orientation => *orientation,
+        }
+    }
+
+    /// Diagonal cells, as pairs of successive orientations
+    pub fn diagonals() -> [(Orientation, Orientation); 4] {
+        [
+            (Orientation::North, Orientation::East),
+            (Orientation::East, Orientation::South),
+            (Orientation::South, Orientation::West),
+            (Orientation::West, Orientation::North),
+        ]
+    }
+
+    /// Cells two steps away, as pairs of successive orientations
+    pub fn second_ring() -> [(Orientation, Orientation); 8] {
+        [
+            (Orientation::North, Orientation::North),
+            (Orientation::East, Orientation::East),
+            (Orientation::South, Orientation::South),
+            (Orientation::West, Orientation::West),
+            (Orientation::North, Orientation::East),
+            (Orientation::East, Orientation::South),
+            (Orientation::South, Orientation::West),
+            (Orientation::West, Orientation::North),
+        ]
+    }
+
     pub fn offset(&self, coordinate: &Coordinates) -> Coordinates {
         match self {
             Orientation::North => Coordinates::new(coordinate.x, coordinate.y + 1),
@@ -152,20 +524,107 @@ impl Orientation {
     }
 }
 
-#[derive(Default, Component, Clone, PartialEq, Hash, Eq, Debug)]
+#[derive(Component, Clone, PartialEq, Debug)]
 pub struct Prototype {
     pub index: usize,
+    /// Name of its model file, without the extension, to refer to it in the adjacency lists
+    pub name: String,
+    /// Asset path of its model, as given in the palette
+    pub model_path: String,
+    /// Model used in the palette and the rule map
     pub model: Handle<Scene>,
+    /// Model used in the generated map, the rule model by default
+    pub display_model: Handle<Scene>,
     pub equivalences: Equivalences,
+
+    /// Relative probability of each of its tiles to be observed
+    pub weight: f32,
+
+    /// Vertical offset of its model when drawn
+    pub height: f32,
+
+    /// Its materials pulse once collapsed in the generated map
+    pub animated: bool,
+
+    /// Offsets of the other cells covered by its model, for the North orientation
+    ///
+    /// Each covered cell holds a part of the tile, learned and propagated as a tile of
+    /// its own.
+    pub footprint: Vec<Coordinates>,
+
+    /// Small models scattered over its tiles in the generated map
+    pub decorations: Vec<Handle<Scene>>,
 }
 
 impl Prototype {
-    pub fn new(index: usize, model: Handle<Scene>, equivalences: Equivalences) -> Self {
+    /// Most decorations scattered over a single cell
+    const MAX_DECORATIONS: usize = 3;
+
+    pub fn new(
+        index: usize,
+        model: Handle<Scene>,
+        equivalences: Equivalences,
+        weight: f32,
+    ) -> Self {
         Self {
             index,
+            name: String::new(),
+            model_path: String::new(),
+            display_model: model.clone(),
             model,
             equivalences,
+            weight,
+            height: 0.0,
+            animated: false,
+            footprint: Vec::new(),
+            decorations: Vec::new(),
+        }
+    }
+
+    /// Decorations of a tile at `coordinates`, with their transform relative to the tile
+    ///
+    /// Each decoration slot of the cell is filled with a `density` chance. The scatter
+    /// only depends on the coordinates, for the decorations to stay put across redraws.
+    pub fn scatter_decorations(
+        &self,
+        coordinates: &Coordinates,
+        density: f32,
+    ) -> Vec<(Handle<Scene>, Transform)> {
+        if self.decorations.is_empty() || density <= 0.0 {
+            return Vec::new();
         }
+        let seed = ((coordinates.x as u32 as u64) << 32) | coordinates.y as u32 as u64;
+        let mut rng = StdRng::seed_from_u64(seed);
+        (0..Self::MAX_DECORATIONS)
+            .filter_map(|_| {
+                // Drawn whether the slot is filled or not, to keep the other slots in place
+                let filled = rng.gen::<f32>() < density;
+                let decoration = rng.gen_range(0..self.decorations.len());
+                let position = Vec3::new(rng.gen_range(-0.4..0.4), 0.0, rng.gen_range(-0.4..0.4));
+                let rotation = Quat::from_rotation_y(rng.gen_range(0.0..std::f32::consts::TAU));
+                filled.then(|| {
+                    let transform = Transform::from_translation(position).with_rotation(rotation);
+                    (self.decorations[decoration].clone(), transform)
+                })
+            })
+            .collect()
+    }
+
+    /// Number of cells covered by its tiles
+    pub fn parts(&self) -> usize {
+        self.footprint.len() + 1
+    }
+
+    /// Offset of a part from the cell its tile is anchored at, rotated with the tile
+    pub fn part_offset(&self, part: usize, orientation: Orientation) -> Coordinates {
+        let mut offset = match part {
+            0 => Coordinates::default(),
+            part => self.footprint.get(part - 1).copied().unwrap_or_default(),
+        };
+        for _ in 0..orientation as i32 {
+            offset = Coordinates::new(-offset.y, offset.x);
+        }
+        offset
     }
 
     pub fn make_tile(&self, orientation: Orientation) -> Tile {
@@ -174,8 +633,15 @@ impl Prototype {
 
     pub fn make_rotated_tile(&self, original_orientation: Orientation, rotation: i32) -> Tile {
         let orientation = original_orientation.rotated(rotation);
-        let orientation = match self.equivalences {
-            Equivalences::None => orientation,
+        // The footprint is not symmetric, even if the model is
+        let equivalences = match self.equivalences {
+            Equivalences::Fixed => Equivalences::Fixed,
+            _ if !self.footprint.is_empty() => Equivalences::None,
+            equivalences => equivalences,
+        };
+        let orientation = match equivalences {
+            Equivalences::None | Equivalences::Mirror => orientation,
+            Equivalences::Fixed => original_orientation,
             Equivalences::HalfTurn => match orientation {
                 Orientation::North | Orientation::South => Orientation::North,
                 Orientation::East | Orientation::West => Orientation::East,
@@ -184,14 +650,49 @@ impl Prototype {
         };
         self.make_tile(orientation)
     }
+
+    /// Reflection of a tile across the North-South axis, if it has one
+    ///
+    /// A symmetric tile is its own mirror image once its orientation is mirrored. The
+    /// multi-cell tiles are never mirrored.
+    pub fn make_mirrored_tile(&self, orientation: Orientation) -> Option<Tile> {
+        if !self.footprint.is_empty() {
+            return None;
+        }
+        match self.equivalences {
+            Equivalences::None | Equivalences::Fixed => None,
+            _ => Some(self.make_rotated_tile(orientation.mirrored(), 0)),
+        }
+    }
 }
 
+/// Tile of a generated map cell, in an exported scene
+#[derive(Component, Default, Clone, Copy, PartialEq, Debug, Reflect, Serialize, Deserialize)]
+#[reflect_value(Component, Serialize, Deserialize, PartialEq)]
+pub struct GeneratedTile(pub Tile);
+
+/// Ordered by prototype index, then orientation, then part
 #[derive(
-    Default, Component, Inspectable, Clone, Copy, PartialEq, Hash, Eq, Debug, Serialize, Deserialize,
+    Default,
+    Component,
+    Inspectable,
+    Clone,
+    Copy,
+    PartialEq,
+    Hash,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+    Serialize,
+    Deserialize,
 )]
 pub struct Tile {
     pub prototype_index: usize,
     pub orientation: Orientation,
+    /// Cell of the prototype footprint covered by the tile, 0 for the one it is anchored at
+    #[serde(default, skip_serializing_if = "Tile::is_anchor_part")]
+    pub part: usize,
 }
 
 impl Tile {
@@ -199,6 +700,24 @@ impl Tile {
         Self {
             prototype_index,
             orientation,
+            part: 0,
+        }
+    }
+
+    fn is_anchor_part(part: &usize) -> bool {
+        *part == 0
+    }
+}
+
+impl std::fmt::Display for Tile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.part {
+            0 => write!(f, "{}@{:?}", self.prototype_index, self.orientation),
+            part => write!(
+                f,
+                "{}.{}@{:?}",
+                self.prototype_index, part, self.orientation
+            ),
         }
     }
 }
@@ -214,6 +733,88 @@ impl OptionalTile {
     }
 }
 
+/// Serialized rule map
+///
+/// It is written with the version of its format, the files from older versions being
+/// migrated when read.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "VersionedMapRule", try_from = "VersionedMapRule")]
+pub struct MapRule {
+    pub map: Vec<Vec<OptionalTile>>,
+}
+
+impl MapRule {
+    /// Version of the format written by this build
+    pub const VERSION: u32 = 1;
+
+    /// Rule map of the rule tiles, the ones out of its size being ignored
+    pub fn read<'a>(
+        tiles: impl Iterator<Item = (&'a OptionalTile, &'a Coordinates)>,
+        width: usize,
+        height: usize,
+    ) -> Self {
+        let mut map = vec![vec![OptionalTile::default(); height]; width];
+        for (tile, coordinates) in tiles {
+            if let Some(map_tile) = map
+                .get_mut(coordinates.x as usize)
+                .and_then(|line| line.get_mut(coordinates.y as usize))
+            {
+                *map_tile = tile.clone();
+            }
+        }
+        Self { map }
+    }
+
+    /// Rule map embedded in the binary
+    pub fn default_map() -> Result<Self, String> {
+        serde_json::from_str(include_str!("default_rule_map.json")).map_err(|e| e.to_string())
+    }
+
+    /// Rule map without any tile, of the size of the rule tiles
+    pub fn empty(width: usize, height: usize) -> Self {
+        Self {
+            map: vec![vec![OptionalTile::default(); height]; width],
+        }
+    }
+}
+
+/// Rule map as written in the files
+#[derive(Serialize, Deserialize)]
+struct VersionedMapRule {
+    /// Missing from the files written before the versioning
+    #[serde(default)]
+    version: u32,
+    /// Read once the version is known
+    map: serde_json::Value,
+}
+
+impl From<MapRule> for VersionedMapRule {
+    fn from(map: MapRule) -> Self {
+        Self {
+            version: MapRule::VERSION,
+            map: serde_json::to_value(map.map).unwrap(),
+        }
+    }
+}
+
+impl TryFrom<VersionedMapRule> for MapRule {
+    type Error = String;
+
+    fn try_from(versioned: VersionedMapRule) -> Result<Self, Self::Error> {
+        match versioned.version {
+            // The unversioned files have the same layout as the first version
+            0 | 1 => serde_json::from_value(versioned.map)
+                .map(|map| Self { map })
+                .map_err(|e| e.to_string()),
+            version => Err(format!(
+                "unsupported rule map version {}, this build reads up to version {}",
+                version,
+                Self::VERSION
+            )),
+        }
+    }
+}
+
 /// Superposition of possible states
 ///
 /// If the tiles size is 1, then it's resolved.
@@ -233,6 +834,36 @@ pub struct TileSuperpositionHistory {
     pub history: VecDeque<HashSet<Tile>>,
 }
 
+/// Latest waves of a cell, with the replay step they were recorded at
+#[derive(Default, Component)]
+pub struct WaveTrace {
+    pub waves: VecDeque<(usize, HashSet<Tile>)>,
+}
+
+impl WaveTrace {
+    /// Record the wave if it changed since the latest one, forgetting the oldest ones
+    pub fn record(&mut self, step: usize, tiles: &HashSet<Tile>, max_length: usize) -> bool {
+        if self.waves.back().map(|(_, latest)| latest) == Some(tiles) {
+            return false;
+        }
+        self.waves.push_back((step, tiles.clone()));
+        while self.waves.len() > max_length {
+            self.waves.pop_front();
+        }
+        true
+    }
+
+    /// Wave of the cell at a step, the oldest one kept if it was recorded later
+    pub fn at(&self, step: usize) -> Option<&HashSet<Tile>> {
+        self.waves
+            .iter()
+            .rev()
+            .find(|(recorded, _)| *recorded <= step)
+            .or_else(|| self.waves.front())
+            .map(|(_, tiles)| tiles)
+    }
+}
+
 /// History of guesses for backtracking purpose
 #[derive(Default, Clone, PartialEq, Eq, Debug)]
 pub struct GuessHistory {
@@ -240,6 +871,240 @@ pub struct GuessHistory {
     pub history: VecDeque<(Entity, Tile)>,
 }
 
+/// Resolved cells left out of the propagation with their tile, see `Tuning::freeze_settled`
+///
+/// Found again at the end of each propagation pass. A frozen cell is only skipped while
+/// it keeps the same tile, which the waves restored by the backtracking always had
+/// once propagated.
+#[derive(Default)]
+pub struct FrozenCells(pub HashMap<Entity, Tile>);
+
+/// Previous states of the edited rule tiles, for undo/redo purpose
+pub type RuleEditStep = Vec<(Entity, OptionalTile)>;
+
+/// Undo and redo stacks of the rule map edits
+#[derive(Default)]
+pub struct RuleEditHistory {
+    pub undo: VecDeque<RuleEditStep>,
+    pub redo: Vec<RuleEditStep>,
+
+    /// True while a continuous edit (mouse held) is recorded in the latest step
+    pub recording: bool,
+}
+
+impl RuleEditHistory {
+    /// Record the previous state of an edited rule tile
+    pub fn record(&mut self, entity: Entity, previous: OptionalTile, max_size: usize) {
+        if !self.recording || self.undo.is_empty() {
+            self.undo.push_front(RuleEditStep::new());
+            self.redo.clear();
+            self.recording = true;
+        }
+        self.undo.front_mut().unwrap().push((entity, previous));
+        self.undo.truncate(max_size);
+    }
+
+    /// Close the ongoing step, the next edit will start a new one
+    pub fn end_step(&mut self) {
+        self.recording = false;
+    }
+}
+
+/// States of the generated map before each forced tile, for undo purpose
+///
+/// Unlike the guess history used by the backtracking, only the tiles forced by a
+/// `SetTileEvent` are recorded. Changing the rules or regenerating the map clears it.
+#[derive(Default)]
+pub struct MapEditHistory {
+    pub undo: VecDeque<Vec<(Entity, HashSet<Tile>)>>,
+}
+
+/// Tiles excluded from the generation, while staying in the rule map
+#[derive(Default)]
+pub struct BannedTiles(pub HashSet<Tile>);
+
+/// Maximum number of tiles of some prototypes in the generated map, by prototype index
+///
+/// A multi-cell tile counts once. Read from `tile_budget.ron` at startup.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct TileBudget(pub HashMap<usize, usize>);
+
+impl TileBudget {
+    const PATH: &'static str = "tile_budget.ron";
+
+    /// Load the budget written by hand, if any
+    pub fn load() -> Self {
+        if cfg!(target_arch = "wasm32") {
+            return Self::default();
+        }
+        let serialized = match std::fs::read_to_string(Self::PATH) {
+            Ok(serialized) => serialized,
+            Err(_) => return Self::default(),
+        };
+        match ron::from_str(&serialized) {
+            Ok(budget) => Self(budget),
+            Err(e) => {
+                warn!("Failed to read {}: {}", Self::PATH, e);
+                Self::default()
+            }
+        }
+    }
+}
+
+/// Generated cell only taking the tiles facing an orientation, whatever their prototype
+///
+/// Unlike a forced tile, the cell is still observed among the remaining candidates.
+#[derive(Component, Clone, Copy, PartialEq, Debug)]
+pub struct OrientationLock(pub Orientation);
+
+impl OrientationLock {
+    /// Whether a tile faces the locked orientation, the symmetric tiles facing all the
+    /// orientations they are equivalent to
+    pub fn allows(&self, tile: &Tile, rules: &Rules) -> bool {
+        rules.prototype(tile).is_some_and(|prototype| {
+            prototype.make_rotated_tile(self.0, 0).orientation == tile.orientation
+        })
+    }
+}
+
+/// Startup phase of the application
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppState {
+    /// Waiting for the tile models
+    Loading,
+    Running,
+}
+
+/// Random generator of the observations, reseeded at the start of every generation
+pub struct GenerationRng(pub StdRng);
+
+impl Default for GenerationRng {
+    fn default() -> Self {
+        Self(StdRng::from_entropy())
+    }
+}
+
+/// Seed of the current generation, and the number of generations started since the
+/// rules last changed
+///
+/// Every generation starts from its own seed, so that a map can be generated again by
+/// launching with `--seed`.
+pub struct GenerationInfo {
+    pub seed: u64,
+    pub attempt: usize,
+}
+
+impl Default for GenerationInfo {
+    fn default() -> Self {
+        Self {
+            seed: rand::random(),
+            attempt: 0,
+        }
+    }
+}
+
+impl GenerationInfo {
+    /// Reseed the generator for a new generation, retrying the same rules or not
+    ///
+    /// The first generation uses the initial seed, the next ones a seed drawn from the
+    /// generator, so that a seeded launch goes through the same seeds.
+    pub fn start(&mut self, rng: &mut GenerationRng, retry: bool) {
+        if self.attempt > 0 {
+            self.seed = rng.0.gen();
+        }
+        rng.0 = StdRng::seed_from_u64(self.seed);
+        self.attempt = match retry {
+            true => self.attempt + 1,
+            false => 1,
+        };
+    }
+
+    /// Reseed the generator with the current seed, to generate from the same start again
+    pub fn restart(&self, rng: &mut GenerationRng) {
+        rng.0 = StdRng::seed_from_u64(self.seed);
+    }
+}
+
+/// Whether the generation is over, to skip the solver systems until the next change
+#[derive(Default)]
+pub struct GenerationState {
+    pub idle: bool,
+}
+
+/// Cell observed by the last collapse step, if any
+#[derive(Default)]
+pub struct FocusCell(pub Option<Entity>);
+
+#[derive(Component, Default)]
+pub struct FocusMarkerTag;
+
+/// Cell edited with the keyboard
+#[derive(Default)]
+pub struct CursorCell {
+    /// Hidden until moved with the arrow keys
+    pub coordinates: Option<Coordinates>,
+    /// On the generated map instead of the rule map
+    pub world_map: bool,
+}
+
+#[derive(Component, Default)]
+pub struct CursorMarkerTag;
+
+/// Highlight of a rule tile teaching the selected adjacency
+#[derive(Component, Default)]
+pub struct ProvenanceMarkerTag;
+
+/// Grow animation of a freshly collapsed tile
+#[derive(Component, Default)]
+pub struct CollapseAnim {
+    pub elapsed: f32,
+}
+
+/// Model pivot of a drawn rule tile, with the tile it draws
+#[derive(Component)]
+pub struct DrawnTile(pub Tile);
+
+/// Turn animation of a rule tile whose orientation changed
+#[derive(Component)]
+pub struct RotationAnim {
+    pub from: Quat,
+    pub to: Quat,
+    pub elapsed: f32,
+}
+
+impl RotationAnim {
+    pub fn new(from: Quat, to: Quat) -> Self {
+        Self {
+            from,
+            to,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Rotation once `elapsed` seconds passed, and whether the animation is over
+    pub fn rotation(&self, duration: f32) -> (Quat, bool) {
+        let progress = match duration > 0.0 {
+            true => (self.elapsed / duration).min(1.0),
+            false => 1.0,
+        };
+        (self.from.slerp(self.to, progress), progress >= 1.0)
+    }
+}
+
+/// Candidate tile rendered with translucent materials
+#[derive(Component, Default)]
+pub struct GhostTag;
+
+/// Collapsed tile of an animated prototype, rendered with pulsing materials
+#[derive(Component, Default)]
+pub struct AnimatedTag;
+
+/// Screen-space label showing the candidate count of an undecided cell
+#[derive(Component)]
+pub struct CandidateCountLabel {
+    pub cell: Entity,
+}
+
 #[derive(Default, Component, Inspectable, Clone, PartialEq)]
 pub struct DrawTile {
     pub tile: OptionalTile,
@@ -258,25 +1123,87 @@ pub struct TileSelection {
 }
 
 impl TileSelection {
+    /// Rotate the selection, wrapping around the four orientations
+    pub fn rotate(&mut self, amount: i32) {
+        self.rotation = self.orientation().rotated(amount) as i32;
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        Orientation::North.rotated(self.rotation)
+    }
+
     pub fn make_tile(&self) -> Option<Tile> {
+        self.make_rotated_tile(0)
+    }
+
+    /// Tile of the selection, with an extra rotation
+    pub fn make_rotated_tile(&self, rotation: i32) -> Option<Tile> {
         match &self.prototype {
             Some(prototype) => Some(
                 prototype
                     .clone()
-                    .make_rotated_tile(Orientation::North, self.rotation),
+                    .make_rotated_tile(Orientation::North, self.rotation + rotation),
             ),
             None => None,
         }
     }
 }
 
+/// Extra rotation of the selection on the hovered rule tile, reset when leaving it
+#[derive(Default)]
+pub struct HoverRotation {
+    pub entity: Option<Entity>,
+    pub rotation: i32,
+}
+
+impl HoverRotation {
+    /// Extra rotation of the selection placed on `entity`
+    pub fn rotation(&self, entity: Entity) -> i32 {
+        match self.entity == Some(entity) {
+            true => self.rotation,
+            false => 0,
+        }
+    }
+}
+
 #[derive(Component, Inspectable, Default)]
 pub struct RuleTileTag;
 
+/// Preview of the current selection, next to the palette
+#[derive(Component, Default)]
+pub struct SelectionPreviewTag;
+
 #[derive(Component, Inspectable)]
 pub struct PaletteTag {}
 
-#[derive(Component, Inspectable, Default)]
+/// Drag between two palette tiles, declaring the second one allowed on a side of the first
+pub struct AdjacencyDrag {
+    /// Side of the first tile where the second one is allowed
+    pub side: Orientation,
+    /// Palette tile where the drag started
+    pub from: Option<Entity>,
+    pub hovered: Option<Entity>,
+    /// Whether the drag left the starting tile, to tell a click from a drag back onto it
+    pub left_start: bool,
+}
+
+impl Default for AdjacencyDrag {
+    fn default() -> Self {
+        Self {
+            side: Orientation::East,
+            from: None,
+            hovered: None,
+            left_start: false,
+        }
+    }
+}
+
+#[derive(Component, Default)]
+pub struct SelectionHintTag;
+
+#[derive(
+    Component, Inspectable, Default, Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize,
+)]
 pub struct Coordinates {
     pub x: i32,
     pub y: i32,
@@ -286,13 +1213,284 @@ impl Coordinates {
     pub fn new(x: i32, y: i32) -> Self {
         Self { x, y }
     }
+
+    /// Wrap the coordinates around the edges of a map
+    pub fn wrapped(&self, width: usize, height: usize) -> Self {
+        Self::new(
+            self.x.rem_euclid(width as i32),
+            self.y.rem_euclid(height as i32),
+        )
+    }
+}
+
+/// Ongoing rectangular selection in the rule map
+#[derive(Default)]
+pub struct BoxSelection {
+    pub start: Option<Coordinates>,
+    pub end: Option<Coordinates>,
+
+    /// Clear the rectangle instead of filling it with the selection
+    pub erase: bool,
+}
+
+impl BoxSelection {
+    pub fn contains(&self, coordinates: &Coordinates) -> bool {
+        match (&self.start, &self.end) {
+            (Some(start), Some(end)) => {
+                coordinates.x >= start.x.min(end.x)
+                    && coordinates.x <= start.x.max(end.x)
+                    && coordinates.y >= start.y.min(end.y)
+                    && coordinates.y <= start.y.max(end.y)
+            }
+            _ => false,
+        }
+    }
+
+    /// Tile to apply on the rectangle
+    pub fn make_tile(&self, selection: &TileSelection) -> Option<Tile> {
+        match self.erase {
+            true => None,
+            false => selection.make_tile(),
+        }
+    }
 }
 
 pub struct RulesNeedUpdateEvent {}
 
+/// Restart the generation from scratch, keeping the current rules
+pub struct RegenerateEvent {}
+
+/// Resolve all the remaining cells at once, deterministically
+pub struct FinalizeEvent {}
+
+/// Frame the whole generated map with the camera again
+pub struct FrameMapEvent {}
+
+/// Observe the last observed cell again, excluding the tile it got
+pub struct RerollLastEvent {}
+
+/// Restart the generation around a copy of the rule map
+pub struct StampRuleMapEvent {}
+
+/// Force a tile in a cell of the generated map, from any system
+///
+/// The tile is handled like an observation: it is excluded by the backtracking if it
+/// leads to a contradiction.
+#[derive(Clone)]
+pub struct SetTileEvent {
+    pub coordinates: Coordinates,
+    pub tile: Tile,
+}
+
+/// Empty the whole rule map
+pub struct ClearRulesEvent {}
+
+/// Respawn the generated map at the size of the settings
+pub struct ResizeMapEvent {}
+
 #[derive(Default, Debug, Clone)]
 pub struct Allowed {
     pub allowed: HashMap<Orientation, HashSet<Tile>>,
+
+    /// Allowed tiles in the second ring, keyed by the path leading to them
+    pub distant: HashMap<(Orientation, Orientation), HashSet<Tile>>,
+}
+
+impl Allowed {
+    /// Allowed tiles at the end of a path of one or two orientations
+    pub fn get(&self, path: &[Orientation]) -> Option<&HashSet<Tile>> {
+        match path {
+            [orientation] => self.allowed.get(orientation),
+            [first, second] => self.distant.get(&(*first, *second)),
+            _ => None,
+        }
+    }
+}
+
+/// Problems found in the last rules update
+#[derive(Default, Debug)]
+pub struct RuleDiagnostics {
+    /// Tiles without any allowed neighbour in some orientations
+    pub dead_ends: Vec<(Tile, Vec<Orientation>)>,
+    /// Tiles allowing a neighbour that doesn't allow them back, with the orientation
+    pub asymmetries: Vec<(Tile, Orientation, Tile)>,
+}
+
+/// Rule tiles each direct adjacency was learned from, when recorded
+///
+/// The adjacencies derived by rotating or mirroring a learned one come from the same
+/// tiles.
+#[derive(Default)]
+pub struct RuleProvenance {
+    /// Coordinates of the tiles allowing a neighbour on one side, by adjacency
+    pub adjacencies: HashMap<(Tile, Orientation, Tile), Vec<Coordinates>>,
+    /// Adjacency whose rule tiles are highlighted
+    pub selected: Option<(Tile, Orientation, Tile)>,
+}
+
+/// Progress of the generation
+#[derive(Default, Inspectable)]
+pub struct CollapseStats {
+    #[inspectable(label = "cells")]
+    pub total: usize,
+
+    #[inspectable(label = "resolved")]
+    pub resolved: usize,
+
+    #[inspectable(label = "contradictions")]
+    pub contradictions: usize,
+
+    #[inspectable(label = "backtracks")]
+    pub backtracks: usize,
+
+    #[inspectable(label = "average entropy")]
+    pub average_entropy: f32,
+}
+
+/// Number of resolved cells of each prototype
+///
+/// A multi-cell tile is counted once, on its anchor cell.
+#[derive(Default, PartialEq, Debug)]
+pub struct TileHistogram {
+    /// Prototype index to count
+    pub counts: BTreeMap<usize, usize>,
+    pub resolved: usize,
+}
+
+impl TileHistogram {
+    pub fn count<'a>(waves: impl Iterator<Item = &'a HashSet<Tile>>) -> Self {
+        let mut histogram = Self::default();
+        for wave in waves {
+            let tile = match wave.iter().next() {
+                Some(tile) if wave.len() == 1 && tile.part == 0 => tile,
+                _ => continue,
+            };
+            *histogram.counts.entry(tile.prototype_index).or_default() += 1;
+            histogram.resolved += 1;
+        }
+        histogram
+    }
+
+    /// Part of the resolved tiles having this prototype
+    pub fn share(&self, prototype_index: usize) -> f32 {
+        match self.resolved {
+            0 => 0.0,
+            resolved => {
+                self.counts.get(&prototype_index).copied().unwrap_or(0) as f32 / resolved as f32
+            }
+        }
+    }
+}
+
+/// Replay of the recorded waves, drawn instead of the current ones
+#[derive(Default, Inspectable)]
+pub struct WaveReplay {
+    #[inspectable(label = "replay")]
+    pub enabled: bool,
+
+    /// Step drawn while replaying, a step being a frame changing any wave
+    #[inspectable(label = "replayed step")]
+    pub step: usize,
+
+    #[inspectable(label = "recorded steps")]
+    pub recorded: usize,
+}
+
+/// Restart the generations ending on a contradiction, for unattended use
+#[derive(Default, Inspectable)]
+pub struct AutoRetry {
+    /// No retry when 0
+    #[inspectable(label = "max attempts")]
+    pub max_attempts: usize,
+
+    /// Retries of the current generation so far
+    #[inspectable(ignore)]
+    pub attempts: usize,
+}
+
+/// Placement of the editor UI in front of the camera, in rule tiles unless stated otherwise
+#[derive(Inspectable)]
+pub struct UiLayout {
+    /// Position of the editor UI relative to the camera, in world units
+    #[inspectable(label = "origin")]
+    pub origin: Vec3,
+
+    /// Size of a rule tile, in world units
+    #[inspectable(label = "scale", min = 0.001)]
+    pub scale: f32,
+
+    #[inspectable(label = "rule map origin")]
+    pub rule_map_origin: Vec2,
+
+    /// Position of the first palette tile, the next rows being laid out below it
+    #[inspectable(label = "palette origin")]
+    pub palette_origin: Vec2,
+
+    #[inspectable(label = "palette columns", min = 1)]
+    pub palette_columns: usize,
+
+    /// Distance between the centers of two palette tiles
+    #[inspectable(label = "palette spacing", min = 1.0)]
+    pub palette_spacing: f32,
+}
+
+impl Default for UiLayout {
+    fn default() -> Self {
+        Self {
+            origin: Vec3::new(-1.4, -0.05, -2.0),
+            scale: 0.05,
+            rule_map_origin: Vec2::ZERO,
+            palette_origin: Vec2::new(0.0, -2.0),
+            palette_columns: 16,
+            palette_spacing: 1.0,
+        }
+    }
+}
+
+impl UiLayout {
+    /// Transform of the editor UI, a child of the camera
+    pub fn transform(&self) -> Transform {
+        Transform::identity()
+            .looking_at(Vec3::Y, Vec3::Z)
+            .with_translation(self.origin)
+            .with_scale(Vec3::splat(self.scale))
+    }
+
+    pub fn rule_map_translation(&self) -> Vec3 {
+        Vec3::new(self.rule_map_origin.x, 0.0, self.rule_map_origin.y)
+    }
+
+    pub fn palette_translation(&self, index: usize) -> Vec3 {
+        let columns = self.palette_columns.max(1);
+        let column = (index % columns) as f32;
+        let row = (index / columns) as f32;
+        Vec3::new(
+            self.palette_origin.x + column * self.palette_spacing,
+            0.0,
+            self.palette_origin.y - row * self.palette_spacing,
+        )
+    }
+
+    /// Below the last row of the palette
+    pub fn selection_preview_translation(&self, prototype_count: usize) -> Vec3 {
+        let rows = prototype_count.div_ceil(self.palette_columns.max(1)) as f32;
+        Vec3::new(
+            self.palette_origin.x,
+            0.0,
+            self.palette_origin.y - rows * self.palette_spacing - 1.0,
+        )
+    }
+}
+
+/// Cost of the last propagation pass, from the first dirty cell to the end of its propagation
+#[derive(Default)]
+pub struct PropagationProfile {
+    /// Number of times each cell propagated its constraints
+    pub visits: HashMap<Entity, usize>,
+    /// Number of neighbour wave intersections
+    pub intersections: usize,
+    /// No cell is left to propagate
+    pub complete: bool,
 }
 
 #[derive(Component, Default, Clone)]
@@ -300,7 +1498,31 @@ pub struct Connectivity {
     pub connectivity: HashMap<Orientation, Entity>,
 }
 
-#[derive(Component, Inspectable)]
+impl Connectivity {
+    /// Connect a cell to its existing neighbours, optionally wrapping around the map edges
+    pub fn new(
+        entities: &HashMap<Coordinates, Entity>,
+        coordinates: &Coordinates,
+        width: usize,
+        height: usize,
+        wrap: bool,
+    ) -> Self {
+        let mut connectivity = HashMap::new();
+        for orientation in Orientation::values() {
+            let mut neighbour_coords = orientation.offset(coordinates);
+            if wrap {
+                neighbour_coords = neighbour_coords.wrapped(width, height);
+            }
+            if let Some(neighbour) = entities.get(&neighbour_coords) {
+                connectivity.insert(orientation, *neighbour);
+            }
+        }
+        Self { connectivity }
+    }
+}
+
+#[derive(Component, Inspectable, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Tuning {
     #[inspectable(label = "show rule map")]
     pub show_rulemap: bool,
@@ -310,6 +1532,176 @@ pub struct Tuning {
 
     #[inspectable(label = "backtrack history size", min = 0)]
     pub backtrack_history_size: usize,
+
+    /// Trim the guesses that resolved the fewest cells instead of the oldest ones
+    ///
+    /// The backtracking can then jump further back when a region turns out infeasible,
+    /// without a larger history. Each guess being kept with a snapshot of the whole map,
+    /// a larger history is the more reliable but more memory hungry alternative.
+    #[inspectable(label = "keep progressing guesses")]
+    pub keep_progressing_guesses: bool,
+
+    #[inspectable(label = "undo history size", min = 1)]
+    pub undo_history_size: usize,
+
+    #[inspectable(label = "wrap around")]
+    pub wrap: bool,
+
+    /// Prototype forced on the edges of the map and of its holes, ignored when wrapping around
+    #[inspectable(label = "border prototype")]
+    pub border_prototype: Option<usize>,
+
+    #[inspectable(label = "show focus cell")]
+    pub show_focus_cell: bool,
+
+    /// Pan the camera toward the last observed cell instead of spinning around the map
+    #[inspectable(label = "follow focus cell")]
+    pub follow_focus_cell: bool,
+
+    #[inspectable(label = "show candidates")]
+    pub show_candidates: bool,
+
+    #[inspectable(label = "max shown candidates", min = 2)]
+    pub max_shown_candidates: usize,
+
+    #[inspectable(label = "show candidate count")]
+    pub show_candidate_count: bool,
+
+    /// Chance of each decoration slot of a cell to be filled
+    #[inspectable(label = "decoration density", min = 0.0, max = 1.0)]
+    pub decoration_density: f32,
+
+    /// Show the entropy in grey levels instead of colors
+    #[inspectable(label = "grey entropy")]
+    pub grey_entropy: bool,
+
+    #[inspectable(label = "entropy display")]
+    pub entropy_display: EntropyDisplay,
+
+    /// Size of the generated map cells, for tile models larger than one unit
+    #[inspectable(label = "tile scale", min = 0.1)]
+    pub tile_scale: f32,
+
+    #[inspectable(label = "collapse animation (s)", min = 0.0)]
+    pub collapse_anim_duration: f32,
+
+    /// Time for a rule tile to turn to its new orientation, when rotated or previewed
+    #[inspectable(label = "rotation animation (s)", min = 0.0)]
+    pub rotation_anim_duration: f32,
+
+    #[inspectable(label = "show grid")]
+    pub show_grid: bool,
+
+    /// Draw the generated map as wireframe, to tell identical neighbours apart and spot
+    /// the gaps between tiles. Not available on the web
+    #[inspectable(label = "wireframe")]
+    pub wireframe: bool,
+
+    #[inspectable(label = "show orientation markers")]
+    pub show_orientation_markers: bool,
+
+    #[inspectable(label = "orientation marker color")]
+    pub orientation_marker_color: Color,
+
+    #[inspectable(label = "show minimap")]
+    pub show_minimap: bool,
+
+    /// Side panel listing the prototypes by name, to select them
+    #[inspectable(label = "show palette list")]
+    pub show_palette_list: bool,
+
+    /// Dragging from a palette tile to another allows them side by side, without painting
+    /// them in the rule map
+    #[inspectable(label = "declare adjacencies by dragging")]
+    pub declare_adjacencies: bool,
+
+    /// The mouse wheel rotates the selection on the hovered rule tile only, instead of
+    /// the selection itself
+    #[inspectable(label = "wheel rotates hovered tile")]
+    pub hover_rotation: bool,
+
+    /// Propagate a single cell per frame, highlighting the cells left to propagate
+    #[inspectable(label = "step propagation")]
+    pub step_propagation: bool,
+
+    /// Waves kept per cell for their replay, none recorded when 0
+    #[inspectable(label = "wave trace length")]
+    pub wave_trace_length: usize,
+
+    /// Observe the tiles leaving the most candidates to the other cells, after a dry run of
+    /// their propagation. Slower, with less contradictions.
+    #[inspectable(label = "lookahead observation")]
+    pub lookahead_observation: bool,
+
+    #[inspectable(label = "collapse strategy")]
+    pub collapse_strategy: CollapseStrategy,
+
+    /// Among the cells with the same score, observe the one with the most constrained neighbours
+    ///
+    /// It leads to fewer contradictions with rule sets prone to them. The default rules
+    /// hardly ever contradict, and gain nothing from it.
+    #[inspectable(label = "constrained tie-break")]
+    pub constrained_tie_break: bool,
+
+    #[inspectable(label = "diagonal constraints")]
+    pub diagonals: bool,
+
+    #[inspectable(label = "second ring constraints")]
+    pub second_ring: bool,
+
+    #[inspectable(label = "mirror rules")]
+    pub mirror_rules: bool,
+
+    /// Constrain the neighbours of a cell in parallel, only with the `parallel` feature
+    #[inspectable(label = "parallel propagation")]
+    pub parallel_propagation: bool,
+
+    /// Leave the resolved cells with settled neighbours out of the propagation
+    ///
+    /// Nothing can restrict them anymore, so the generated maps stay the same.
+    #[inspectable(label = "freeze settled cells")]
+    pub freeze_settled: bool,
+
+    #[inspectable(label = "log propagation profile")]
+    pub profile_propagation: bool,
+
+    /// Keep track of the rule tiles each adjacency was learned from
+    #[inspectable(label = "record rule provenance")]
+    pub record_rule_provenance: bool,
+
+    /// Reset the surroundings of an impossible cell instead of backtracking
+    ///
+    /// This is cheaper than backtracking but doesn't guarantee a solution, and can loop
+    /// forever on some rule sets. The number of resets per frame is capped.
+    #[inspectable(label = "local reset on contradiction")]
+    pub local_reset_on_contradiction: bool,
+
+    /// Most cell propagations in a propagation pass, 0 for no limit
+    ///
+    /// A cell only propagates again after losing candidates, so a pass stays within a
+    /// few propagations per cell unless the waves never settle, like with the local
+    /// resets. The pass then ends like on a contradiction, left to the backtracking.
+    #[inspectable(label = "propagation cap")]
+    pub propagation_cap: usize,
+
+    /// Chance of each neighbour constraint to be ignored while propagating
+    ///
+    /// Above 0, the generated map breaks the learned rules on purpose, for softer
+    /// outputs. The ignored constraints are drawn from the generation seed.
+    #[inspectable(label = "constraint relaxation", min = 0.0, max = 1.0)]
+    pub constraint_relaxation: f32,
+
+    #[inspectable(label = "map width", min = 1)]
+    pub map_width: usize,
+
+    #[inspectable(label = "map height", min = 1)]
+    pub map_height: usize,
+
+    #[inspectable(label = "rule map width", min = 1, max = 64)]
+    pub rule_map_width: usize,
+
+    #[inspectable(label = "rule map height", min = 1, max = 64)]
+    pub rule_map_height: usize,
 }
 
 impl Default for Tuning {
@@ -318,79 +1710,365 @@ impl Default for Tuning {
             show_rulemap: true,
             collapse_per_frame: 100,
             backtrack_history_size: 100,
+            keep_progressing_guesses: false,
+            undo_history_size: 100,
+            wrap: false,
+            border_prototype: None,
+            show_focus_cell: true,
+            follow_focus_cell: false,
+            show_candidates: false,
+            max_shown_candidates: 4,
+            show_candidate_count: false,
+            decoration_density: 0.3,
+            grey_entropy: false,
+            entropy_display: EntropyDisplay::Color,
+            tile_scale: 1.0,
+            collapse_anim_duration: 0.3,
+            rotation_anim_duration: 0.15,
+            show_grid: false,
+            wireframe: false,
+            show_orientation_markers: true,
+            orientation_marker_color: Color::RED,
+            show_minimap: false,
+            show_palette_list: true,
+            declare_adjacencies: false,
+            hover_rotation: false,
+            step_propagation: false,
+            wave_trace_length: 32,
+            lookahead_observation: false,
+            collapse_strategy: CollapseStrategy::MinEntropy,
+            constrained_tie_break: false,
+            diagonals: false,
+            second_ring: false,
+            mirror_rules: false,
+            parallel_propagation: false,
+            freeze_settled: true,
+            profile_propagation: false,
+            record_rule_provenance: false,
+            local_reset_on_contradiction: false,
+            propagation_cap: 100_000,
+            constraint_relaxation: 0.0,
+            map_width: 32,
+            rule_map_width: 16,
+            rule_map_height: 16,
+            map_height: 32,
+        }
+    }
+}
+
+impl Tuning {
+    const PATH: &'static str = "tuning.ron";
+
+    /// Load the settings saved by a previous run, if any
+    pub fn load() -> Option<Self> {
+        if cfg!(target_arch = "wasm32") {
+            return None;
+        }
+        let serialized = std::fs::read_to_string(Self::PATH).ok()?;
+        match ron::from_str(&serialized) {
+            Ok(tuning) => Some(tuning),
+            Err(e) => {
+                warn!("Failed to read {}: {}", Self::PATH, e);
+                None
+            }
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self) {
+        let serialized = ron::ser::to_string_pretty(self, Default::default()).unwrap();
+        if let Err(e) = std::fs::write(Self::PATH, serialized) {
+            warn!("Failed to write {}: {}", Self::PATH, e);
         }
     }
 }
 
-#[derive(Debug)]
+#[cfg(not(target_arch = "wasm32"))]
+fn save_tuning(tuning: Res<Tuning>) {
+    if tuning.is_changed() && !tuning.is_added() {
+        tuning.save();
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Rules {
-    pub width: usize,
-    pub height: usize,
     pub prototypes: Vec<Prototype>,
     pub alloweds: HashMap<Tile, Allowed>,
+    /// Adjacencies removed from the learned ones, a tile never having the other to its side
+    pub forbidden: HashSet<(Tile, Orientation, Tile)>,
+    /// Adjacencies declared between palette tiles, added to the learned ones
+    pub declared: HashSet<(Tile, Orientation, Tile)>,
+    pub source: RuleSource,
+}
+
+/// Where the adjacency rules come from
+#[derive(Debug, Clone, Default)]
+pub enum RuleSource {
+    /// Learned from the tiles painted in the rule map
+    #[default]
+    ExampleMap,
+    /// Written by hand, before adding their rotations and mirror images
+    AdjacencyList(HashMap<Tile, Allowed>),
 }
 
 impl FromWorld for Rules {
     fn from_world(world: &mut World) -> Self {
         let asset_server = world.get_resource::<AssetServer>().unwrap();
-        let palette = vec![
-            PaletteElement::new("bridge_center_wood.glb#Scene0", Equivalences::HalfTurn),
-            PaletteElement::new("bridge_side_wood.glb#Scene0", Equivalences::None),
-            PaletteElement::new("bridge_wood.glb#Scene0", Equivalences::HalfTurn),
-            PaletteElement::new("ground_grass.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_pathBend.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathCross.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_pathCorner.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathCornerSmall.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathEndClosed.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathOpen.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_pathSide.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathSideOpen.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathSplit.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_pathStraight.glb#Scene0", Equivalences::HalfTurn),
-            PaletteElement::new("ground_pathTile.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_riverBendBank.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverCorner.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverCross.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_riverCornerSmall.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverEndClosed.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverOpen.glb#Scene0", Equivalences::QuarterTurn),
-            PaletteElement::new("ground_riverSide.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverSideOpen.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverSplit.glb#Scene0", Equivalences::None),
-            PaletteElement::new("ground_riverStraight.glb#Scene0", Equivalences::HalfTurn),
-        ];
-
-        let mut prototypes = Vec::new();
-        for index in 0..palette.len() {
-            let elt = &palette[index];
-            let model = asset_server.load(&elt.tile_model);
-            prototypes.push(Prototype::new(index, model, elt.equivalences))
+        Self {
+            forbidden: Self::load_forbidden(),
+            declared: Self::load_declared(),
+            ..Self::new(|model| asset_server.load(model))
         }
+    }
+}
+
+impl Rules {
+    /// Every tile variant known by the rules, except the banned ones
+    pub fn possible_tiles(&self, banned: &BannedTiles) -> HashSet<Tile> {
+        self.alloweds
+            .keys()
+            .filter(|tile| !banned.0.contains(tile))
+            .copied()
+            .collect()
+    }
+
+    /// Rules without any constraint for the default palette, loading the models with `load`
+    ///
+    /// The weights, heights, decorations and display models of the palette are read from
+    /// `palette_weights.ron`, `palette_heights.ron`, `palette_decorations.ron` and
+    /// `palette_display_models.ron`, if any.
+    fn new(mut load: impl FnMut(&str) -> Handle<Scene>) -> Self {
+        let weights = PaletteElement::load_by_model(PaletteElement::WEIGHTS_PATH);
+        let heights = PaletteElement::load_by_model(PaletteElement::HEIGHTS_PATH);
+        let mut decorations = PaletteElement::load_by_model(PaletteElement::DECORATIONS_PATH);
+        let display_models: HashMap<String, String> =
+            PaletteElement::load_by_model(PaletteElement::DISPLAY_MODELS_PATH);
+        let prototypes = default_palette()
+            .into_iter()
+            .map(|elt| match weights.get(&elt.tile_model) {
+                Some(weight) => elt.weighted(*weight),
+                None => elt,
+            })
+            .map(|elt| match heights.get(&elt.tile_model) {
+                Some(height) => elt.raised(*height),
+                None => elt,
+            })
+            .map(|elt| match decorations.remove(&elt.tile_model) {
+                Some(decorations) => elt.decorated(decorations),
+                None => elt,
+            })
+            .map(|elt| match display_models.get(&elt.tile_model) {
+                Some(display_model) => elt.displayed_as(display_model),
+                None => elt,
+            })
+            .enumerate()
+            .map(|(index, elt)| {
+                let model = load(&elt.tile_model);
+                Prototype {
+                    name: elt.name(),
+                    model_path: elt.tile_model.clone(),
+                    display_model: match &elt.display_model {
+                        Some(display_model) => load(display_model),
+                        None => model.clone(),
+                    },
+                    height: elt.height,
+                    animated: elt.animated,
+                    footprint: elt.footprint.clone(),
+                    decorations: elt.decorations.iter().map(|path| load(path)).collect(),
+                    ..Prototype::new(index, model, elt.equivalences, elt.weight)
+                }
+            })
+            .collect();
 
         Self {
-            width: 32,
-            height: 32,
             prototypes,
             alloweds: Default::default(),
+            forbidden: Default::default(),
+            declared: Default::default(),
+            source: RuleSource::ExampleMap,
         }
     }
+
+    const FORBIDDEN_PATH: &'static str = "forbidden.ron";
+    const DECLARED_PATH: &'static str = "declared_adjacencies.ron";
+
+    /// Load the forbidden adjacencies written by hand, if any
+    pub fn load_forbidden() -> HashSet<(Tile, Orientation, Tile)> {
+        Self::load_adjacencies(Self::FORBIDDEN_PATH)
+    }
+
+    /// Load the adjacencies declared between palette tiles by a previous run, if any
+    pub fn load_declared() -> HashSet<(Tile, Orientation, Tile)> {
+        Self::load_adjacencies(Self::DECLARED_PATH)
+    }
+
+    fn load_adjacencies(path: &str) -> HashSet<(Tile, Orientation, Tile)> {
+        if cfg!(target_arch = "wasm32") {
+            return HashSet::new();
+        }
+        let serialized = match std::fs::read_to_string(path) {
+            Ok(serialized) => serialized,
+            Err(_) => return HashSet::new(),
+        };
+        match ron::from_str(&serialized) {
+            Ok(adjacencies) => adjacencies,
+            Err(e) => {
+                warn!("Failed to read {}: {}", path, e);
+                HashSet::new()
+            }
+        }
+    }
+
+    /// Keep the declared adjacencies for the next runs
+    pub fn save_declared(&self) {
+        if cfg!(target_arch = "wasm32") {
+            return;
+        }
+        let serialized = ron::ser::to_string_pretty(&self.declared, Default::default()).unwrap();
+        if let Err(e) = std::fs::write(Self::DECLARED_PATH, serialized) {
+            warn!("Failed to write {}: {}", Self::DECLARED_PATH, e);
+        }
+    }
+
+    /// Rules of the default palette without any model, for the headless generation
+    pub fn headless() -> Self {
+        Self::new(|_| Handle::default())
+    }
+
+    /// Prototype of a tile, if it is in the palette
+    pub fn prototype(&self, tile: &Tile) -> Option<&Prototype> {
+        self.prototypes.get(tile.prototype_index)
+    }
+
+    /// Relative probability of a tile to be observed, never for unknown prototypes
+    pub fn weight(&self, tile: &Tile) -> f32 {
+        self.prototype(tile)
+            .map_or(0.0, |prototype| prototype.weight)
+    }
 }
 
 struct PaletteElement {
     pub tile_model: String,
+    /// Richer model to draw in the generated map instead of `tile_model`
+    pub display_model: Option<String>,
     pub equivalences: Equivalences,
+    pub weight: f32,
+    pub height: f32,
+    pub animated: bool,
+    /// Other cells covered by the model, see `Prototype::footprint`
+    pub footprint: Vec<Coordinates>,
+    pub decorations: Vec<String>,
 }
 
 impl PaletteElement {
     pub fn new(tile_model: &str, symmetry: Equivalences) -> Self {
         Self {
             tile_model: tile_model.to_string(),
+            display_model: None,
             equivalences: symmetry,
+            weight: 1.0,
+            height: 0.0,
+            animated: false,
+            footprint: Vec::new(),
+            decorations: Vec::new(),
+        }
+    }
+
+    /// Observe it `weight` times as often as a tile of weight 1 among the same candidates
+    pub fn weighted(self, weight: f32) -> Self {
+        Self { weight, ..self }
+    }
+
+    /// Draw its model `height` above the others
+    pub fn raised(self, height: f32) -> Self {
+        Self { height, ..self }
+    }
+
+    /// File name of its tile model, without the extension nor the asset label
+    fn name(&self) -> String {
+        let path = self.tile_model.split('#').next().unwrap_or_default();
+        let file = path.rsplit('/').next().unwrap_or_default();
+        file.split('.').next().unwrap_or_default().to_string()
+    }
+
+    /// Make its materials pulse in the generated map, for the water
+    pub fn animated(self) -> Self {
+        Self {
+            animated: true,
+            ..self
+        }
+    }
+
+    /// Draw `display_model` instead of its tile model in the generated map
+    pub fn displayed_as(self, display_model: &str) -> Self {
+        Self {
+            display_model: Some(display_model.to_string()),
+            ..self
+        }
+    }
+
+    /// Scatter some of these models over its tiles in the generated map
+    pub fn decorated(self, decorations: Vec<String>) -> Self {
+        Self {
+            decorations,
+            ..self
+        }
+    }
+
+    const WEIGHTS_PATH: &'static str = "palette_weights.ron";
+    const HEIGHTS_PATH: &'static str = "palette_heights.ron";
+    const DECORATIONS_PATH: &'static str = "palette_decorations.ron";
+    const DISPLAY_MODELS_PATH: &'static str = "palette_display_models.ron";
+
+    /// Load a setting of the palette elements by model from `path`, if any
+    fn load_by_model<T: DeserializeOwned>(path: &str) -> HashMap<String, T> {
+        if cfg!(target_arch = "wasm32") {
+            return HashMap::new();
+        }
+        let serialized = match std::fs::read_to_string(path) {
+            Ok(serialized) => serialized,
+            Err(_) => return HashMap::new(),
+        };
+        match ron::from_str(&serialized) {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("Failed to read {}: {}", path, e);
+                HashMap::new()
+            }
         }
     }
 }
 
+fn default_palette() -> Vec<PaletteElement> {
+    vec![
+        PaletteElement::new("bridge_center_wood.glb#Scene0", Equivalences::HalfTurn),
+        PaletteElement::new("bridge_side_wood.glb#Scene0", Equivalences::None),
+        PaletteElement::new("bridge_wood.glb#Scene0", Equivalences::HalfTurn),
+        PaletteElement::new("ground_grass.glb#Scene0", Equivalences::QuarterTurn),
+        PaletteElement::new("ground_pathBend.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathCross.glb#Scene0", Equivalences::QuarterTurn),
+        PaletteElement::new("ground_pathCorner.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathCornerSmall.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathEndClosed.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathOpen.glb#Scene0", Equivalences::QuarterTurn),
+        PaletteElement::new("ground_pathSide.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathSideOpen.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathSplit.glb#Scene0", Equivalences::None),
+        PaletteElement::new("ground_pathStraight.glb#Scene0", Equivalences::HalfTurn),
+        PaletteElement::new("ground_pathTile.glb#Scene0", Equivalences::QuarterTurn),
+        PaletteElement::new("ground_riverBendBank.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverCorner.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverCross.glb#Scene0", Equivalences::QuarterTurn).animated(),
+        PaletteElement::new("ground_riverCornerSmall.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverEndClosed.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverOpen.glb#Scene0", Equivalences::QuarterTurn).animated(),
+        PaletteElement::new("ground_riverSide.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverSideOpen.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverSplit.glb#Scene0", Equivalences::None).animated(),
+        PaletteElement::new("ground_riverStraight.glb#Scene0", Equivalences::HalfTurn).animated(),
+    ]
+}
+
 #[cfg(test)]
 #[test]
 fn rotate_orientation() {
@@ -400,3 +2078,219 @@ fn rotate_orientation() {
     orientation.rotate(1);
     assert!(orientation == Orientation::West);
 }
+
+#[cfg(test)]
+#[test]
+fn rule_map_versions() {
+    let tile = r#"[[{"tile": {"prototype_index": 3, "orientation": "East"}}, {"tile": null}]]"#;
+    let expected = vec![vec![
+        OptionalTile::new(Some(Tile::new(3, Orientation::East))),
+        OptionalTile::default(),
+    ]];
+
+    let unversioned: MapRule = serde_json::from_str(&format!(r#"{{"map": {tile}}}"#)).unwrap();
+    assert!(unversioned.map == expected);
+
+    let serialized = serde_json::to_string(&unversioned).unwrap();
+    assert!(serialized.starts_with(r#"{"version":1,"#));
+    let versioned: MapRule = serde_json::from_str(&serialized).unwrap();
+    assert!(versioned.map == expected);
+
+    let future = format!(r#"{{"version": 2, "map": {tile}}}"#);
+    assert!(serde_json::from_str::<MapRule>(&future).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn wave_trace_replay() {
+    let wave = |tiles: &[usize]| -> HashSet<Tile> {
+        tiles
+            .iter()
+            .map(|index| Tile::new(*index, Orientation::North))
+            .collect()
+    };
+    let mut trace = WaveTrace::default();
+    assert!(trace.record(0, &wave(&[1, 2, 3]), 2));
+    assert!(!trace.record(1, &wave(&[1, 2, 3]), 2));
+    assert!(trace.record(3, &wave(&[1, 2]), 2));
+    assert!(trace.at(2) == Some(&wave(&[1, 2, 3])));
+    assert!(trace.at(3) == Some(&wave(&[1, 2])));
+
+    // The oldest wave is forgotten, and shown instead of it
+    assert!(trace.record(5, &wave(&[2]), 2));
+    assert!(trace.at(0) == Some(&wave(&[1, 2])));
+    assert!(trace.at(9) == Some(&wave(&[2])));
+}
+
+#[cfg(test)]
+#[test]
+fn orientation_mapping() {
+    let same = |a: Quat, b: Quat| (a * Vec3::X).abs_diff_eq(b * Vec3::X, 1e-4);
+    let default = OrientationMapping::default();
+    assert!(default.rotation(Orientation::East) == Quat::from(Orientation::East));
+    assert!(default.translation(&Coordinates::new(1, 2), 4, 3) == Vec3::new(1.0, 0.0, 2.0));
+
+    // The other handedness swaps East and West, in a mirrored grid
+    let reversed = OrientationMapping {
+        reversed: true,
+        ..default
+    };
+    let east = reversed.rotation(Orientation::East);
+    assert!(same(east, Quat::from(Orientation::West)));
+    assert!(reversed.translation(&Coordinates::new(1, 2), 4, 3) == Vec3::new(2.0, 0.0, 2.0));
+    assert!(reversed.grid_step(1, 1) == (-1, 1));
+
+    // Turned around with the North away from the camera, the models along
+    let turned_around = OrientationMapping {
+        north: AxisMapping::NegativeZ,
+        ..default
+    };
+    let translation = turned_around.translation(&Coordinates::new(1, 2), 4, 3);
+    assert!(translation == Vec3::new(2.0, 0.0, 0.0));
+    let north = turned_around.rotation(Orientation::North);
+    assert!(same(north, Quat::from(Orientation::South)));
+    assert!(turned_around.grid_step(1, 1) == (-1, -1));
+
+    let turned = OrientationMapping {
+        base_rotation: 90.0,
+        ..default
+    };
+    let north = turned.rotation(Orientation::North);
+    assert!(same(north, Quat::from_rotation_y(90.0_f32.to_radians())));
+}
+
+#[cfg(test)]
+#[test]
+fn tile_histogram() {
+    let wave = |tiles: &[Tile]| tiles.iter().copied().collect::<HashSet<Tile>>();
+    let grass = Tile::new(1, Orientation::North);
+    let road = Tile::new(2, Orientation::East);
+    let house = Tile::new(3, Orientation::North);
+    let waves = [
+        wave(&[grass]),
+        wave(&[Tile::new(1, Orientation::South)]),
+        wave(&[road]),
+        wave(&[grass, road]),
+        wave(&[]),
+        wave(&[house]),
+        wave(&[Tile { part: 1, ..house }]),
+    ];
+
+    let histogram = TileHistogram::count(waves.iter());
+    assert!(histogram.resolved == 4);
+    assert!(histogram.counts == BTreeMap::from([(1, 2), (2, 1), (3, 1)]));
+    assert!(histogram.share(1) == 0.5);
+    assert!(histogram.share(4) == 0.0);
+    assert!(TileHistogram::count([].iter()).share(1) == 0.0);
+}
+
+#[cfg(test)]
+#[test]
+fn camera_framing() {
+    let fov = std::f32::consts::FRAC_PI_4;
+    let framing = MainCameraTag::framing(32, 16, 0.5, fov);
+    for corner in [(-8.0, -4.0), (8.0, -4.0), (-8.0, 4.0), (8.0, 4.0)] {
+        let to_corner = Vec3::new(corner.0, 0.0, corner.1) - framing.translation;
+        assert!(framing.forward().angle_between(to_corner) <= fov / 2.0 + 1e-4);
+    }
+    assert!(framing.forward().angle_between(-framing.translation) < 1e-4);
+
+    let larger = MainCameraTag::framing(64, 16, 0.5, fov);
+    assert!(larger.translation.length() > framing.translation.length());
+}
+
+#[cfg(test)]
+#[test]
+fn decoration_scatter() {
+    let prototype = Prototype {
+        decorations: vec![Handle::default(); 2],
+        ..Prototype::new(0, Handle::default(), Equivalences::QuarterTurn, 1.0)
+    };
+    let transforms = |coordinates: Coordinates, density: f32| -> Vec<Transform> {
+        prototype
+            .scatter_decorations(&coordinates, density)
+            .into_iter()
+            .map(|(_, transform)| transform)
+            .collect()
+    };
+
+    // Stable across redraws, but different from cell to cell
+    let here = transforms(Coordinates::new(3, -2), 1.0);
+    assert!(here.len() == Prototype::MAX_DECORATIONS);
+    assert!(here == transforms(Coordinates::new(3, -2), 1.0));
+    assert!(here != transforms(Coordinates::new(-2, 3), 1.0));
+    assert!(here
+        .iter()
+        .all(|transform| transform.translation.abs().max_element() <= 0.4));
+
+    // The remaining decorations stay in place at a lower density
+    let sparse = transforms(Coordinates::new(3, -2), 0.5);
+    assert!(sparse.iter().all(|transform| here.contains(transform)));
+    assert!(transforms(Coordinates::new(3, -2), 0.0).is_empty());
+    let bare = Prototype::new(0, Handle::default(), Equivalences::QuarterTurn, 1.0);
+    assert!(bare
+        .scatter_decorations(&Coordinates::new(0, 0), 1.0)
+        .is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn rotation_animation() {
+    let from = Quat::from(Orientation::North);
+    let to = Quat::from(Orientation::East);
+    let mut anim = RotationAnim::new(from, to);
+    assert!(anim.rotation(0.2) == (from, false));
+
+    anim.elapsed = 0.1;
+    let (halfway, done) = anim.rotation(0.2);
+    assert!(!done);
+    assert!((halfway.angle_between(from) - halfway.angle_between(to)).abs() < 1e-4);
+
+    // Over at the end, or right away without any duration
+    anim.elapsed = 0.3;
+    assert!(anim.rotation(0.2) == (to, true));
+    assert!(RotationAnim::new(from, to).rotation(0.0) == (to, true));
+}
+
+#[cfg(test)]
+#[test]
+fn entropy_columns() {
+    assert!(EntropyDisplay::column_height(1, 40) == 0.0);
+    assert!(EntropyDisplay::column_height(40, 40) == EntropyDisplay::MAX_COLUMN_HEIGHT);
+    let (low, high) = (
+        EntropyDisplay::column_height(5, 40),
+        EntropyDisplay::column_height(20, 40),
+    );
+    assert!(0.0 < low && low < high && high < EntropyDisplay::MAX_COLUMN_HEIGHT);
+    // No division by zero with a single tile
+    assert!(EntropyDisplay::column_height(1, 1) == 0.0);
+}
+
+#[cfg(test)]
+#[test]
+fn generation_seeds() {
+    let seeds = |retries: &[bool]| {
+        let mut rng = GenerationRng::default();
+        let mut info = GenerationInfo {
+            seed: 5,
+            attempt: 0,
+        };
+        retries
+            .iter()
+            .map(|retry| {
+                info.start(&mut rng, *retry);
+                // The generator starts over from the reported seed
+                let mut reseeded = StdRng::seed_from_u64(info.seed);
+                assert!(rng.0.clone().gen::<u64>() == reseeded.gen::<u64>());
+                (info.seed, info.attempt)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let started = seeds(&[false, true, true, false]);
+    assert!(started[0] == (5, 1));
+    assert!(started[1].1 == 2 && started[2].1 == 3 && started[3].1 == 1);
+    assert!(started[1].0 != 5 && started[2].0 != started[1].0);
+    // The same seeds from the same launch seed
+    assert!(seeds(&[false, true, true, false]) == started);
+}
@@ -1,5 +1,10 @@
-use crate::components::*;
+use std::collections::HashMap;
+
+use crate::{components::*, wcf};
+#[cfg(not(target_arch = "wasm32"))]
+use bevy::pbr::wireframe::{Wireframe, WireframePlugin};
 use bevy::prelude::*;
+use bevy_inspector_egui::bevy_egui::{egui, EguiContext};
 use bevy_mod_picking::Hover;
 use num_traits::ToPrimitive;
 
@@ -9,83 +14,190 @@ impl Plugin for DisplayPlugin {
     fn build(&self, app: &mut App) {
         app.add_system(pick_draw_tile)
             .add_system(draw_rules)
+            .add_system(update_orientation_markers)
             .add_system(draw_map)
+            .add_system(replay_waves)
             .add_system(apply_coordinate)
+            .add_system(apply_orientation_mapping)
+            .add_system(apply_tile_scale)
+            .add_system(apply_entropy_display)
+            .add_system(apply_ui_layout)
             .add_system(animate_light_direction)
             .add_system(animate_camera)
-            .add_system(update_map_visibility);
+            .add_system(frame_camera)
+            .add_system(update_map_visibility)
+            .add_system(update_grid_visibility)
+            .add_system(update_selection_hint)
+            .add_system(update_selection_preview)
+            .add_system(show_candidate_probabilities)
+            .add_system(show_rule_tooltip)
+            .add_system(show_tile_histogram)
+            .add_system(show_generation_info)
+            .add_system(show_palette_list)
+            .add_system(show_declared_adjacencies)
+            .add_system(show_rule_provenance)
+            .add_system(draw_rule_provenance)
+            .add_system(draw_focus_cell)
+            .add_system(draw_cursor)
+            .add_system(apply_ghost_materials)
+            .add_system(apply_animated_materials)
+            .add_system(pulse_animated_materials)
+            .add_system(animate_collapse)
+            .add_system(animate_rotations)
+            .add_system(update_candidate_count_labels)
+            .add_system(place_candidate_count_labels);
+
+        // No wireframe rendering with WebGL
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_plugin(WireframePlugin).add_system(draw_wireframes);
     }
 }
 
 fn pick_draw_tile(
-    mut query: Query<(&mut DrawTile, &OptionalTile, &Hover)>,
+    mut query: Query<(Entity, &mut DrawTile, &OptionalTile, &Coordinates, &Hover)>,
     selection: Res<TileSelection>,
+    hover_rotation: Res<HoverRotation>,
+    box_selection: Res<BoxSelection>,
 ) {
-    for (mut draw_tile, map_tile, hover) in query.iter_mut() {
-        match hover.hovered() {
-            // When hovered, display the selection tile
-            true => {
-                let tile = OptionalTile::new(selection.make_tile());
-                if draw_tile.tile != tile {
-                    draw_tile.tile = tile;
-                }
+    for (entity, mut draw_tile, map_tile, coordinates, hover) in query.iter_mut() {
+        let tile = if box_selection.start.is_some() {
+            // When drawing a rectangle, preview it
+            match box_selection.contains(coordinates) {
+                true => OptionalTile::new(box_selection.make_tile(&selection)),
+                false => map_tile.clone(),
             }
-            // When not hovered, display the tile from the map
-            false => {
-                if draw_tile.tile != *map_tile {
-                    draw_tile.tile = map_tile.clone();
+        } else {
+            match hover.hovered() {
+                // When hovered, display the selection tile
+                true => {
+                    OptionalTile::new(selection.make_rotated_tile(hover_rotation.rotation(entity)))
                 }
+                // When not hovered, display the tile from the map
+                false => map_tile.clone(),
             }
+        };
+        if draw_tile.tile != tile {
+            draw_tile.tile = tile;
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn draw_rules(
-    query: Query<(Entity, &DrawTile), Changed<DrawTile>>,
+    query: Query<(Entity, &DrawTile, Option<&Children>), Changed<DrawTile>>,
+    drawn_query: Query<(&DrawnTile, &Transform)>,
     mut commands: Commands,
     models: Res<ModelAssets>,
     rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    mapping: Res<OrientationMapping>,
 ) {
-    for (entity, draw_tile) in query.iter() {
+    for (entity, draw_tile, children) in query.iter() {
+        // Model drawn until now, turned toward the new orientation of the same prototype
+        let previous = children
+            .into_iter()
+            .flat_map(|children| children.iter())
+            .find_map(|child| drawn_query.get(*child).ok());
         let mut entity = commands.entity(entity);
         entity.despawn_descendants();
 
         if let Some(tile) = &draw_tile.tile.tile {
             entity.with_children(|parent| {
-                let prototype = &rules.prototypes[tile.prototype_index];
+                let prototype = match rules.prototype(tile) {
+                    Some(prototype) => prototype,
+                    None => return spawn_missing_tile(parent, &models, tile),
+                };
                 let model = prototype.model.clone();
-                let transform = Transform::from_rotation(tile.orientation.clone().into())
-                    .with_translation(Vec3::new(0.0, 0.2, 0.0));
+                let rotation = mapping.rotation(tile.orientation);
+                let turned_from = previous
+                    .filter(|(DrawnTile(previous), _)| {
+                        previous.prototype_index == tile.prototype_index && previous != tile
+                    })
+                    .filter(|_| tuning.rotation_anim_duration > 0.0)
+                    .map(|(_, previous_transform)| previous_transform.rotation);
+                let transform = Transform::from_rotation(turned_from.unwrap_or(rotation))
+                    .with_translation(Vec3::new(0.0, 0.2 + prototype.height, 0.0));
 
-                parent
-                    .spawn_bundle((transform, GlobalTransform::default()))
-                    .with_children(|tile| {
-                        tile.spawn_scene(model);
+                let mut pivot = parent.spawn_bundle((transform, GlobalTransform::default()));
+                pivot.insert(DrawnTile(*tile));
+                if let Some(from) = turned_from {
+                    pivot.insert(RotationAnim::new(from, rotation));
+                }
+                pivot.with_children(|tile| {
+                    tile.spawn_scene(model);
+                    if tuning.show_orientation_markers {
                         tile.spawn_bundle(PbrBundle {
                             material: models.up_cube_mat.clone(),
                             mesh: models.up_cube_mesh.clone(),
                             transform: Transform::from_translation(-Vec3::Z / 2.5),
                             ..Default::default()
                         });
-                    });
+                    }
+                });
             });
         };
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn draw_map(
-    query: Query<(Entity, &TileSuperposition), Changed<TileSuperposition>>,
+    query: Query<
+        (Entity, &TileSuperposition, &Coordinates, Option<&WaveTrace>),
+        Changed<TileSuperposition>,
+    >,
     mut commands: Commands,
     models: Res<ModelAssets>,
     rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    banned: Res<BannedTiles>,
+    replay: Res<WaveReplay>,
+    mapping: Res<OrientationMapping>,
 ) {
-    for (entity, multi_tile) in query.iter() {
+    if query.is_empty() {
+        return;
+    }
+
+    // Candidates of a cell before any observation
+    let max_entropy = rules.possible_tiles(&banned).len();
+    let entropy_mats = match tuning.grey_entropy {
+        true => &models.undecided_mats,
+        false => &models.undecided_color_mats,
+    };
+
+    for (entity, multi_tile, coordinates, trace) in query.iter() {
         let mut entity = commands.entity(entity);
         entity.despawn_descendants();
 
-        let entropy = multi_tile.tiles.len();
+        // Recorded wave while replaying, without any propagation going on
+        let (tiles, dirty) = match trace.filter(|_| replay.enabled) {
+            Some(trace) => (trace.at(replay.step).unwrap_or(&multi_tile.tiles), false),
+            None => (&multi_tile.tiles, multi_tile.dirty),
+        };
+        let entropy = tiles.len();
+
+        // Propagation frontier
+        if tuning.step_propagation && dirty {
+            entity.with_children(|tile| {
+                tile.spawn_bundle(PbrBundle {
+                    mesh: models.focus_mesh.clone(),
+                    material: models.frontier_mat.clone(),
+                    transform: Transform::from_xyz(0.0, 0.04, 0.0),
+                    ..Default::default()
+                });
+            });
+        }
 
         match entropy {
+            // Not a contradiction when nothing can be generated yet
+            0 if rules.alloweds.is_empty() => {
+                entity.with_children(|tile| {
+                    tile.spawn_bundle(PbrBundle {
+                        mesh: models.undecided_mesh.clone(),
+                        material: models.no_rules_mat.clone(),
+                        ..Default::default()
+                    });
+                });
+            }
             0 => {
                 entity.with_children(|tile| {
                     tile.spawn_bundle(PbrBundle {
@@ -96,41 +208,273 @@ fn draw_map(
                 });
             }
             1 => {
-                let tile = multi_tile.tiles.iter().next().unwrap();
-                let prototype = &rules.prototypes[tile.prototype_index];
-                let model = prototype.model.clone();
-                let transform = Transform::from_rotation(tile.orientation.clone().into());
+                let tile = tiles.iter().next().unwrap();
+                if tile.part != 0 {
+                    // Covered by the model drawn on the anchor part
+                    continue;
+                }
+                let prototype = match rules.prototype(tile) {
+                    Some(prototype) => prototype,
+                    None => {
+                        entity.with_children(|parent| spawn_missing_tile(parent, &models, tile));
+                        continue;
+                    }
+                };
+                let model = prototype.display_model.clone();
+                let mut transform = Transform::from_rotation(mapping.rotation(tile.orientation))
+                    .with_translation(Vec3::new(0.0, prototype.height, 0.0));
+                if tuning.collapse_anim_duration > 0.0 {
+                    transform.scale = Vec3::ZERO;
+                }
+                let animated = prototype.animated;
+                let decorations =
+                    prototype.scatter_decorations(coordinates, tuning.decoration_density);
                 entity.with_children(|tile| {
-                    tile.spawn_bundle(TransformBundle::from_transform(transform))
-                        .with_children(|tile| {
-                            tile.spawn_scene(model);
-                        });
+                    let mut tile = tile.spawn_bundle(TransformBundle::from_transform(transform));
+                    tile.insert(CollapseAnim::default());
+                    if animated {
+                        tile.insert(AnimatedTag);
+                    }
+                    tile.with_children(|tile| {
+                        tile.spawn_scene(model);
+                        for (decoration, transform) in decorations {
+                            tile.spawn_bundle(TransformBundle::from_transform(transform))
+                                .with_children(|decoration_parent| {
+                                    decoration_parent.spawn_scene(decoration);
+                                });
+                        }
+                    });
+                });
+            }
+            _ if tuning.show_candidates && entropy <= tuning.max_shown_candidates => {
+                entity.with_children(|tile| {
+                    for candidate in tiles.iter().filter(|tile| tile.part == 0) {
+                        let prototype = match rules.prototype(candidate) {
+                            Some(prototype) => prototype,
+                            None => {
+                                spawn_missing_tile(tile, &models, candidate);
+                                continue;
+                            }
+                        };
+                        let model = prototype.display_model.clone();
+                        let transform =
+                            Transform::from_rotation(mapping.rotation(candidate.orientation))
+                                .with_translation(Vec3::new(0.0, prototype.height, 0.0))
+                                .with_scale(Vec3::splat(0.8));
+                        tile.spawn_bundle(TransformBundle::from_transform(transform))
+                            .insert(GhostTag)
+                            .with_children(|tile| {
+                                tile.spawn_scene(model);
+                            });
+                    }
                 });
             }
             _ => {
                 entity.with_children(|tile| {
-                    let mat_index =
-                        100.0 * (1.0 - (entropy as f32) / (rules.alloweds.len() as f32));
-                    let mat_index = mat_index
+                    // From the darkest with every candidates to the lightest with 2 of them
+                    let level = 1.0
+                        - (entropy as f32 - 2.0) / (max_entropy.saturating_sub(2).max(1) as f32);
+                    let mat_index = (level * (entropy_mats.len() - 1) as f32)
+                        .round()
                         .to_usize()
                         .unwrap_or(0)
-                        .min(models.undecided_mats.len() - 1);
-                    tile.spawn_bundle(PbrBundle {
-                        mesh: models.undecided_mesh.clone(),
-                        material: models.undecided_mats[mat_index].clone(),
-                        ..Default::default()
-                    });
+                        .min(entropy_mats.len() - 1);
+                    let material = entropy_mats[mat_index].clone();
+                    match tuning.entropy_display {
+                        EntropyDisplay::Color => tile.spawn_bundle(PbrBundle {
+                            mesh: models.undecided_mesh.clone(),
+                            material,
+                            ..Default::default()
+                        }),
+                        EntropyDisplay::Height => tile.spawn_bundle(PbrBundle {
+                            mesh: models.entropy_column_mesh.clone(),
+                            material,
+                            transform: Transform::from_scale(Vec3::new(
+                                1.0,
+                                EntropyDisplay::column_height(entropy, max_entropy),
+                                1.0,
+                            )),
+                            ..Default::default()
+                        }),
+                    };
                 });
             }
         }
     }
 }
 
-fn apply_coordinate(mut query: Query<(&mut Transform, &Coordinates), Changed<Coordinates>>) {
-    for (mut transform, coordinates) in query.iter_mut() {
-        transform.translation.x = coordinates.x as f32;
-        transform.translation.y = 0.;
-        transform.translation.z = coordinates.y as f32;
+/// Redraw the map when the replay moves, keeping the replayed step in the recorded ones
+fn replay_waves(
+    mut replay: ResMut<WaveReplay>,
+    mut shown: Local<(bool, usize)>,
+    mut query: Query<&mut TileSuperposition, With<WaveTrace>>,
+) {
+    let latest = replay.recorded.saturating_sub(1);
+    if replay.step > latest {
+        replay.step = latest;
+    }
+    if *shown == (replay.enabled, replay.step) {
+        return;
+    }
+    // Back to the current waves when leaving the replay
+    if replay.enabled || shown.0 {
+        for mut wave in query.iter_mut() {
+            wave.set_changed();
+        }
+    }
+    *shown = (replay.enabled, replay.step);
+}
+
+/// Placeholder of a tile whose prototype is not in the palette, instead of its model
+fn spawn_missing_tile(parent: &mut ChildBuilder, models: &ModelAssets, tile: &Tile) {
+    error!(
+        "No prototype {} in the palette, drawing {} as missing",
+        tile.prototype_index, tile
+    );
+    parent.spawn_bundle(PbrBundle {
+        mesh: models.missing_mesh.clone(),
+        material: models.missing_mat.clone(),
+        transform: Transform::from_xyz(0.0, 0.25, 0.0),
+        ..Default::default()
+    });
+}
+
+/// Apply the marker settings, redrawing the rule tiles when toggled
+fn update_orientation_markers(
+    tuning: Res<Tuning>,
+    mut shown: Local<Option<bool>>,
+    models: Res<ModelAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut query: Query<&mut DrawTile>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    // Only touch the material when needed, to avoid useless asset events
+    if let Some(material) = materials.get(&models.up_cube_mat) {
+        if material.base_color != tuning.orientation_marker_color {
+            materials.get_mut(&models.up_cube_mat).unwrap().base_color =
+                tuning.orientation_marker_color;
+        }
+    }
+
+    if *shown != Some(tuning.show_orientation_markers) {
+        if shown.is_some() {
+            for mut draw_tile in query.iter_mut() {
+                draw_tile.set_changed();
+            }
+        }
+        *shown = Some(tuning.show_orientation_markers);
+    }
+}
+
+/// Redraw the undecided cells when the entropy is shown differently
+fn apply_entropy_display(
+    tuning: Res<Tuning>,
+    mut shown: Local<Option<(EntropyDisplay, bool)>>,
+    mut query: Query<&mut TileSuperposition>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    let display = (tuning.entropy_display, tuning.grey_entropy);
+    if *shown != Some(display) {
+        if shown.is_some() {
+            for mut wave in query.iter_mut().filter(|wave| wave.tiles.len() > 1) {
+                wave.set_changed();
+            }
+        }
+        *shown = Some(display);
+    }
+}
+
+fn apply_coordinate(
+    mapping: Res<OrientationMapping>,
+    tuning: Res<Tuning>,
+    mut query: Query<(
+        &mut Transform,
+        &Coordinates,
+        ChangeTrackers<Coordinates>,
+        Option<&RuleTileTag>,
+    )>,
+) {
+    for (mut transform, coordinates, tracker, rule_tile) in query.iter_mut() {
+        if !tracker.is_changed() && !mapping.is_changed() && !tuning.is_changed() {
+            continue;
+        }
+        let (width, height) = match rule_tile {
+            Some(_) => (tuning.rule_map_width, tuning.rule_map_height),
+            None => (tuning.map_width, tuning.map_height),
+        };
+        transform.translation = mapping.translation(coordinates, width, height);
+    }
+}
+
+/// Turn the models again when the orientation mapping is calibrated
+fn apply_orientation_mapping(
+    mapping: Res<OrientationMapping>,
+    mut draw_query: Query<&mut DrawTile>,
+    mut wave_query: Query<&mut TileSuperposition>,
+    mut palette_query: Query<&mut Transform, With<PaletteModelTag>>,
+) {
+    if !mapping.is_changed() || mapping.is_added() {
+        return;
+    }
+
+    for mut draw_tile in draw_query.iter_mut() {
+        draw_tile.set_changed();
+    }
+    for mut wave in wave_query.iter_mut() {
+        wave.set_changed();
+    }
+    for mut transform in palette_query.iter_mut() {
+        transform.rotation = mapping.rotation(Orientation::North);
+    }
+}
+
+/// Scale the whole generated map, its cells staying at unit coordinates
+fn apply_tile_scale(tuning: Res<Tuning>, mut query: Query<&mut Transform, With<WorldMapTag>>) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    let transform = WorldMapTag::transform(tuning.map_width, tuning.map_height, tuning.tile_scale);
+    for mut world_map_transform in query.iter_mut() {
+        if *world_map_transform != transform {
+            *world_map_transform = transform;
+        }
+    }
+}
+
+/// Lay out the editor UI again when its layout is edited
+#[allow(clippy::type_complexity)]
+fn apply_ui_layout(
+    layout: Res<UiLayout>,
+    rules: Res<Rules>,
+    mut queries: ParamSet<(
+        Query<&mut Transform, With<RuleMapTag>>,
+        Query<&mut Transform, With<RuleMapRootTag>>,
+        Query<(&mut Transform, &Tile), With<PaletteTag>>,
+        Query<&mut Transform, With<SelectionPreviewTag>>,
+    )>,
+) {
+    if !layout.is_changed() || layout.is_added() {
+        return;
+    }
+
+    for mut transform in queries.p0().iter_mut() {
+        *transform = layout.transform();
+    }
+    for mut transform in queries.p1().iter_mut() {
+        transform.translation = layout.rule_map_translation();
+    }
+    for (mut transform, tile) in queries.p2().iter_mut() {
+        transform.translation = layout.palette_translation(tile.prototype_index);
+    }
+    for mut transform in queries.p3().iter_mut() {
+        transform.translation = layout.selection_preview_translation(rules.prototypes.len());
     }
 }
 
@@ -148,9 +492,66 @@ fn animate_light_direction(
     }
 }
 
-fn animate_camera(time: Res<Time>, mut query: Query<&mut Transform, With<CameraHoldTag>>) {
+fn animate_camera(
+    time: Res<Time>,
+    tuning: Res<Tuning>,
+    focus: Res<FocusCell>,
+    cells_query: Query<&GlobalTransform, With<TileSuperposition>>,
+    mut query: Query<&mut Transform, With<CameraHoldTag>>,
+) {
+    // Share of the remaining distance covered each second
+    const FOLLOW_SPEED: f32 = 2.0;
+
+    let target = match tuning.follow_focus_cell {
+        true => focus
+            .0
+            .and_then(|entity| cells_query.get(entity).ok())
+            .map(|cell_transform| cell_transform.translation * Vec3::new(1.0, 0.0, 1.0)),
+        false => Some(Vec3::ZERO),
+    };
+    let step = (time.delta_seconds() * FOLLOW_SPEED).min(1.0);
+
     for mut transform in query.iter_mut() {
-        transform.rotation = Quat::from_rotation_y(time.seconds_since_startup() as f32 / 50.0);
+        // Stay on the last followed cell once the generation is over
+        if let Some(target) = target {
+            transform.translation = transform.translation.lerp(target, step);
+        }
+        if !tuning.follow_focus_cell {
+            transform.rotate(Quat::from_rotation_y(time.delta_seconds() / 50.0));
+        }
+    }
+}
+
+/// Frame the whole generated map when its size changes, or when requested
+///
+/// A request also brings the camera back over the center of the map.
+#[allow(clippy::type_complexity)]
+fn frame_camera(
+    tuning: Res<Tuning>,
+    mut events: EventReader<FrameMapEvent>,
+    mut framed: Local<(usize, usize, f32)>,
+    mut camera_query: Query<(&mut Transform, &PerspectiveProjection), With<MainCameraTag>>,
+    mut hold_query: Query<&mut Transform, (With<CameraHoldTag>, Without<MainCameraTag>)>,
+) {
+    let requested = events.iter().count() > 0;
+    let size = (tuning.map_width, tuning.map_height, tuning.tile_scale);
+    if !requested && *framed == size {
+        return;
+    }
+    *framed = size;
+
+    for (mut transform, projection) in camera_query.iter_mut() {
+        *transform = MainCameraTag::framing(
+            tuning.map_width,
+            tuning.map_height,
+            tuning.tile_scale,
+            projection.fov,
+        );
+    }
+    if requested {
+        for mut transform in hold_query.iter_mut() {
+            transform.translation = Vec3::ZERO;
+        }
     }
 }
 
@@ -190,3 +591,760 @@ fn update_map_visibility(
         );
     }
 }
+
+fn update_grid_visibility(
+    grid_query: Query<Entity, With<GridTag>>,
+    tuning: Res<Tuning>,
+    children_query: Query<&Children>,
+    mut visible_query: Query<&mut Visibility>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    if let Ok(grid) = grid_query.get_single() {
+        set_visible_recursive(tuning.show_grid, grid, &mut visible_query, &children_query);
+    }
+}
+
+fn update_selection_hint(
+    selection: Res<TileSelection>,
+    mut query: Query<&mut Text, With<SelectionHintTag>>,
+) {
+    if !selection.is_changed() {
+        return;
+    }
+
+    for mut text in query.iter_mut() {
+        text.sections[0].value = match &selection.prototype {
+            Some(_) => format!("rotation: {:?} (Q/E)", selection.orientation()),
+            None => "nothing selected".to_string(),
+        };
+    }
+}
+
+/// List the candidates of the hovered undecided cell, with their chance to be observed
+fn show_candidate_probabilities(
+    mut egui_context: ResMut<EguiContext>,
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    selection: Res<TileSelection>,
+    query: Query<(
+        &TileSuperposition,
+        &Coordinates,
+        &Hover,
+        Option<&OrientationLock>,
+    )>,
+) {
+    let (wave, coordinates, lock) = match query.iter().find(|(_, _, hover, _)| hover.hovered()) {
+        Some((wave, coordinates, _, lock)) if wave.tiles.len() > 1 => (wave, coordinates, lock),
+        _ => return,
+    };
+
+    let mut candidates: Vec<&Tile> = wave.tiles.iter().collect();
+    candidates.sort();
+    let total_weight: f32 = candidates.iter().map(|tile| rules.weight(tile)).sum();
+
+    // Whether the palette selection would fit in this cell, and the cells it would constrain
+    let selection_preview = selection.make_tile().map(|tile| {
+        let waves = query
+            .iter()
+            .map(|(wave, coordinates, _, _)| (*coordinates, &wave.tiles))
+            .collect();
+        (
+            wcf::is_legal(&rules, &tuning, &waves, *coordinates, tile),
+            wcf::constraint_closure(&rules, &tuning, &waves, *coordinates, tile).len(),
+        )
+    });
+
+    egui::Window::new("candidates").show(egui_context.ctx_mut(), |ui| {
+        ui.label(format!(
+            "cell {}:{}, {} candidates",
+            coordinates.x,
+            coordinates.y,
+            candidates.len()
+        ));
+        if let Some(OrientationLock(orientation)) = lock {
+            ui.label(format!("locked facing {:?}", orientation));
+        }
+        for tile in candidates {
+            // Uniform choice when all the weights are null
+            let probability = match total_weight > 0.0 {
+                true => rules.weight(tile) / total_weight,
+                false => 1.0 / wave.tiles.len() as f32,
+            };
+            ui.label(format!("{}: {:.1}%", tile, probability * 100.0));
+        }
+        match selection_preview {
+            Some((true, constrained)) => ui.label(format!(
+                "selection: legal, constrains {} cells",
+                constrained
+            )),
+            Some((false, _)) => ui.label("selection: illegal"),
+            None => ui.label("selection: none"),
+        };
+    });
+}
+
+/// Every prototype with its index and symmetry, clicking one selects it like in the palette
+fn show_palette_list(
+    mut egui_context: ResMut<EguiContext>,
+    tuning: Res<Tuning>,
+    rules: Res<Rules>,
+    mut selection: ResMut<TileSelection>,
+) {
+    if !tuning.show_palette_list {
+        return;
+    }
+
+    let selected_index = selection
+        .prototype
+        .as_ref()
+        .map(|prototype| prototype.index);
+    egui::SidePanel::left("palette list").show(egui_context.ctx_mut(), |ui| {
+        ui.heading("palette");
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            for prototype in rules.prototypes.iter() {
+                let label = format!(
+                    "{} {} ({:?})",
+                    prototype.index, prototype.name, prototype.equivalences
+                );
+                let selected = selected_index == Some(prototype.index);
+                let entry = ui
+                    .selectable_label(selected, label)
+                    .on_hover_text(&prototype.model_path);
+                if entry.clicked() && !selected {
+                    selection.prototype = Some(prototype.clone());
+                }
+            }
+        });
+    });
+}
+
+/// Side of the adjacencies declared by dragging between palette tiles, and the ones declared
+fn show_declared_adjacencies(
+    mut egui_context: ResMut<EguiContext>,
+    tuning: Res<Tuning>,
+    mut drag: ResMut<AdjacencyDrag>,
+    mut rules: ResMut<Rules>,
+    mut rules_events: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if !tuning.declare_adjacencies {
+        return;
+    }
+
+    let name = |tile: &Tile| match rules.prototype(tile) {
+        Some(prototype) => prototype.name.clone(),
+        None => tile.prototype_index.to_string(),
+    };
+    let mut declared: Vec<(Tile, Orientation, Tile)> = rules.declared.iter().copied().collect();
+    declared.sort();
+    let mut removed = None;
+    egui::Window::new("declared adjacencies").show(egui_context.ctx_mut(), |ui| {
+        ui.label("Drag from a palette tile to the one allowed on its side:");
+        ui.horizontal(|ui| {
+            for side in [
+                Orientation::North,
+                Orientation::East,
+                Orientation::South,
+                Orientation::West,
+            ] {
+                if ui
+                    .selectable_label(drag.side == side, format!("{:?}", side))
+                    .clicked()
+                    && drag.side != side
+                {
+                    drag.side = side;
+                }
+            }
+        });
+        ui.separator();
+        for adjacency in declared.iter() {
+            let (tile, side, neighbour) = adjacency;
+            ui.horizontal(|ui| {
+                ui.label(format!("{} {:?} {}", name(tile), side, name(neighbour)));
+                if ui.small_button("remove").clicked() {
+                    removed = Some(*adjacency);
+                }
+            });
+        }
+    });
+
+    if let Some(adjacency) = removed {
+        rules.declared.remove(&adjacency);
+        rules.save_declared();
+        rules_events.send(RulesNeedUpdateEvent {});
+    }
+}
+
+/// Seed and attempt of the current generation, to generate a good map again
+fn show_generation_info(mut egui_context: ResMut<EguiContext>, info: Res<GenerationInfo>) {
+    if info.attempt == 0 {
+        return;
+    }
+
+    let ctx = egui_context.ctx_mut();
+    egui::Window::new("generation").show(ctx, |ui| {
+        ui.label(format!("seed: {}", info.seed));
+        ui.label(format!("attempt: {}", info.attempt));
+        if ui.button("copy seed").clicked() {
+            ctx.output().copied_text = info.seed.to_string();
+        }
+    });
+}
+
+/// Bar chart of the resolved tiles of each prototype
+fn show_tile_histogram(mut egui_context: ResMut<EguiContext>, histogram: Res<TileHistogram>) {
+    if histogram.resolved == 0 {
+        return;
+    }
+
+    egui::Window::new("tile histogram").show(egui_context.ctx_mut(), |ui| {
+        ui.label(format!("{} resolved tiles", histogram.resolved));
+        for (prototype_index, count) in histogram.counts.iter() {
+            let share = histogram.share(*prototype_index);
+            ui.add(egui::ProgressBar::new(share).text(format!(
+                "{}: {} ({:.1}%)",
+                prototype_index,
+                count,
+                share * 100.0
+            )));
+        }
+    });
+}
+
+/// Learned neighbours of the selected tile, to pick an adjacency and highlight its rule tiles
+fn show_rule_provenance(
+    mut egui_context: ResMut<EguiContext>,
+    tuning: Res<Tuning>,
+    selection: Res<TileSelection>,
+    mut provenance: ResMut<RuleProvenance>,
+) {
+    if !tuning.record_rule_provenance {
+        return;
+    }
+
+    let tile = selection.make_tile();
+    egui::Window::new("rule provenance").show(egui_context.ctx_mut(), |ui| {
+        let tile = match tile {
+            Some(tile) => tile,
+            None => {
+                ui.label("select a tile to see where its adjacencies come from");
+                return;
+            }
+        };
+        ui.label(format!("{} allows:", tile));
+        for orientation in Orientation::values() {
+            let mut adjacencies: Vec<(Tile, usize)> = provenance
+                .adjacencies
+                .iter()
+                .filter(|((from, side, _), _)| *from == tile && *side == orientation)
+                .map(|((_, _, neighbour), coordinates)| (*neighbour, coordinates.len()))
+                .collect();
+            adjacencies.sort();
+            ui.horizontal_wrapped(|ui| {
+                ui.label(format!("{:?}:", orientation));
+                for (neighbour, count) in adjacencies {
+                    let adjacency = (tile, orientation, neighbour);
+                    let selected = provenance.selected == Some(adjacency);
+                    let label = format!("{} ({})", neighbour, count);
+                    if ui.selectable_label(selected, label).clicked() {
+                        provenance.selected = match selected {
+                            true => None,
+                            false => Some(adjacency),
+                        };
+                    }
+                }
+            });
+        }
+    });
+}
+
+/// Highlight the rule tiles teaching the selected adjacency
+fn draw_rule_provenance(
+    mut commands: Commands,
+    provenance: Res<RuleProvenance>,
+    models: Res<ModelAssets>,
+    rule_tiles_query: Query<(&Coordinates, &Transform, &Parent), With<RuleTileTag>>,
+    marker_query: Query<Entity, With<ProvenanceMarkerTag>>,
+) {
+    if !provenance.is_changed() {
+        return;
+    }
+
+    for marker in marker_query.iter() {
+        commands.entity(marker).despawn_recursive();
+    }
+    let taught_by = match provenance
+        .selected
+        .and_then(|selected| provenance.adjacencies.get(&selected))
+    {
+        Some(taught_by) => taught_by,
+        None => return,
+    };
+    // Next to the rule tiles, which redraw all their children
+    for (coordinates, transform, parent) in rule_tiles_query.iter() {
+        if !taught_by.contains(coordinates) {
+            continue;
+        }
+        commands.entity(parent.0).with_children(|rule_map| {
+            rule_map
+                .spawn_bundle(PbrBundle {
+                    mesh: models.focus_mesh.clone(),
+                    material: models.focus_mat.clone(),
+                    transform: Transform::from_translation(transform.translation + Vec3::Y * 0.05),
+                    ..default()
+                })
+                .insert(ProvenanceMarkerTag);
+        });
+    }
+}
+
+/// Learned constraints of the hovered rule tile
+fn show_rule_tooltip(
+    mut egui_context: ResMut<EguiContext>,
+    rules: Res<Rules>,
+    query: Query<(&OptionalTile, &Hover), With<RuleTileTag>>,
+) {
+    let tile = match query.iter().find(|(_, hover)| hover.hovered()) {
+        Some((OptionalTile { tile: Some(tile) }, _)) => *tile,
+        _ => return,
+    };
+
+    egui::show_tooltip(
+        egui_context.ctx_mut(),
+        egui::Id::new("rule_tooltip"),
+        |ui| {
+            ui.label(format!(
+                "prototype {}, {:?}",
+                tile.prototype_index, tile.orientation
+            ));
+            // Only known once the rules are updated
+            let allowed = match rules.alloweds.get(&tile) {
+                Some(allowed) => allowed,
+                None => return,
+            };
+            for orientation in Orientation::values() {
+                let mut neighbours: Vec<&Tile> = allowed
+                    .get(&[orientation])
+                    .map(|tiles| tiles.iter().collect())
+                    .unwrap_or_default();
+                neighbours.sort();
+                let neighbours: Vec<String> =
+                    neighbours.iter().map(|tile| tile.to_string()).collect();
+                ui.label(format!("{:?}: {}", orientation, neighbours.join(", ")));
+            }
+        },
+    );
+}
+
+fn update_selection_preview(
+    selection: Res<TileSelection>,
+    models: Res<ModelAssets>,
+    mut query: Query<(&mut DrawTile, &mut Handle<StandardMaterial>), With<SelectionPreviewTag>>,
+) {
+    if !selection.is_changed() {
+        return;
+    }
+
+    for (mut draw_tile, mut material) in query.iter_mut() {
+        draw_tile.tile = OptionalTile::new(selection.make_tile());
+        // Empty red slot when nothing is selected
+        *material = match &selection.prototype {
+            Some(_) => models.pick_mat.clone(),
+            None => models.impossible_mat.clone(),
+        };
+    }
+}
+
+fn draw_focus_cell(
+    focus: Res<FocusCell>,
+    tuning: Res<Tuning>,
+    cells_query: Query<&GlobalTransform, With<TileSuperposition>>,
+    mut marker_query: Query<(&mut Transform, &mut Visibility), With<FocusMarkerTag>>,
+) {
+    if !focus.is_changed() && !tuning.is_changed() {
+        return;
+    }
+
+    let focus_transform = match tuning.show_focus_cell {
+        true => focus.0.and_then(|entity| cells_query.get(entity).ok()),
+        false => None,
+    };
+
+    for (mut transform, mut visibility) in marker_query.iter_mut() {
+        match focus_transform {
+            Some(cell_transform) => {
+                transform.translation = cell_transform.translation + Vec3::Y * 0.05;
+                transform.scale = cell_transform.scale;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+/// Highlight the keyboard cursor, following the camera when on the rule map
+fn draw_cursor(
+    cursor: Res<CursorCell>,
+    rule_cells_query: Query<(&Coordinates, &GlobalTransform), With<RuleTileTag>>,
+    world_cells_query: Query<(&Coordinates, &GlobalTransform), With<TileSuperposition>>,
+    mut marker_query: Query<(&mut Transform, &mut Visibility), With<CursorMarkerTag>>,
+) {
+    let cell_transform = cursor.coordinates.and_then(|coordinates| {
+        let is_cursor_cell = |(cell_coordinates, _): &(&Coordinates, &GlobalTransform)| {
+            **cell_coordinates == coordinates
+        };
+        let cell = match cursor.world_map {
+            true => world_cells_query.iter().find(is_cursor_cell),
+            false => rule_cells_query.iter().find(is_cursor_cell),
+        };
+        cell.map(|(_, transform)| *transform)
+    });
+
+    for (mut transform, mut visibility) in marker_query.iter_mut() {
+        match cell_transform {
+            Some(cell_transform) => {
+                transform.translation = cell_transform.mul_vec3(Vec3::Y * 0.05);
+                transform.rotation = cell_transform.rotation;
+                transform.scale = cell_transform.scale;
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+/// Draw the meshes of the generated map as wireframe when enabled, including the ones spawned since
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(clippy::type_complexity)]
+fn draw_wireframes(
+    mut commands: Commands,
+    tuning: Res<Tuning>,
+    query: Query<(Entity, ChangeTrackers<Handle<Mesh>>, Option<&Wireframe>)>,
+    parent_query: Query<&Parent>,
+    world_map_query: Query<(), With<WorldMapTag>>,
+) {
+    for (entity, mesh, wireframe) in query.iter() {
+        if !tuning.is_changed() && !mesh.is_added() {
+            continue;
+        }
+        let in_map = has_ancestor(entity, &parent_query, |ancestor| {
+            world_map_query.get(ancestor).is_ok()
+        });
+        match (tuning.wireframe && in_map, wireframe.is_some()) {
+            (true, false) => {
+                commands.entity(entity).insert(Wireframe);
+            }
+            (false, true) => {
+                commands.entity(entity).remove::<Wireframe>();
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Swap the materials of the candidate tiles for translucent variants once their scene is spawned
+fn apply_ghost_materials(
+    mut query: Query<(Entity, &mut Handle<StandardMaterial>), Added<Handle<StandardMaterial>>>,
+    parent_query: Query<&Parent>,
+    ghost_query: Query<(), With<GhostTag>>,
+    mut models: ResMut<ModelAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, mut material) in query.iter_mut() {
+        if !has_ancestor(entity, &parent_query, |ancestor| {
+            ghost_query.get(ancestor).is_ok()
+        }) {
+            continue;
+        }
+
+        if !models.ghost_mats.contains_key(&material) {
+            let mut ghost_mat = match materials.get(material.clone()) {
+                Some(mat) => mat.clone(),
+                None => continue,
+            };
+            ghost_mat.base_color.set_a(0.3);
+            ghost_mat.alpha_mode = AlphaMode::Blend;
+            let ghost_handle = materials.add(ghost_mat);
+            models.ghost_mats.insert(material.clone(), ghost_handle);
+        }
+        *material = models.ghost_mats[&material].clone();
+    }
+}
+
+/// Swap the materials of the animated tiles for their pulsing variants once their scene is spawned
+fn apply_animated_materials(
+    mut query: Query<(Entity, &mut Handle<StandardMaterial>), Added<Handle<StandardMaterial>>>,
+    parent_query: Query<&Parent>,
+    animated_query: Query<(), With<AnimatedTag>>,
+    mut models: ResMut<ModelAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, mut material) in query.iter_mut() {
+        if !has_ancestor(entity, &parent_query, |ancestor| {
+            animated_query.get(ancestor).is_ok()
+        }) {
+            continue;
+        }
+
+        if !models.animated_mats.contains_key(&material) {
+            let animated_mat = match materials.get(material.clone()) {
+                Some(mat) => mat.clone(),
+                None => continue,
+            };
+            let animated_handle = materials.add(animated_mat);
+            models
+                .animated_mats
+                .insert(material.clone(), animated_handle);
+        }
+        *material = models.animated_mats[&material].clone();
+    }
+}
+
+/// Whether an ancestor of `entity` is tagged
+fn has_ancestor(
+    entity: Entity,
+    parent_query: &Query<&Parent>,
+    is_tagged: impl Fn(Entity) -> bool,
+) -> bool {
+    let mut ancestor = entity;
+    while let Ok(parent) = parent_query.get(ancestor) {
+        ancestor = parent.0;
+        if is_tagged(ancestor) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Make the materials of the animated tiles glow and fade
+fn pulse_animated_materials(
+    time: Res<Time>,
+    models: Res<ModelAssets>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    const PERIOD: f32 = 3.0;
+    const MAX_GLOW: f32 = 0.25;
+
+    let phase = time.seconds_since_startup() as f32 * std::f32::consts::TAU / PERIOD;
+    let glow = MAX_GLOW * (0.5 + 0.5 * phase.sin());
+    for handle in models.animated_mats.values() {
+        if let Some(material) = materials.get_mut(handle) {
+            material.emissive = material.base_color * glow;
+        }
+    }
+}
+
+fn animate_collapse(
+    mut query: Query<(Entity, &mut Transform, &mut CollapseAnim)>,
+    mut commands: Commands,
+    time: Res<Time>,
+    tuning: Res<Tuning>,
+) {
+    for (entity, mut transform, mut anim) in query.iter_mut() {
+        anim.elapsed += time.delta_seconds();
+        let progress = match tuning.collapse_anim_duration > 0.0 {
+            true => (anim.elapsed / tuning.collapse_anim_duration).min(1.0),
+            false => 1.0,
+        };
+        transform.scale = Vec3::splat(progress);
+
+        if progress >= 1.0 {
+            commands.entity(entity).remove::<CollapseAnim>();
+        }
+    }
+}
+
+/// Turn the rule tiles toward their new orientation
+fn animate_rotations(
+    mut query: Query<(Entity, &mut Transform, &mut RotationAnim)>,
+    mut commands: Commands,
+    time: Res<Time>,
+    tuning: Res<Tuning>,
+) {
+    for (entity, mut transform, mut anim) in query.iter_mut() {
+        anim.elapsed += time.delta_seconds();
+        let (rotation, done) = anim.rotation(tuning.rotation_anim_duration);
+        transform.rotation = rotation;
+
+        if done {
+            commands.entity(entity).remove::<RotationAnim>();
+        }
+    }
+}
+
+fn update_candidate_count_labels(
+    mut commands: Commands,
+    tuning: Res<Tuning>,
+    models: Res<ModelAssets>,
+    cells_query: Query<(
+        Entity,
+        &TileSuperposition,
+        ChangeTrackers<TileSuperposition>,
+    )>,
+    mut labels_query: Query<(Entity, &CandidateCountLabel, &mut Text)>,
+) {
+    if !tuning.show_candidate_count {
+        if tuning.is_changed() {
+            for (label, _, _) in labels_query.iter() {
+                commands.entity(label).despawn();
+            }
+        }
+        return;
+    }
+
+    let labels: HashMap<Entity, Entity> = labels_query
+        .iter()
+        .map(|(label, candidate_label, _)| (candidate_label.cell, label))
+        .collect();
+
+    for (cell, wave, tracker) in cells_query.iter() {
+        if !tracker.is_changed() && !tuning.is_changed() {
+            continue;
+        }
+
+        let entropy = wave.tiles.len();
+        match labels.get(&cell) {
+            Some(label) if entropy > 1 => {
+                let mut text = labels_query.get_component_mut::<Text>(*label).unwrap();
+                text.sections[0].value = entropy.to_string();
+            }
+            // Resolved or impossible
+            Some(label) => commands.entity(*label).despawn(),
+            None if entropy > 1 => {
+                commands
+                    .spawn_bundle(TextBundle {
+                        style: Style {
+                            position_type: PositionType::Absolute,
+                            ..default()
+                        },
+                        text: Text::with_section(
+                            entropy.to_string(),
+                            TextStyle {
+                                font: models.font.clone(),
+                                font_size: 12.0,
+                                color: Color::WHITE,
+                            },
+                            default(),
+                        ),
+                        ..default()
+                    })
+                    .insert(CandidateCountLabel { cell });
+            }
+            None => (),
+        }
+    }
+}
+
+/// Keep the candidate count labels above their cell
+fn place_candidate_count_labels(
+    mut commands: Commands,
+    windows: Res<Windows>,
+    images: Res<Assets<Image>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<PerspectiveProjection>>,
+    cells_query: Query<&GlobalTransform, With<TileSuperposition>>,
+    mut labels_query: Query<(Entity, &CandidateCountLabel, &mut Style, &mut Visibility)>,
+) {
+    let (camera, camera_transform) = match camera_query.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    for (label, candidate_label, mut style, mut visibility) in labels_query.iter_mut() {
+        // The map was respawned
+        let cell_transform = match cells_query.get(candidate_label.cell) {
+            Ok(transform) => transform,
+            Err(_) => {
+                commands.entity(label).despawn();
+                continue;
+            }
+        };
+
+        match camera.world_to_screen(
+            &windows,
+            &images,
+            camera_transform,
+            cell_transform.translation,
+        ) {
+            Some(position) => {
+                visibility.is_visible = true;
+                style.position.left = Val::Px(position.x);
+                style.position.bottom = Val::Px(position.y);
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn display_models() {
+    use bevy::{asset::AssetPlugin, core::CorePlugin, scene::ScenePlugin};
+    use std::collections::HashSet;
+
+    let mut app = App::new();
+    app.add_plugin(CorePlugin)
+        .add_plugin(AssetPlugin)
+        .add_plugin(ScenePlugin)
+        .add_asset::<Mesh>()
+        .add_asset::<StandardMaterial>()
+        .init_resource::<ModelAssets>()
+        .init_resource::<Tuning>()
+        .init_resource::<BannedTiles>()
+        .init_resource::<WaveReplay>()
+        .init_resource::<OrientationMapping>()
+        .add_system(draw_rules)
+        .add_system(draw_map);
+
+    let mut scenes = app.world.get_resource_mut::<Assets<Scene>>().unwrap();
+    let mut named_scene = |name: &str| {
+        let mut world = World::new();
+        world.spawn().insert(Name::new(name.to_string()));
+        scenes.add(Scene::new(world))
+    };
+    let model = named_scene("rules");
+    let display_model = named_scene("map");
+    app.insert_resource(Rules {
+        prototypes: vec![Prototype {
+            display_model,
+            ..Prototype::new(0, model, Equivalences::QuarterTurn, 1.0)
+        }],
+        alloweds: Default::default(),
+        forbidden: Default::default(),
+        declared: Default::default(),
+        source: RuleSource::ExampleMap,
+    });
+
+    let tile = Tile::new(0, Orientation::North);
+    let map_cell = app
+        .world
+        .spawn()
+        .insert(TileSuperposition {
+            tiles: HashSet::from([tile]),
+            dirty: false,
+        })
+        .insert(Coordinates::new(0, 0))
+        .id();
+    let rule_cell = app
+        .world
+        .spawn()
+        .insert(DrawTile { tile: tile.into() })
+        .id();
+
+    // Drawn, then spawned by the scene spawner on the next frame
+    app.update();
+    app.update();
+
+    let mut drawn = HashMap::new();
+    let mut query = app.world.query::<(Entity, &Name)>();
+    for (entity, name) in query.iter(&app.world) {
+        let mut root = entity;
+        while let Some(parent) = app.world.get::<Parent>(root) {
+            root = parent.0;
+        }
+        drawn.insert(root, name.as_str().to_string());
+    }
+    assert!(drawn[&map_cell] == "map");
+    assert!(drawn[&rule_cell] == "rules");
+}
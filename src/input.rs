@@ -1,6 +1,9 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use crate::components::*;
+use crate::wcf::describe_rules;
 use bevy::{input::mouse::MouseWheel, prelude::*};
-use bevy_mod_picking::{Hover, PickingEvent};
+use bevy_mod_picking::{Hover, HoverEvent, PickingEvent};
 
 pub struct InputPlugin;
 
@@ -8,41 +11,476 @@ impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
         let system_set = SystemSet::new()
             .with_system(pick_tile)
+            .with_system(keyboard_cursor)
             .with_system(on_mouse_wheel)
-            .with_system(palette_select);
+            .with_system(keyboard_rotate)
+            .with_system(undo_redo)
+            .with_system(undo_map_edit)
+            .with_system(box_fill)
+            .with_system(flood_fill)
+            .with_system(eyedropper)
+            .with_system(lock_orientation)
+            .with_system(keyboard_palette_select)
+            .with_system(keyboard_regenerate)
+            .with_system(keyboard_reroll)
+            .with_system(keyboard_finalize)
+            .with_system(keyboard_frame_map)
+            .with_system(keyboard_stamp_rule_map)
+            .with_system(keyboard_resize_rule_map)
+            .with_system(keyboard_ban)
+            .with_system(keyboard_dump_rules)
+            .with_system(keyboard_clear_rules)
+            .with_system(clear_rules)
+            .with_system(palette_select)
+            .with_system(drag_adjacency);
         app.add_system_set_to_stage(CoreStage::PostUpdate, system_set);
     }
 }
 
+fn shift_pressed(keyboard_input: &Input<KeyCode>) -> bool {
+    keyboard_input.any_pressed([KeyCode::LShift, KeyCode::RShift])
+}
+
+fn ctrl_pressed(keyboard_input: &Input<KeyCode>) -> bool {
+    keyboard_input.any_pressed([KeyCode::LControl, KeyCode::RControl])
+}
+
+fn alt_pressed(keyboard_input: &Input<KeyCode>) -> bool {
+    keyboard_input.any_pressed([KeyCode::LAlt, KeyCode::RAlt])
+}
+
+/// Replace a rule tile, recording the previous one for the undo
+fn edit_rule_tile(
+    entity: Entity,
+    map_tile: &mut Mut<OptionalTile>,
+    new_tile: &OptionalTile,
+    tuning: &Tuning,
+    edit_history: &mut RuleEditHistory,
+) -> bool {
+    if **map_tile == *new_tile {
+        return false;
+    }
+    edit_history.record(entity, (*map_tile).clone(), tuning.undo_history_size);
+    **map_tile = new_tile.clone();
+    true
+}
+
+#[allow(clippy::too_many_arguments)]
 fn pick_tile(
-    mut query: Query<(&mut OptionalTile, &Hover)>,
+    mut query: Query<(Entity, &mut OptionalTile, &Hover)>,
     selection: Res<TileSelection>,
+    hover_rotation: Res<HoverRotation>,
+    tuning: Res<Tuning>,
     mouse_button_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    box_selection: Res<BoxSelection>,
+    mut edit_history: ResMut<RuleEditHistory>,
     mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+    mut pending_update: Local<bool>,
 ) {
-    let new_tile;
+    // The rules are learned once the drawing stops, instead of resetting the generation
+    // on every drawn tile
+    let mut flush = |pending_update: &mut bool| {
+        if *pending_update {
+            event_writer.send(RulesNeedUpdateEvent {});
+            *pending_update = false;
+        }
+    };
+
+    // Another tool is in use
+    if shift_pressed(&keyboard_input)
+        || ctrl_pressed(&keyboard_input)
+        || alt_pressed(&keyboard_input)
+        || box_selection.start.is_some()
+    {
+        flush(&mut pending_update);
+        return;
+    }
+
+    let erase;
     if mouse_button_input.pressed(MouseButton::Left) {
-        new_tile = selection.make_tile();
+        erase = false;
     } else if mouse_button_input.pressed(MouseButton::Right) {
-        new_tile = None;
+        erase = true;
     } else {
+        edit_history.end_step();
+        flush(&mut pending_update);
+        return;
+    }
+
+    for (entity, mut map_tile, hover) in query.iter_mut() {
+        if hover.hovered() {
+            let new_tile = match erase {
+                true => OptionalTile::default(),
+                false => {
+                    OptionalTile::new(selection.make_rotated_tile(hover_rotation.rotation(entity)))
+                }
+            };
+            *pending_update |=
+                edit_rule_tile(entity, &mut map_tile, &new_tile, &tuning, &mut edit_history);
+        }
+    }
+}
+
+/// Edit the maps without the mouse
+///
+/// The arrow keys move the cursor, Tab switches between the rule map and the generated
+/// map and Escape hides it. Space or Enter places the selection, Delete clears the rule tile.
+#[allow(clippy::too_many_arguments)]
+fn keyboard_cursor(
+    mut query: Query<(Entity, &mut OptionalTile, &Coordinates), With<RuleTileTag>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    selection: Res<TileSelection>,
+    tuning: Res<Tuning>,
+    mapping: Res<OrientationMapping>,
+    mut cursor: ResMut<CursorCell>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut rules_event_writer: EventWriter<RulesNeedUpdateEvent>,
+    mut set_tile_event_writer: EventWriter<SetTileEvent>,
+) {
+    // The rule map is drawn from the top, like the generated map seen from the camera
+    const MOVES: [(KeyCode, i32, i32); 4] = [
+        (KeyCode::Up, 0, -1),
+        (KeyCode::Down, 0, 1),
+        (KeyCode::Left, -1, 0),
+        (KeyCode::Right, 1, 0),
+    ];
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        cursor.coordinates = None;
         return;
     }
+    if keyboard_input.just_pressed(KeyCode::Tab) {
+        cursor.world_map = !cursor.world_map;
+    }
+    let (width, height) = match cursor.world_map {
+        true => (tuning.map_width as i32, tuning.map_height as i32),
+        false => (tuning.rule_map_width as i32, tuning.rule_map_height as i32),
+    };
+
+    for (key, dx, dy) in MOVES {
+        // The grids are drawn mirrored with the reversed handedness, or turned around
+        let (dx, dy) = mapping.grid_step(dx, dy);
+        // Alt and the arrows resize the rule map instead
+        if keyboard_input.just_pressed(key) && !alt_pressed(&keyboard_input) {
+            cursor.coordinates = Some(match cursor.coordinates {
+                Some(coordinates) => Coordinates::new(
+                    (coordinates.x + dx).clamp(0, width - 1),
+                    (coordinates.y + dy).clamp(0, height - 1),
+                ),
+                None => Coordinates::new(0, 0),
+            });
+        }
+    }
+    // Stay on the map after switching
+    let coordinates = match cursor.coordinates {
+        Some(coordinates) => Coordinates::new(
+            coordinates.x.clamp(0, width - 1),
+            coordinates.y.clamp(0, height - 1),
+        ),
+        None => return,
+    };
+    if cursor.coordinates != Some(coordinates) {
+        cursor.coordinates = Some(coordinates);
+    }
+
+    let new_tile = if keyboard_input.any_just_pressed([KeyCode::Space, KeyCode::Return]) {
+        selection.make_tile()
+    } else if keyboard_input.just_pressed(KeyCode::Delete) && !cursor.world_map {
+        None
+    } else {
+        return;
+    };
+
+    if cursor.world_map {
+        if let Some(tile) = new_tile {
+            set_tile_event_writer.send(SetTileEvent { coordinates, tile });
+        }
+        return;
+    }
+
     let new_tile = OptionalTile::new(new_tile);
+    let mut changed = false;
+    edit_history.end_step();
+    for (entity, mut map_tile, cell_coordinates) in query.iter_mut() {
+        if *cell_coordinates == coordinates {
+            changed |= edit_rule_tile(entity, &mut map_tile, &new_tile, &tuning, &mut edit_history);
+        }
+    }
+    edit_history.end_step();
+
+    if changed {
+        rules_event_writer.send(RulesNeedUpdateEvent {});
+    }
+}
 
+#[allow(clippy::too_many_arguments)]
+fn box_fill(
+    mut query: Query<(Entity, &mut OptionalTile, &Coordinates, &Hover), With<RuleTileTag>>,
+    selection: Res<TileSelection>,
+    tuning: Res<Tuning>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut box_selection: ResMut<BoxSelection>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    let hovered = query
+        .iter()
+        .find(|(_, _, _, hover)| hover.hovered())
+        .map(|(_, _, coordinates, _)| *coordinates);
+
+    // Start the rectangle
+    if box_selection.start.is_none() && shift_pressed(&keyboard_input) {
+        for (button, erase) in [(MouseButton::Left, false), (MouseButton::Right, true)] {
+            if mouse_button_input.just_pressed(button) && hovered.is_some() {
+                box_selection.start = hovered;
+                box_selection.erase = erase;
+            }
+        }
+    }
+
+    if box_selection.start.is_none() {
+        return;
+    }
+
+    // Follow the cursor for the preview
+    if hovered.is_some() && box_selection.end != hovered {
+        box_selection.end = hovered;
+    }
+
+    let button = match box_selection.erase {
+        true => MouseButton::Right,
+        false => MouseButton::Left,
+    };
+    if !mouse_button_input.just_released(button) {
+        return;
+    }
+
+    // Fill the rectangle
+    let new_tile = OptionalTile::new(box_selection.make_tile(&selection));
     let mut changed = false;
-    for (mut map_tile, hover) in query.iter_mut() {
-        if hover.hovered() && *map_tile != new_tile {
+    edit_history.end_step();
+    for (entity, mut map_tile, coordinates, _) in query.iter_mut() {
+        if box_selection.contains(coordinates) && *map_tile != new_tile {
+            edit_history.record(entity, map_tile.clone(), tuning.undo_history_size);
             *map_tile = new_tile.clone();
             changed = true;
         }
     }
+    edit_history.end_step();
+    *box_selection = BoxSelection::default();
 
     if changed {
         event_writer.send(RulesNeedUpdateEvent {});
     }
 }
 
+/// Replace the contiguous region sharing the clicked tile with the selection
+#[allow(clippy::too_many_arguments)]
+fn flood_fill(
+    mut query: Query<(Entity, &mut OptionalTile, &Coordinates, &Hover), With<RuleTileTag>>,
+    selection: Res<TileSelection>,
+    tuning: Res<Tuning>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if !ctrl_pressed(&keyboard_input) {
+        return;
+    }
+
+    let new_tile;
+    if mouse_button_input.just_pressed(MouseButton::Left) {
+        new_tile = selection.make_tile();
+    } else if mouse_button_input.just_pressed(MouseButton::Right) {
+        new_tile = None;
+    } else {
+        return;
+    }
+    let new_tile = OptionalTile::new(new_tile);
+
+    let (start, target) = match query.iter().find(|(_, _, _, hover)| hover.hovered()) {
+        Some((_, map_tile, coordinates, _)) => (*coordinates, map_tile.clone()),
+        None => return,
+    };
+
+    // Nothing to replace
+    if target == new_tile {
+        return;
+    }
+
+    let cells: HashMap<Coordinates, Entity> = query
+        .iter()
+        .map(|(entity, _, coordinates, _)| (*coordinates, entity))
+        .collect();
+    let mut visited = HashSet::new();
+    let mut queue = VecDeque::from([start]);
+
+    edit_history.end_step();
+    while let Some(coordinates) = queue.pop_front() {
+        if !visited.insert(coordinates) {
+            continue;
+        }
+
+        // Out of the rule map
+        let entity = match cells.get(&coordinates) {
+            Some(entity) => *entity,
+            None => continue,
+        };
+
+        let (_, mut map_tile, _, _) = query.get_mut(entity).unwrap();
+        if *map_tile != target {
+            continue;
+        }
+        edit_history.record(entity, map_tile.clone(), tuning.undo_history_size);
+        *map_tile = new_tile.clone();
+
+        for orientation in Orientation::values() {
+            queue.push_back(orientation.offset(&coordinates));
+        }
+    }
+    edit_history.end_step();
+
+    event_writer.send(RulesNeedUpdateEvent {});
+}
+
+/// Pick the selection from a rule tile
+fn eyedropper(
+    query: Query<(&OptionalTile, &Hover), With<RuleTileTag>>,
+    mut selection: ResMut<TileSelection>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    rules: Res<Rules>,
+) {
+    let picking = mouse_button_input.just_pressed(MouseButton::Middle)
+        || (alt_pressed(&keyboard_input) && mouse_button_input.just_pressed(MouseButton::Left));
+    if !picking {
+        return;
+    }
+
+    for (map_tile, hover) in query.iter() {
+        if !hover.hovered() {
+            continue;
+        }
+        if let Some(tile) = &map_tile.tile {
+            match rules.prototype(tile) {
+                Some(prototype) => {
+                    selection.prototype = Some(prototype.clone());
+                    selection.rotation = tile.orientation as i32;
+                }
+                None => error!("No prototype {} in the palette", tile.prototype_index),
+            }
+        }
+    }
+}
+
+/// Lock a generated cell to the orientation of the selection with Ctrl and left click,
+/// unlock it with Ctrl and right click
+fn lock_orientation(
+    mut commands: Commands,
+    query: Query<(Entity, &Hover), With<TileSuperposition>>,
+    selection: Res<TileSelection>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    keyboard_input: Res<Input<KeyCode>>,
+) {
+    if !ctrl_pressed(&keyboard_input) {
+        return;
+    }
+    let lock = match (
+        mouse_button_input.just_pressed(MouseButton::Left),
+        mouse_button_input.just_pressed(MouseButton::Right),
+    ) {
+        (true, _) => Some(OrientationLock(selection.orientation())),
+        (_, true) => None,
+        _ => return,
+    };
+
+    for (entity, hover) in query.iter() {
+        if !hover.hovered() {
+            continue;
+        }
+        match lock {
+            Some(lock) => commands.entity(entity).insert(lock),
+            None => commands.entity(entity).remove::<OrientationLock>(),
+        };
+    }
+}
+
+/// Apply an edit step, returning the step reverting it
+fn apply_edit_step(
+    step: RuleEditStep,
+    query: &mut Query<&mut OptionalTile, With<RuleTileTag>>,
+) -> RuleEditStep {
+    let mut reverse = RuleEditStep::new();
+    for (entity, tile) in step.into_iter().rev() {
+        if let Ok(mut map_tile) = query.get_mut(entity) {
+            reverse.push((entity, map_tile.clone()));
+            *map_tile = tile;
+        }
+    }
+    reverse
+}
+
+fn undo_redo(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut query: Query<&mut OptionalTile, With<RuleTileTag>>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if !ctrl_pressed(&keyboard_input) {
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Z) {
+        if let Some(step) = edit_history.undo.pop_front() {
+            let reverse = apply_edit_step(step, &mut query);
+            edit_history.redo.push(reverse);
+            edit_history.end_step();
+            event_writer.send(RulesNeedUpdateEvent {});
+        }
+    } else if keyboard_input.just_pressed(KeyCode::Y) {
+        if let Some(step) = edit_history.redo.pop() {
+            let reverse = apply_edit_step(step, &mut query);
+            edit_history.undo.push_front(reverse);
+            edit_history.end_step();
+            event_writer.send(RulesNeedUpdateEvent {});
+        }
+    }
+}
+
+/// Restore the generated map as it was before the last forced tile
+fn undo_map_edit(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut query: Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
+    mut map_edits: ResMut<MapEditHistory>,
+    mut history: ResMut<GuessHistory>,
+    mut state: ResMut<GenerationState>,
+) {
+    if !alt_pressed(&keyboard_input) || !keyboard_input.just_pressed(KeyCode::Z) {
+        return;
+    }
+    let step = match map_edits.undo.pop_front() {
+        Some(step) => step,
+        None => return,
+    };
+
+    for (entity, tiles) in step {
+        if let Ok((mut wave, _)) = query.get_mut(entity) {
+            wave.tiles = tiles;
+            wave.dirty = false;
+        }
+    }
+
+    // The solver guesses since the edit don't apply to the restored map anymore
+    history.history.clear();
+    for (_, mut wave_history) in query.iter_mut() {
+        wave_history.history.clear();
+    }
+    state.idle = false;
+}
+
 fn palette_select(
     mut events: EventReader<PickingEvent>,
     mut selection: ResMut<TileSelection>,
@@ -53,9 +491,10 @@ fn palette_select(
         match event {
             PickingEvent::Clicked(e) => {
                 match palette_query.get(*e) {
-                    Ok(e) => {
-                        selection.prototype = Some(rules.prototypes[e.prototype_index].clone())
-                    }
+                    Ok(tile) => match rules.prototype(tile) {
+                        Some(prototype) => selection.prototype = Some(prototype.clone()),
+                        None => error!("No prototype {} in the palette", tile.prototype_index),
+                    },
                     Err(_) => (),
                 };
             }
@@ -64,12 +503,324 @@ fn palette_select(
     }
 }
 
+/// Declare an adjacency by dragging from a palette tile to another, when enabled
+///
+/// The tile where the mouse is released is allowed on the chosen side of the first one.
+/// Dragging out of a tile and back onto it allows it next to itself.
+fn drag_adjacency(
+    mut events: EventReader<PickingEvent>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    tuning: Res<Tuning>,
+    mut drag: ResMut<AdjacencyDrag>,
+    palette_query: Query<&Tile, With<PaletteTag>>,
+    mut rules: ResMut<Rules>,
+    mut rules_events: EventWriter<RulesNeedUpdateEvent>,
+) {
+    for event in events.iter() {
+        match event {
+            PickingEvent::Hover(HoverEvent::JustEntered(e)) if palette_query.get(*e).is_ok() => {
+                drag.hovered = Some(*e);
+            }
+            PickingEvent::Hover(HoverEvent::JustLeft(e)) if drag.hovered == Some(*e) => {
+                drag.hovered = None;
+            }
+            _ => (),
+        }
+    }
+    if !tuning.declare_adjacencies {
+        if drag.from.is_some() {
+            drag.from = None;
+        }
+        return;
+    }
+
+    if mouse_button_input.just_pressed(MouseButton::Left) {
+        drag.from = drag.hovered;
+        drag.left_start = false;
+    }
+    if drag.from.is_some() && drag.hovered != drag.from {
+        drag.left_start = true;
+    }
+    if !mouse_button_input.just_released(MouseButton::Left) {
+        return;
+    }
+    let (from, to) = match (drag.from.take(), drag.hovered) {
+        (Some(from), Some(to)) if from != to || drag.left_start => (from, to),
+        // A click only selects the tile
+        _ => return,
+    };
+    let (tile, neighbour) = match (palette_query.get(from), palette_query.get(to)) {
+        (Ok(tile), Ok(neighbour)) => (*tile, *neighbour),
+        _ => return,
+    };
+    let side = drag.side;
+    let name = |tile: &Tile| match rules.prototype(tile) {
+        Some(prototype) => prototype.name.clone(),
+        None => tile.prototype_index.to_string(),
+    };
+    let description = format!(
+        "{} allowed on the {:?} side of {}",
+        name(&neighbour),
+        side,
+        name(&tile)
+    );
+    if rules.declared.insert((tile, side, neighbour)) {
+        info!("Declared {}", description);
+        rules.save_declared();
+        rules_events.send(RulesNeedUpdateEvent {});
+    }
+}
+
+/// Select the prototypes with the number keys, by banks of ten
+///
+/// 1 to 0 select the first ten prototypes, Shift and Ctrl select the next banks.
+fn keyboard_palette_select(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut selection: ResMut<TileSelection>,
+    rules: Res<Rules>,
+) {
+    const KEYS: [KeyCode; 10] = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+        KeyCode::Key0,
+    ];
+
+    let bank = if ctrl_pressed(&keyboard_input) {
+        2
+    } else if shift_pressed(&keyboard_input) {
+        1
+    } else {
+        0
+    };
+
+    for (slot, key) in KEYS.iter().enumerate() {
+        if keyboard_input.just_pressed(*key) {
+            if let Some(prototype) = rules.prototypes.get(bank * KEYS.len() + slot) {
+                selection.prototype = Some(prototype.clone());
+            }
+        }
+    }
+}
+
+fn keyboard_regenerate(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut event_writer: EventWriter<RegenerateEvent>,
+) {
+    if keyboard_input.just_pressed(KeyCode::R) && !shift_pressed(&keyboard_input) {
+        event_writer.send(RegenerateEvent {});
+    }
+}
+
+/// Observe the last observed cell again with Shift+R
+fn keyboard_reroll(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut event_writer: EventWriter<RerollLastEvent>,
+) {
+    if keyboard_input.just_pressed(KeyCode::R) && shift_pressed(&keyboard_input) {
+        event_writer.send(RerollLastEvent {});
+    }
+}
+
+fn keyboard_finalize(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut event_writer: EventWriter<FinalizeEvent>,
+) {
+    if keyboard_input.just_pressed(KeyCode::F) {
+        event_writer.send(FinalizeEvent {});
+    }
+}
+
+fn keyboard_frame_map(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut event_writer: EventWriter<FrameMapEvent>,
+) {
+    if keyboard_input.just_pressed(KeyCode::Home) {
+        event_writer.send(FrameMapEvent {});
+    }
+}
+
+fn keyboard_stamp_rule_map(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut event_writer: EventWriter<StampRuleMapEvent>,
+) {
+    if keyboard_input.just_pressed(KeyCode::T) {
+        event_writer.send(StampRuleMapEvent {});
+    }
+}
+
+/// Grow or shrink the rule map from its bottom right corner with Alt and the arrow keys
+fn keyboard_resize_rule_map(keyboard_input: Res<Input<KeyCode>>, mut tuning: ResMut<Tuning>) {
+    const RESIZES: [(KeyCode, i32, i32); 4] = [
+        (KeyCode::Up, 0, -1),
+        (KeyCode::Down, 0, 1),
+        (KeyCode::Left, -1, 0),
+        (KeyCode::Right, 1, 0),
+    ];
+    const MAX_SIZE: i32 = 64;
+
+    if !alt_pressed(&keyboard_input) {
+        return;
+    }
+    for (key, dw, dh) in RESIZES {
+        if !keyboard_input.just_pressed(key) {
+            continue;
+        }
+        let width = (tuning.rule_map_width as i32 + dw).clamp(1, MAX_SIZE) as usize;
+        let height = (tuning.rule_map_height as i32 + dh).clamp(1, MAX_SIZE) as usize;
+        if (width, height) != (tuning.rule_map_width, tuning.rule_map_height) {
+            tuning.rule_map_width = width;
+            tuning.rule_map_height = height;
+        }
+    }
+}
+
+/// Ban or allow back all the variants of the selected prototype
+fn keyboard_ban(
+    keyboard_input: Res<Input<KeyCode>>,
+    selection: Res<TileSelection>,
+    mut banned: ResMut<BannedTiles>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if !keyboard_input.just_pressed(KeyCode::B) {
+        return;
+    }
+    let prototype = match &selection.prototype {
+        Some(prototype) => prototype,
+        None => return,
+    };
+
+    let variants: HashSet<Tile> = (0..4)
+        .map(|rotation| prototype.make_rotated_tile(Orientation::North, rotation))
+        .collect();
+    if variants.is_subset(&banned.0) {
+        info!("Allowing prototype {}", prototype.index);
+        banned.0.retain(|tile| !variants.contains(tile));
+    } else {
+        info!("Banning prototype {}", prototype.index);
+        banned.0.extend(variants);
+    }
+    event_writer.send(RulesNeedUpdateEvent {});
+}
+
+/// Print the expanded adjacency rules, to check the generated tile variants
+///
+/// They are also written as a Graphviz graph, outside of the browser.
+fn keyboard_dump_rules(keyboard_input: Res<Input<KeyCode>>, rules: Res<Rules>) {
+    if !keyboard_input.just_pressed(KeyCode::F3) {
+        return;
+    }
+
+    let description = describe_rules(&rules.alloweds);
+    info!("Adjacency rules:\n{}", description);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(e) = std::fs::write("rules_dump.txt", &description) {
+        warn!("Failed to write rules_dump.txt: {}", e);
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Err(e) = std::fs::write("rules.dot", crate::wcf::rules_to_dot(&rules.alloweds)) {
+        warn!("Failed to write rules.dot: {}", e);
+    }
+}
+
+/// Clear the rule map when Delete or Backspace is pressed twice in a row
+fn keyboard_clear_rules(
+    keyboard_input: Res<Input<KeyCode>>,
+    time: Res<Time>,
+    cursor: Res<CursorCell>,
+    mut first_press: Local<Option<f64>>,
+    mut event_writer: EventWriter<ClearRulesEvent>,
+) {
+    const CONFIRMATION_DELAY: f64 = 1.0;
+
+    // Delete clears the cursor cell instead while the cursor is shown
+    let delete = keyboard_input.just_pressed(KeyCode::Delete) && cursor.coordinates.is_none();
+    if !delete && !keyboard_input.just_pressed(KeyCode::Back) {
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+    match *first_press {
+        Some(first) if now - first < CONFIRMATION_DELAY => {
+            *first_press = None;
+            event_writer.send(ClearRulesEvent {});
+        }
+        _ => {
+            info!("Press again to clear the rule map");
+            *first_press = Some(now);
+        }
+    }
+}
+
+fn clear_rules(
+    mut query: Query<(Entity, &mut OptionalTile), With<RuleTileTag>>,
+    tuning: Res<Tuning>,
+    mut event_reader: EventReader<ClearRulesEvent>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if event_reader.iter().count() == 0 {
+        return;
+    }
+
+    let empty = OptionalTile::default();
+    edit_history.end_step();
+    for (entity, mut map_tile) in query.iter_mut() {
+        if *map_tile != empty {
+            edit_history.record(entity, map_tile.clone(), tuning.undo_history_size);
+            *map_tile = empty.clone();
+        }
+    }
+    edit_history.end_step();
+
+    event_writer.send(RulesNeedUpdateEvent {});
+}
+
+/// Rotate the selection, or its preview on the hovered rule tile when enabled
 fn on_mouse_wheel(
     mut mouse_wheel_events: EventReader<MouseWheel>,
+    query: Query<(Entity, &Hover), With<RuleTileTag>>,
+    tuning: Res<Tuning>,
     mut selection: ResMut<TileSelection>,
+    mut hover_rotation: ResMut<HoverRotation>,
 ) {
+    let hovered = match tuning.hover_rotation {
+        true => query
+            .iter()
+            .find(|(_, hover)| hover.hovered())
+            .map(|(entity, _)| entity),
+        false => None,
+    };
+    if hover_rotation.entity != hovered {
+        *hover_rotation = HoverRotation {
+            entity: hovered,
+            rotation: 0,
+        };
+    }
+
     for event in mouse_wheel_events.iter() {
         let increment = if event.y > 0.0 { 1 } else { -1 };
-        selection.rotation += increment;
+        match hovered {
+            Some(_) => {
+                hover_rotation.rotation = (hover_rotation.rotation + increment).rem_euclid(4)
+            }
+            None => selection.rotate(increment),
+        }
+    }
+}
+
+fn keyboard_rotate(keyboard_input: Res<Input<KeyCode>>, mut selection: ResMut<TileSelection>) {
+    if keyboard_input.any_just_pressed([KeyCode::Q, KeyCode::Comma]) {
+        selection.rotate(-1);
+    }
+    if keyboard_input.any_just_pressed([KeyCode::E, KeyCode::Period]) {
+        selection.rotate(1);
     }
 }
@@ -0,0 +1,94 @@
+use std::collections::HashSet;
+
+use crate::components::*;
+use bevy::{asset::HandleId, asset::LoadState, prelude::*};
+
+/// Wait for the tile models before starting, with a progress text
+pub struct LoadingPlugin;
+
+impl Plugin for LoadingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_state(AppState::Loading)
+            .add_system_set(
+                SystemSet::on_enter(AppState::Loading).with_system(setup_loading_screen),
+            )
+            .add_system_set(SystemSet::on_update(AppState::Loading).with_system(check_loading))
+            .add_system_set(
+                SystemSet::on_exit(AppState::Loading).with_system(cleanup_loading_screen),
+            );
+    }
+}
+
+/// Loading progress text, and its camera
+#[derive(Component, Default)]
+pub struct LoadingScreenTag;
+
+fn setup_loading_screen(mut commands: Commands, models: Res<ModelAssets>) {
+    commands
+        .spawn_bundle(UiCameraBundle::default())
+        .insert(LoadingScreenTag);
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                margin: Rect::all(Val::Auto),
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: models.font.clone(),
+                    font_size: 32.0,
+                    color: Color::WHITE,
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert_bundle((Name::from("loading"), LoadingScreenTag));
+}
+
+fn check_loading(
+    asset_server: Res<AssetServer>,
+    rules: Res<Rules>,
+    mut state: ResMut<State<AppState>>,
+    mut failed: Local<HashSet<HandleId>>,
+    mut query: Query<&mut Text, With<LoadingScreenTag>>,
+) {
+    let handles: HashSet<HandleId> = rules
+        .prototypes
+        .iter()
+        .flat_map(|prototype| {
+            let decorations = prototype.decorations.iter().map(|decoration| decoration.id);
+            [prototype.model.id, prototype.display_model.id]
+                .into_iter()
+                .chain(decorations)
+        })
+        .collect();
+
+    let mut loaded = 0;
+    for handle in handles.iter() {
+        match asset_server.get_load_state(*handle) {
+            LoadState::Loaded => loaded += 1,
+            // Missing models are left empty instead of blocking the start
+            LoadState::Failed if failed.insert(*handle) => {
+                let path = asset_server.get_handle_path(*handle);
+                warn!("Failed to load {:?}, skipping it", path);
+            }
+            _ => {}
+        }
+    }
+
+    let done = loaded + failed.len();
+    for mut text in query.iter_mut() {
+        text.sections[0].value = format!("loading models {}/{}", done, handles.len());
+    }
+    if done == handles.len() {
+        state.set(AppState::Running).unwrap();
+    }
+}
+
+fn cleanup_loading_screen(mut commands: Commands, query: Query<Entity, With<LoadingScreenTag>>) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+}
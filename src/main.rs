@@ -1,40 +1,265 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    str::FromStr,
+};
 
 use bevy::prelude::*;
 use bevy_embedded_assets::EmbeddedAssetPlugin;
 use bevy_inspector_egui::InspectorPlugin;
 use bevy_mod_picking::*;
 
+#[cfg(feature = "capture")]
+mod capture;
 mod components;
 use components::*;
 use serde::{Deserialize, Serialize};
 mod display;
 mod input;
+mod loading;
+mod minimap;
+#[cfg(not(target_arch = "wasm32"))]
+mod presets;
+#[cfg(not(target_arch = "wasm32"))]
+mod scene_export;
+#[cfg(not(target_arch = "wasm32"))]
+mod snapshot;
 mod wcf;
 fn main() {
+    let options = match LaunchOptions::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    // Print a generated map in the terminal, without opening any window
+    if options.headless {
+        headless(&options);
+        return;
+    }
+
     let mut app = App::new();
 
     app.add_plugins_with(DefaultPlugins, |group| {
         group.add_before::<bevy::asset::AssetPlugin, _>(EmbeddedAssetPlugin)
     })
     .add_plugin(InspectorPlugin::<Tuning>::new())
+    .add_plugin(InspectorPlugin::<CollapseStats>::new())
+    .add_plugin(InspectorPlugin::<AutoRetry>::new())
+    .add_plugin(InspectorPlugin::<UiLayout>::new())
+    .add_plugin(InspectorPlugin::<WaveReplay>::new())
+    .add_plugin(InspectorPlugin::<OrientationMapping>::new())
     .add_plugins(DefaultPickingPlugins)
     .add_plugin(components::ComponentsPlugin)
     .add_plugin(wcf::WCFPlugin)
     .add_plugin(display::DisplayPlugin)
     .add_plugin(input::InputPlugin)
-    .add_startup_system(setup)
+    .add_plugin(minimap::MinimapPlugin)
+    .add_plugin(loading::LoadingPlugin)
+    .add_system_set(SystemSet::on_enter(AppState::Running).with_system(setup))
+    .add_system(request_map_resize)
+    .add_system(resize_map)
+    .add_system(resize_rule_map)
     .insert_resource(AmbientLight {
         color: Color::WHITE,
         brightness: 1.0 / 5.0f32,
     });
 
+    options.apply(&mut app.world);
+    app.insert_resource(options);
+
+    // No file system on the web
+    #[cfg(not(target_arch = "wasm32"))]
+    app.add_plugin(InspectorPlugin::<presets::RulePresets>::new())
+        .add_plugin(presets::PresetsPlugin)
+        .add_plugin(snapshot::SnapshotPlugin)
+        .add_plugin(scene_export::SceneExportPlugin);
+
+    #[cfg(feature = "capture")]
+    app.add_plugin(InspectorPlugin::<capture::FrameCapture>::new())
+        .add_plugin(capture::CapturePlugin);
+
     #[cfg(feature = "save_rule_map")]
     app.add_system(save_rules);
     app.run();
 }
 
-fn setup(mut commands: Commands, rules: Res<Rules>, models: Res<ModelAssets>) {
+/// Starting configuration given on the command line, the defaults being kept otherwise
+#[derive(Default)]
+struct LaunchOptions {
+    headless: bool,
+    /// Rule map to start from, instead of the embedded one
+    rules: Option<PathBuf>,
+    /// Adjacency list replacing the rule map as the source of the rules
+    adjacencies: Option<PathBuf>,
+    width: Option<usize>,
+    height: Option<usize>,
+    seed: Option<u64>,
+}
+
+impl LaunchOptions {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        fn value<T: FromStr>(
+            args: &mut impl Iterator<Item = String>,
+            name: &str,
+        ) -> Result<T, String> {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value after {}", name))?;
+            value
+                .parse()
+                .map_err(|_| format!("invalid value {} for {}", value, name))
+        }
+
+        let mut options = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--rules" => options.rules = Some(value(&mut args, &arg)?),
+                "--adjacencies" => options.adjacencies = Some(value(&mut args, &arg)?),
+                "--width" => options.width = Some(value(&mut args, &arg)?),
+                "--height" => options.height = Some(value(&mut args, &arg)?),
+                "--seed" => options.seed = Some(value(&mut args, &arg)?),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        if options.width == Some(0) || options.height == Some(0) {
+            return Err("the map can't be empty".to_string());
+        }
+        Ok(options)
+    }
+
+    /// Override the settings and the rules created with their defaults
+    fn apply(&self, world: &mut World) {
+        let mut tuning = world.get_resource_mut::<Tuning>().unwrap();
+        tuning.map_width = self.width.unwrap_or(tuning.map_width);
+        tuning.map_height = self.height.unwrap_or(tuning.map_height);
+
+        let mut rules = world.get_resource_mut::<Rules>().unwrap();
+        rules.source = self.rule_source(&rules.prototypes);
+
+        if let Some(seed) = self.seed {
+            world.insert_resource(GenerationInfo { seed, attempt: 0 });
+        }
+    }
+
+    /// Adjacency list to learn the rules from if given, the rule map if it can't be read
+    fn rule_source(&self, prototypes: &[Prototype]) -> RuleSource {
+        let path = match &self.adjacencies {
+            Some(path) => path,
+            None => return RuleSource::ExampleMap,
+        };
+        let adjacencies = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| wcf::parse_adjacency_list(&text, prototypes));
+        match adjacencies {
+            Ok(adjacencies) => RuleSource::AdjacencyList(adjacencies),
+            Err(e) => {
+                error!("Failed to load {}: {}", path.display(), e);
+                RuleSource::ExampleMap
+            }
+        }
+    }
+
+    /// Rule map to start from, the embedded one if none is given or if it can't be read
+    ///
+    /// An empty map of the rule map size of `tuning` is the last resort.
+    fn rule_map(&self, tuning: &Tuning) -> MapRule {
+        let default_map = || match MapRule::default_map() {
+            Ok(map) => map,
+            Err(e) => {
+                warn!("Failed to read the default rule map: {}", e);
+                MapRule::empty(tuning.rule_map_width, tuning.rule_map_height)
+            }
+        };
+        let path = match &self.rules {
+            Some(path) => path,
+            None => return default_map(),
+        };
+        let map = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|serialized| serde_json::from_str(&serialized).map_err(|e| e.to_string()));
+        match map {
+            Ok(map) => map,
+            Err(e) => {
+                warn!("Failed to load {}: {}", path.display(), e);
+                default_map()
+            }
+        }
+    }
+}
+
+fn headless(options: &LaunchOptions) {
+    let seed = options.seed.unwrap_or_else(rand::random);
+
+    let tuning = Tuning::default();
+    let width = options.width.unwrap_or(tuning.map_width);
+    let height = options.height.unwrap_or(tuning.map_height);
+    let mut rules = Rules::headless();
+    rules.source = options.rule_source(&rules.prototypes);
+    rules.alloweds = wcf::learn_source(&rules, &options.rule_map(&tuning).map, false);
+    wcf::declare(
+        &mut rules.alloweds,
+        &Rules::load_declared(),
+        &rules.prototypes,
+        false,
+    );
+    wcf::forbid(&mut rules.alloweds, &Rules::load_forbidden());
+
+    println!("seed: {}", seed);
+    let solution = wcf::solve_with(
+        &rules,
+        width,
+        height,
+        seed,
+        wcf::SolveOptions {
+            constrained_tie_break: tuning.constrained_tie_break,
+            parallel: tuning.parallel_propagation,
+            lookahead: tuning.lookahead_observation,
+            freeze_settled: tuning.freeze_settled,
+        },
+    );
+    eprintln!("{} backtracks", solution.backtracks);
+    match solution.map {
+        Ok(map) => {
+            // One tile per column: prototype index and orientation initial
+            for y in (0..height).rev() {
+                let line: Vec<String> = map
+                    .iter()
+                    .rev()
+                    .map(|column| {
+                        let tile = &column[y];
+                        format!(
+                            "{:2}{:.1}",
+                            tile.prototype_index,
+                            format!("{:?}", tile.orientation)
+                        )
+                    })
+                    .collect();
+                println!("{}", line.join(" "));
+            }
+        }
+        Err(contradiction) => {
+            eprintln!("{:?}", contradiction);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    mut commands: Commands,
+    rules: Res<Rules>,
+    banned: Res<BannedTiles>,
+    tuning: Res<Tuning>,
+    layout: Res<UiLayout>,
+    mapping: Res<OrientationMapping>,
+    options: Res<LaunchOptions>,
+    models: Res<ModelAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
     const HALF_SIZE: f32 = 1.0;
     commands.spawn_bundle(DirectionalLightBundle {
         directional_light: DirectionalLight {
@@ -53,28 +278,74 @@ fn setup(mut commands: Commands, rules: Res<Rules>, models: Res<ModelAssets>) {
         ..default()
     });
 
+    // Focus cell marker
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: models.focus_mesh.clone(),
+            material: models.focus_mat.clone(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert_bundle((Name::from("focus_marker"), FocusMarkerTag));
+
+    // Keyboard cursor marker
+    commands
+        .spawn_bundle(PbrBundle {
+            mesh: models.focus_mesh.clone(),
+            material: models.cursor_mat.clone(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert_bundle((Name::from("cursor_marker"), CursorMarkerTag));
+
+    // Overlay
+    commands.spawn_bundle(UiCameraBundle::default());
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(5.0),
+                    left: Val::Px(5.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: models.font.clone(),
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert_bundle((Name::from("selection_hint"), SelectionHintTag));
+
     commands
         .spawn_bundle(TransformBundle::default())
         .insert_bundle((Name::from("camera"), CameraHoldTag::default()))
         .with_children(|commands| {
             commands
                 .spawn_bundle(PerspectiveCameraBundle {
-                    transform: Transform::from_xyz(0.0, 10.0, 30.0)
-                        .looking_at(Vec3::new(0.0, 0.0, 0.0), Vec3::Y),
+                    transform: MainCameraTag::framing(
+                        tuning.map_width,
+                        tuning.map_height,
+                        tuning.tile_scale,
+                        PerspectiveProjection::default().fov,
+                    ),
                     ..default()
                 })
                 .insert_bundle(PickingCameraBundle::default())
+                .insert(MainCameraTag)
                 .with_children(|camera| {
-                    let rules_width = 16;
-                    let rules_height = 16;
+                    let rules_width = tuning.rule_map_width;
+                    let rules_height = tuning.rule_map_height;
                     // UI
                     camera
-                        .spawn_bundle(TransformBundle::from(
-                            Transform::identity()
-                                .looking_at(Vec3::Y, Vec3::Z)
-                                .with_translation(Vec3::new(-1.4, -0.05, -2.0))
-                                .with_scale(Vec3::new(0.05, 0.05, 0.05)),
-                        ))
+                        .spawn_bundle(TransformBundle::from(layout.transform()))
                         .insert_bundle((Name::from("ui"), RuleMapTag::default()))
                         .with_children(|ui| {
                             // Palette
@@ -84,26 +355,34 @@ fn setup(mut commands: Commands, rules: Res<Rules>, models: Res<ModelAssets>) {
                                     for i in 0..rules.prototypes.len() {
                                         let prototype = &rules.prototypes[i];
                                         let model = prototype.model.clone();
-                                        let x = i as i32 % rules_width;
-                                        let y = -(i as i32 / rules_height) - 2;
                                         palette
                                             .spawn_bundle(PbrBundle {
                                                 material: models.pick_mat.clone(),
                                                 mesh: models.pick_mesh.clone(),
+                                                transform: Transform::from_translation(
+                                                    layout.palette_translation(i),
+                                                ),
                                                 ..Default::default()
                                             })
                                             .insert_bundle(PickableBundle::default())
                                             .insert_bundle((
                                                 Name::from(format!("tile proto {i}")),
-                                                Coordinates::new(x, y),
                                                 Tile::new(i, Orientation::North),
                                                 PaletteTag {},
                                             ))
                                             .with_children(|tile| {
                                                 tile.spawn_bundle((
-                                                    Transform::from_xyz(0.0, 0.2, 0.0)
-                                                        .with_scale(Vec3::new(0.9, 0.9, 0.9)),
+                                                    Transform::from_xyz(
+                                                        0.0,
+                                                        0.2 + prototype.height,
+                                                        0.0,
+                                                    )
+                                                    .with_scale(Vec3::new(0.9, 0.9, 0.9))
+                                                    .with_rotation(
+                                                        mapping.rotation(Orientation::North),
+                                                    ),
                                                     GlobalTransform::default(),
+                                                    PaletteModelTag,
                                                 ))
                                                 .with_children(|tile| {
                                                     tile.spawn_scene(model);
@@ -112,111 +391,334 @@ fn setup(mut commands: Commands, rules: Res<Rules>, models: Res<ModelAssets>) {
                                     }
                                 });
 
+                            // Current selection
+                            ui.spawn_bundle(PbrBundle {
+                                material: models.pick_mat.clone(),
+                                mesh: models.pick_mesh.clone(),
+                                transform: Transform::from_translation(
+                                    layout.selection_preview_translation(rules.prototypes.len()),
+                                ),
+                                ..Default::default()
+                            })
+                            .insert_bundle((
+                                Name::from("selection_preview"),
+                                DrawTile::default(),
+                                SelectionPreviewTag,
+                            ));
+
                             // Rule map
-                            let map_json = include_str!("default_rule_map.json");
-                            let map: MapRule = serde_json::from_str(map_json).unwrap();
+                            let map = options.rule_map(&tuning);
 
-                            ui.spawn_bundle(TransformBundle::default())
-                                .insert(Name::from("rule_map"))
-                                .with_children(|rule_map| {
-                                    for x in 0..rules_width {
-                                        for y in 0..rules_height {
-                                            let tile = &map.map[x as usize][y as usize];
-                                            rule_map
-                                                .spawn_bundle(PbrBundle {
-                                                    material: models.pick_mat.clone(),
-                                                    mesh: models.pick_mesh.clone(),
-                                                    ..Default::default()
-                                                })
-                                                .insert_bundle((
-                                                    Name::from(format!("{x}:{y}")),
-                                                    Coordinates::new(x as i32, y as i32),
-                                                    tile.clone(),
-                                                    DrawTile::default(),
-                                                    RuleTileTag::default(),
-                                                ))
-                                                .insert_bundle(PickableBundle::default());
-                                        }
+                            ui.spawn_bundle(TransformBundle::from(Transform::from_translation(
+                                layout.rule_map_translation(),
+                            )))
+                            .insert_bundle((Name::from("rule_map"), RuleMapRootTag))
+                            .with_children(|rule_map| {
+                                for x in 0..rules_width {
+                                    for y in 0..rules_height {
+                                        // Smaller rule maps are padded with empty tiles
+                                        let tile = map
+                                            .map
+                                            .get(x)
+                                            .and_then(|line| line.get(y))
+                                            .cloned()
+                                            .unwrap_or_default();
+                                        let coordinates = Coordinates::new(x as i32, y as i32);
+                                        spawn_rule_tile(rule_map, &models, coordinates, tile);
                                     }
-                                });
+                                }
+                            });
                         });
                 });
         });
 
-    // Generated map
-    let width = rules.width;
-    let height = rules.height;
-    let mut map_entities = vec![vec![Entity::from_raw(0); height]; width];
+    spawn_world_map(
+        &mut commands,
+        &rules,
+        &banned,
+        &tuning,
+        &models,
+        &mut meshes,
+    );
+}
+
+/// Spawn an editable cell of the rule map
+fn spawn_rule_tile(
+    rule_map: &mut ChildBuilder,
+    models: &ModelAssets,
+    coordinates: Coordinates,
+    tile: OptionalTile,
+) {
+    rule_map
+        .spawn_bundle(PbrBundle {
+            material: models.pick_mat.clone(),
+            mesh: models.pick_mesh.clone(),
+            ..Default::default()
+        })
+        .insert_bundle((
+            Name::from(format!("{}:{}", coordinates.x, coordinates.y)),
+            coordinates,
+            tile,
+            DrawTile::default(),
+            RuleTileTag::default(),
+        ))
+        .insert_bundle(PickableBundle::default());
+}
+
+/// Grow or shrink the rule map to its size in the settings
+///
+/// The tiles in both sizes are kept, the new ones are empty.
+fn resize_rule_map(
+    mut commands: Commands,
+    tuning: Res<Tuning>,
+    models: Res<ModelAssets>,
+    root_query: Query<Entity, With<RuleMapRootTag>>,
+    rules_query: Query<(Entity, &Coordinates), With<RuleTileTag>>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+    // Spawned with the rest of the editor UI
+    let root = match root_query.get_single() {
+        Ok(root) => root,
+        Err(_) => return,
+    };
+
+    let (width, height) = (tuning.rule_map_width as i32, tuning.rule_map_height as i32);
+    let mut kept = HashSet::new();
+    let mut resized = false;
+    for (entity, coordinates) in rules_query.iter() {
+        if (0..width).contains(&coordinates.x) && (0..height).contains(&coordinates.y) {
+            kept.insert(*coordinates);
+        } else {
+            commands.entity(entity).despawn_recursive();
+            resized = true;
+        }
+    }
+    commands.entity(root).with_children(|rule_map| {
+        for x in 0..width {
+            for y in 0..height {
+                let coordinates = Coordinates::new(x, y);
+                if !kept.contains(&coordinates) {
+                    spawn_rule_tile(rule_map, &models, coordinates, OptionalTile::default());
+                    resized = true;
+                }
+            }
+        }
+    });
+
+    if resized {
+        event_writer.send(RulesNeedUpdateEvent {});
+    }
+}
+
+/// Spawn the generated map cells in their initial state
+fn spawn_world_map(
+    commands: &mut Commands,
+    rules: &Rules,
+    banned: &BannedTiles,
+    tuning: &Tuning,
+    models: &ModelAssets,
+    meshes: &mut Assets<Mesh>,
+) {
+    let width = tuning.map_width;
+    let height = tuning.map_height;
+    let mask = MapMask::load();
+    let possible_tiles = rules.possible_tiles(banned);
+    let mut entities = HashMap::new();
     commands
-        .spawn_bundle(TransformBundle::from_transform(Transform::from_xyz(
-            -((width / 2) as f32),
-            0.0,
-            -((height / 2) as f32),
+        .spawn_bundle(TransformBundle::from_transform(WorldMapTag::transform(
+            width,
+            height,
+            tuning.tile_scale,
         )))
-        .insert(Name::from("world_map"))
+        .insert_bundle((Name::from("world_map"), WorldMapTag))
         .with_children(|rule_map| {
             for x in 0..width {
                 for y in 0..height {
+                    if !mask.contains(x, y) {
+                        continue;
+                    }
+                    let coordinates = Coordinates::new(x as i32, y as i32);
                     let entity = rule_map
-                        .spawn_bundle(TransformBundle::default())
+                        .spawn_bundle(PbrBundle {
+                            mesh: models.pick_mesh.clone(),
+                            material: models.cell_pick_mat.clone(),
+                            ..default()
+                        })
                         .insert_bundle((
                             Name::from(format!("{x}:{y}")),
-                            Coordinates::new(x as i32, y as i32),
-                            TileSuperposition::default(),
+                            coordinates,
+                            TileSuperposition {
+                                tiles: possible_tiles.clone(),
+                                dirty: false,
+                            },
                             TileSuperpositionHistory::default(),
+                            WaveTrace::default(),
                         ))
+                        .insert_bundle(PickableBundle::default())
                         .id();
-                    map_entities[x][y] = entity;
+                    entities.insert(coordinates, entity);
                 }
             }
+
+            // Grid lines at the cell boundaries
+            let line_width = 0.02;
+            let x_line = meshes.add(shape::Box::new(width as f32, 0.01, line_width).into());
+            let y_line = meshes.add(shape::Box::new(line_width, 0.01, height as f32).into());
+            rule_map
+                .spawn_bundle(TransformBundle::default())
+                .insert_bundle((Name::from("grid"), GridTag))
+                .with_children(|grid| {
+                    let visibility = Visibility {
+                        is_visible: tuning.show_grid,
+                    };
+                    for x in 0..=width {
+                        grid.spawn_bundle(PbrBundle {
+                            mesh: y_line.clone(),
+                            material: models.grid_mat.clone(),
+                            visibility: visibility.clone(),
+                            transform: Transform::from_xyz(
+                                x as f32 - 0.5,
+                                0.02,
+                                (height as f32 - 1.0) / 2.0,
+                            ),
+                            ..default()
+                        });
+                    }
+                    for y in 0..=height {
+                        grid.spawn_bundle(PbrBundle {
+                            mesh: x_line.clone(),
+                            material: models.grid_mat.clone(),
+                            visibility: visibility.clone(),
+                            transform: Transform::from_xyz(
+                                (width as f32 - 1.0) / 2.0,
+                                0.02,
+                                y as f32 - 0.5,
+                            ),
+                            ..default()
+                        });
+                    }
+                });
         });
 
-    // Compute connectivity
-    for x in 0..width {
-        for y in 0..height {
-            let mut entity = commands.entity(map_entities[x][y]);
-            let coord = Coordinates::new(x as i32, y as i32);
-            let mut connectivity = HashMap::new();
-            for orientation in Orientation::values() {
-                let neighbour_coords = orientation.offset(&coord);
-                if let Some(e) = get_tile_entity(&map_entities, &neighbour_coords) {
-                    connectivity.insert(orientation, e);
-                }
-            }
-            entity.insert(Connectivity { connectivity });
-        }
+    // Connect the cells to their neighbours
+    for (coordinates, entity) in entities.iter() {
+        commands.entity(*entity).insert(Connectivity::new(
+            &entities,
+            coordinates,
+            width,
+            height,
+            tuning.wrap,
+        ));
     }
 }
 
-/// Safe tile get from indexes
-fn get_tile_entity(map: &Vec<Vec<Entity>>, coordinates: &Coordinates) -> Option<Entity> {
-    if coordinates.x < 0 || coordinates.y < 0 {
-        return None;
+/// Respawn the generated map when its size changes in the settings
+fn request_map_resize(
+    tuning: Res<Tuning>,
+    mut size: Local<Option<(usize, usize)>>,
+    mut event_writer: EventWriter<ResizeMapEvent>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    let new_size = (tuning.map_width, tuning.map_height);
+    if matches!(*size, Some(old_size) if old_size != new_size) {
+        event_writer.send(ResizeMapEvent {});
+    }
+    *size = Some(new_size);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn resize_map(
+    mut commands: Commands,
+    mut event_reader: EventReader<ResizeMapEvent>,
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    models: Res<ModelAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    world_map_query: Query<Entity, With<WorldMapTag>>,
+    mut history: ResMut<GuessHistory>,
+    mut map_edits: ResMut<MapEditHistory>,
+    mut focus: ResMut<FocusCell>,
+    banned: Res<BannedTiles>,
+    mut state: ResMut<GenerationState>,
+    mut rng: ResMut<GenerationRng>,
+    info: Res<GenerationInfo>,
+) {
+    if event_reader.iter().count() == 0 {
+        return;
+    }
+
+    for world_map in world_map_query.iter() {
+        commands.entity(world_map).despawn_recursive();
     }
-    let line = map.get(coordinates.x as usize)?;
-    let tile = line.get(coordinates.y as usize)?;
-    Some(tile.clone())
+    spawn_world_map(
+        &mut commands,
+        &rules,
+        &banned,
+        &tuning,
+        &models,
+        &mut meshes,
+    );
+
+    // The guesses and edits refer to the despawned cells
+    history.history.clear();
+    map_edits.undo.clear();
+    focus.0 = None;
+    // The same seed gives the same map at a given size
+    info.restart(&mut rng);
+    state.idle = false;
 }
 
-#[derive(Serialize, Deserialize)]
-struct MapRule {
-    pub map: Vec<Vec<OptionalTile>>,
+/// Cells existing in the generated map, for irregular footprints
+#[derive(Default, Serialize, Deserialize)]
+struct MapMask {
+    pub mask: Vec<Vec<bool>>,
+}
+
+impl MapMask {
+    const PATH: &'static str = "mask.json";
+
+    /// Read the optional mask file, every cell exists without it
+    fn load() -> Self {
+        let serialized = match std::fs::read_to_string(Self::PATH) {
+            Ok(serialized) => serialized,
+            Err(_) => return Self::default(),
+        };
+        match serde_json::from_str(&serialized) {
+            Ok(mask) => mask,
+            Err(e) => {
+                warn!("Failed to read {}: {}", Self::PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Cells out of the mask bounds exist
+    fn contains(&self, x: usize, y: usize) -> bool {
+        match self.mask.get(x).and_then(|line| line.get(y)) {
+            Some(present) => *present,
+            None => true,
+        }
+    }
 }
 
 #[cfg(feature = "save_rule_map")]
 fn save_rules(
     rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
+    tuning: Res<Tuning>,
     mut event_reader: EventReader<RulesNeedUpdateEvent>,
 ) {
     for _ in event_reader.iter() {
-        // Read the rule map
-        let rule_width = 16;
-        let rule_height = 16;
-        let mut rule_tiles = vec![vec![OptionalTile::default(); rule_width]; rule_height];
-        for (tile, coordinates) in rules_query.iter() {
-            rule_tiles[coordinates.x as usize][coordinates.y as usize] = tile.clone();
-        }
-        let map = MapRule { map: rule_tiles };
+        let map = MapRule::read(
+            rules_query.iter(),
+            tuning.rule_map_width,
+            tuning.rule_map_height,
+        );
 
         let serialized = serde_json::to_string_pretty(&map).unwrap();
         std::fs::write("src/default_rule_map.json", serialized).unwrap();
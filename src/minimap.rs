@@ -0,0 +1,217 @@
+use crate::components::*;
+use bevy::{
+    core_pipeline::{draw_3d_graph, node, AlphaMask3d, Opaque3d, Transparent3d},
+    prelude::*,
+    render::{
+        camera::{
+            ActiveCamera, CameraProjection, CameraTypePlugin, DepthCalculation, RenderTarget,
+            ScalingMode,
+        },
+        primitives::Frustum,
+        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, SlotValue},
+        render_phase::RenderPhase,
+        render_resource::{
+            Extent3d, TextureDescriptor, TextureDimension, TextureFormat, TextureUsages,
+        },
+        renderer::RenderContext,
+        view::VisibleEntities,
+        RenderApp, RenderStage,
+    },
+};
+
+/// Top-down view of the whole generated map, in a corner of the window
+pub struct MinimapPlugin;
+
+impl Plugin for MinimapPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(CameraTypePlugin::<MinimapCamera>::default())
+            .add_system(toggle_minimap)
+            .add_system(fit_minimap);
+
+        // Render the minimap camera before the main pass, like the main 3D camera
+        let render_app = app.sub_app_mut(RenderApp);
+        let driver = MinimapCameraDriver::new(&mut render_app.world);
+        render_app.add_system_to_stage(RenderStage::Extract, extract_minimap_camera_phases);
+
+        let mut graph = render_app.world.resource_mut::<RenderGraph>();
+        graph.add_node(MINIMAP_PASS_DRIVER, driver);
+        graph
+            .add_node_edge(node::MAIN_PASS_DEPENDENCIES, MINIMAP_PASS_DRIVER)
+            .unwrap();
+        graph
+            .add_node_edge(node::CLEAR_PASS_DRIVER, MINIMAP_PASS_DRIVER)
+            .unwrap();
+        graph
+            .add_node_edge(MINIMAP_PASS_DRIVER, node::MAIN_PASS_DRIVER)
+            .unwrap();
+    }
+}
+
+const MINIMAP_PASS_DRIVER: &str = "minimap_pass_driver";
+
+/// Size of the minimap texture and of its display, in pixels
+const MINIMAP_SIZE: u32 = 256;
+
+/// Camera rendering the minimap texture
+#[derive(Component, Default)]
+pub struct MinimapCamera;
+
+/// UI node displaying the minimap texture
+#[derive(Component, Default)]
+pub struct MinimapImageTag;
+
+fn extract_minimap_camera_phases(mut commands: Commands, active: Res<ActiveCamera<MinimapCamera>>) {
+    if let Some(entity) = active.get() {
+        commands.get_or_spawn(entity).insert_bundle((
+            RenderPhase::<Opaque3d>::default(),
+            RenderPhase::<AlphaMask3d>::default(),
+            RenderPhase::<Transparent3d>::default(),
+        ));
+    }
+}
+
+/// Run the 3D graph for the minimap camera
+struct MinimapCameraDriver {
+    query: QueryState<Entity, With<MinimapCamera>>,
+}
+
+impl MinimapCameraDriver {
+    pub fn new(render_world: &mut World) -> Self {
+        Self {
+            query: QueryState::new(render_world),
+        }
+    }
+}
+
+impl Node for MinimapCameraDriver {
+    fn update(&mut self, world: &mut World) {
+        self.query.update_archetypes(world);
+    }
+
+    fn run(
+        &self,
+        graph: &mut RenderGraphContext,
+        _render_context: &mut RenderContext,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        for camera in self.query.iter_manual(world) {
+            graph.run_sub_graph(draw_3d_graph::NAME, vec![SlotValue::Entity(camera)])?;
+        }
+        Ok(())
+    }
+}
+
+/// Half of the visible height, to see the whole map
+fn minimap_scale(tuning: &Tuning) -> f32 {
+    (tuning.map_width.max(tuning.map_height) as f32 / 2.0 + 1.0) * tuning.tile_scale
+}
+
+fn toggle_minimap(
+    mut commands: Commands,
+    tuning: Res<Tuning>,
+    mut images: ResMut<Assets<Image>>,
+    camera_query: Query<Entity, With<MinimapCamera>>,
+    image_query: Query<Entity, With<MinimapImageTag>>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+    let shown = !camera_query.is_empty();
+    if shown == tuning.show_minimap {
+        return;
+    }
+
+    if !tuning.show_minimap {
+        for entity in camera_query.iter().chain(image_query.iter()) {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    let size = Extent3d {
+        width: MINIMAP_SIZE,
+        height: MINIMAP_SIZE,
+        ..default()
+    };
+    let mut image = Image {
+        texture_descriptor: TextureDescriptor {
+            label: Some("minimap"),
+            size,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Bgra8UnormSrgb,
+            mip_level_count: 1,
+            sample_count: 1,
+            usage: TextureUsages::TEXTURE_BINDING
+                | TextureUsages::COPY_DST
+                | TextureUsages::RENDER_ATTACHMENT,
+        },
+        ..default()
+    };
+    image.resize(size);
+    let image = images.add(image);
+
+    // Looking down on the map, with the north on top like the main view
+    let projection = OrthographicProjection {
+        scaling_mode: ScalingMode::FixedVertical,
+        depth_calculation: DepthCalculation::Distance,
+        scale: minimap_scale(&tuning),
+        ..default()
+    };
+    let transform =
+        Transform::from_xyz(0.0, 100.0, 0.0).looking_at(Vec3::ZERO, Vec3::new(0.0, 0.0, -1.0));
+    commands
+        .spawn_bundle(OrthographicCameraBundle {
+            camera: Camera {
+                target: RenderTarget::Image(image.clone()),
+                near: projection.near,
+                far: projection.far,
+                ..default()
+            },
+            frustum: Frustum::from_view_projection(
+                &projection.get_projection_matrix(),
+                &Vec3::ZERO,
+                &Vec3::Z,
+                projection.far(),
+            ),
+            orthographic_projection: projection,
+            visible_entities: VisibleEntities::default(),
+            transform,
+            global_transform: default(),
+            marker: MinimapCamera,
+        })
+        .insert(Name::from("minimap_camera"));
+
+    commands
+        .spawn_bundle(ImageBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(5.0),
+                    right: Val::Px(5.0),
+                    ..default()
+                },
+                size: Size::new(Val::Px(MINIMAP_SIZE as f32), Val::Px(MINIMAP_SIZE as f32)),
+                ..default()
+            },
+            image: UiImage(image),
+            ..default()
+        })
+        .insert_bundle((Name::from("minimap"), MinimapImageTag));
+}
+
+/// Keep the whole map in the minimap after a resize
+fn fit_minimap(
+    tuning: Res<Tuning>,
+    mut query: Query<&mut OrthographicProjection, With<MinimapCamera>>,
+) {
+    if !tuning.is_changed() {
+        return;
+    }
+
+    for mut projection in query.iter_mut() {
+        let scale = minimap_scale(&tuning);
+        if projection.scale != scale {
+            projection.scale = scale;
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::components::*;
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::TextureFormat,
+        texture::{CompressedImageFormats, ImageType},
+    },
+};
+use bevy_inspector_egui::Inspectable;
+use serde::Deserialize;
+
+/// Saving and loading of the rule map presets from the rules directory
+pub struct PresetsPlugin;
+
+impl Plugin for PresetsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<RulePresets>()
+            .add_system(save_preset)
+            .add_system(cycle_presets)
+            .add_system(import_image);
+    }
+}
+
+const PRESETS_DIR: &str = "rules";
+
+#[derive(Inspectable)]
+pub struct RulePresets {
+    /// Name of the preset to save, or of the last loaded one
+    #[inspectable(label = "preset name")]
+    pub name: String,
+
+    #[inspectable(label = "available presets")]
+    pub available: Vec<String>,
+}
+
+impl Default for RulePresets {
+    fn default() -> Self {
+        Self {
+            name: "my_rules".to_string(),
+            available: list_presets(),
+        }
+    }
+}
+
+fn preset_path(name: &str) -> PathBuf {
+    PathBuf::from(PRESETS_DIR).join(format!("{name}.json"))
+}
+
+fn image_path(name: &str) -> PathBuf {
+    PathBuf::from(PRESETS_DIR).join(format!("{name}.png"))
+}
+
+fn colors_path(name: &str) -> PathBuf {
+    PathBuf::from(PRESETS_DIR).join(format!("{name}.colors.ron"))
+}
+
+/// Prototype index of each pixel color of an example image, colors being written `#rrggbb`
+#[derive(Deserialize)]
+struct ColorTable {
+    colors: HashMap<String, usize>,
+}
+
+impl ColorTable {
+    fn parse(&self) -> Result<HashMap<[u8; 3], usize>, String> {
+        self.colors
+            .iter()
+            .map(|(color, index)| {
+                let hex = color.trim_start_matches('#');
+                let channel = |i: usize| {
+                    hex.get(i..i + 2)
+                        .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+                        .ok_or_else(|| format!("invalid color {}", color))
+                };
+                if hex.len() != 6 {
+                    return Err(format!("invalid color {}", color));
+                }
+                Ok(([channel(0)?, channel(2)?, channel(4)?], *index))
+            })
+            .collect()
+    }
+}
+
+/// Rule map drawn by an example image, one pixel per rule tile
+///
+/// The top row of the image is the North of the map. Unknown colors are left empty and
+/// the pixels past the rule map are ignored.
+fn rule_map_from_image(
+    image: &Image,
+    colors: &HashMap<[u8; 3], usize>,
+    prototypes: &[Prototype],
+    rule_width: usize,
+    rule_height: usize,
+) -> Result<MapRule, String> {
+    let format = image.texture_descriptor.format;
+    if format != TextureFormat::Rgba8UnormSrgb && format != TextureFormat::Rgba8Unorm {
+        return Err(format!("unsupported pixel format {:?}", format));
+    }
+    let size = image.texture_descriptor.size;
+    let (width, height) = (size.width as usize, size.height as usize);
+
+    let mut map = vec![vec![OptionalTile::default(); rule_height]; rule_width];
+    for (x, line) in map.iter_mut().enumerate().take(width) {
+        for (y, tile) in line.iter_mut().enumerate().take(height) {
+            let pixel = ((height - 1 - y) * width + x) * 4;
+            let color = [
+                image.data[pixel],
+                image.data[pixel + 1],
+                image.data[pixel + 2],
+            ];
+            if let Some(prototype) = colors.get(&color).and_then(|index| prototypes.get(*index)) {
+                *tile = OptionalTile::new(Some(prototype.make_rotated_tile(Orientation::North, 0)));
+            }
+        }
+    }
+    Ok(MapRule { map })
+}
+
+/// Replace the rule map, as a single undoable edit
+fn apply_rule_map(
+    map: &MapRule,
+    rules_query: &mut Query<(Entity, &mut OptionalTile, &Coordinates), With<RuleTileTag>>,
+    tuning: &Tuning,
+    edit_history: &mut RuleEditHistory,
+    event_writer: &mut EventWriter<RulesNeedUpdateEvent>,
+) {
+    edit_history.end_step();
+    for (entity, mut map_tile, coordinates) in rules_query.iter_mut() {
+        let tile = map
+            .map
+            .get(coordinates.x as usize)
+            .and_then(|line| line.get(coordinates.y as usize))
+            .cloned()
+            .unwrap_or_default();
+        if *map_tile != tile {
+            edit_history.record(entity, map_tile.clone(), tuning.undo_history_size);
+            *map_tile = tile;
+        }
+    }
+    edit_history.end_step();
+    event_writer.send(RulesNeedUpdateEvent {});
+}
+
+/// Names of the presets found in the rules directory
+fn list_presets() -> Vec<String> {
+    let entries = match std::fs::read_dir(PRESETS_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut presets: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("json"))
+        .filter_map(|path| Some(path.file_stem()?.to_str()?.to_string()))
+        .collect();
+    presets.sort();
+    presets
+}
+
+/// Save the rule map under the preset name with Ctrl+S
+fn save_preset(
+    keyboard_input: Res<Input<KeyCode>>,
+    rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
+    tuning: Res<Tuning>,
+    mut presets: ResMut<RulePresets>,
+) {
+    let ctrl = keyboard_input.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+    if !ctrl || !keyboard_input.just_pressed(KeyCode::S) {
+        return;
+    }
+
+    let map = MapRule::read(
+        rules_query.iter(),
+        tuning.rule_map_width,
+        tuning.rule_map_height,
+    );
+
+    let path = preset_path(&presets.name);
+    let serialized = serde_json::to_string_pretty(&map).unwrap();
+    let result =
+        std::fs::create_dir_all(PRESETS_DIR).and_then(|_| std::fs::write(&path, serialized));
+    match result {
+        Ok(_) => info!("Saved the rule map to {}", path.display()),
+        Err(e) => warn!("Failed to save {}: {}", path.display(), e),
+    }
+
+    presets.available = list_presets();
+}
+
+/// Load the previous or next preset with Page Up and Page Down
+fn cycle_presets(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut rules_query: Query<(Entity, &mut OptionalTile, &Coordinates), With<RuleTileTag>>,
+    tuning: Res<Tuning>,
+    mut presets: ResMut<RulePresets>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    let step = if keyboard_input.just_pressed(KeyCode::PageDown) {
+        1
+    } else if keyboard_input.just_pressed(KeyCode::PageUp) {
+        -1
+    } else {
+        return;
+    };
+
+    presets.available = list_presets();
+    if presets.available.is_empty() {
+        warn!("No preset found in {PRESETS_DIR}");
+        return;
+    }
+
+    // Move from the current preset, or start from the first one
+    let count = presets.available.len() as i32;
+    let index = match presets
+        .available
+        .iter()
+        .position(|name| *name == presets.name)
+    {
+        Some(index) => (index as i32 + step).rem_euclid(count),
+        None => 0,
+    };
+    presets.name = presets.available[index as usize].clone();
+
+    let path = preset_path(&presets.name);
+    let map: MapRule = match std::fs::read_to_string(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|serialized| serde_json::from_str(&serialized).map_err(|e| e.to_string()))
+    {
+        Ok(map) => map,
+        Err(e) => {
+            warn!("Failed to load {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    apply_rule_map(
+        &map,
+        &mut rules_query,
+        &tuning,
+        &mut edit_history,
+        &mut event_writer,
+    );
+    info!("Loaded the rule map from {}", path.display());
+}
+
+/// Learn the rule map from the example image named after the preset with Ctrl+I
+#[allow(clippy::too_many_arguments)]
+fn import_image(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut rules_query: Query<(Entity, &mut OptionalTile, &Coordinates), With<RuleTileTag>>,
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    presets: Res<RulePresets>,
+    mut edit_history: ResMut<RuleEditHistory>,
+    mut event_writer: EventWriter<RulesNeedUpdateEvent>,
+) {
+    let ctrl = keyboard_input.any_pressed([KeyCode::LControl, KeyCode::RControl]);
+    if !ctrl || !keyboard_input.just_pressed(KeyCode::I) {
+        return;
+    }
+
+    let path = image_path(&presets.name);
+    let colors_path = colors_path(&presets.name);
+    let map = std::fs::read_to_string(&colors_path)
+        .map_err(|e| format!("{}: {}", colors_path.display(), e))
+        .and_then(|serialized| {
+            ron::from_str::<ColorTable>(&serialized)
+                .map_err(|e| format!("{}: {}", colors_path.display(), e))
+        })
+        .and_then(|table| table.parse())
+        .and_then(|colors| {
+            let buffer = std::fs::read(&path).map_err(|e| e.to_string())?;
+            let image = Image::from_buffer(
+                &buffer,
+                ImageType::Extension("png"),
+                CompressedImageFormats::NONE,
+                true,
+            )
+            .map_err(|e| e.to_string())?;
+            rule_map_from_image(
+                &image,
+                &colors,
+                &rules.prototypes,
+                tuning.rule_map_width,
+                tuning.rule_map_height,
+            )
+        });
+    let map = match map {
+        Ok(map) => map,
+        Err(e) => {
+            warn!("Failed to import {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    apply_rule_map(
+        &map,
+        &mut rules_query,
+        &tuning,
+        &mut edit_history,
+        &mut event_writer,
+    );
+    info!("Imported the rule map from {}", path.display());
+}
@@ -0,0 +1,125 @@
+use crate::components::*;
+use bevy::{prelude::*, reflect::TypeRegistryArc};
+
+/// Export of the generated map as a Bevy scene, to load it in other projects
+pub struct SceneExportPlugin;
+
+impl Plugin for SceneExportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(export_generated_map.exclusive_system());
+    }
+}
+
+const SCENE_PATH: &str = "generated_map.scn.ron";
+
+/// Scene of the resolved cells of the generated map, with the number of skipped cells
+///
+/// Each tile is an entity with its `Transform` relative to the map, a `Name` and its
+/// `GeneratedTile`. The cells still undecided or impossible are skipped, as well as
+/// the parts of the multi-cell tiles other than their anchor.
+pub fn export_scene(world: &mut World) -> (DynamicScene, usize) {
+    let mut scene_world = World::new();
+    let mut skipped = 0;
+    let mut query = world.query::<(&TileSuperposition, &Coordinates, &Transform)>();
+    let rules = world.resource::<Rules>();
+    let mapping = world.resource::<OrientationMapping>();
+    let mut cells: Vec<_> = query.iter(world).collect();
+    // Stable entity order across exports
+    cells.sort_by_key(|(_, coordinates, _)| (coordinates.x, coordinates.y));
+
+    for (wave, coordinates, cell_transform) in cells {
+        let tile = match wave.tiles.iter().next() {
+            Some(tile) if wave.tiles.len() == 1 => *tile,
+            _ => {
+                skipped += 1;
+                continue;
+            }
+        };
+        if tile.part != 0 {
+            continue;
+        }
+        let (height, model_path) = rules.prototype(&tile).map_or((0.0, ""), |prototype| {
+            (prototype.height, prototype.model_path.as_str())
+        });
+        let transform = cell_transform.mul_transform(
+            Transform::from_rotation(mapping.rotation(tile.orientation))
+                .with_translation(Vec3::new(0.0, height, 0.0)),
+        );
+        scene_world
+            .spawn()
+            .insert(transform)
+            .insert(Name::new(format!(
+                "{} {} ({}:{})",
+                model_path, tile, coordinates.x, coordinates.y
+            )))
+            .insert(GeneratedTile(tile));
+    }
+
+    let registry = world.resource::<TypeRegistryArc>();
+    (DynamicScene::from_world(&scene_world, registry), skipped)
+}
+
+/// Write the generated map to a scene file with F6
+fn export_generated_map(world: &mut World) {
+    if !world.resource::<Input<KeyCode>>().just_pressed(KeyCode::F6) {
+        return;
+    }
+
+    let (scene, skipped) = export_scene(world);
+    if skipped > 0 {
+        warn!("Skipped {} unresolved cells in the exported scene", skipped);
+    }
+    let written = scene
+        .serialize_ron(world.resource::<TypeRegistryArc>())
+        .map_err(|e| e.to_string())
+        .and_then(|serialized| std::fs::write(SCENE_PATH, serialized).map_err(|e| e.to_string()));
+    match written {
+        Ok(_) => info!("Exported the generated map to {}", SCENE_PATH),
+        Err(e) => warn!("Failed to export {}: {}", SCENE_PATH, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn export_resolved_cells() {
+        let mut world = World::new();
+        let registry = TypeRegistryArc::default();
+        registry.write().register::<Transform>();
+        registry.write().register::<Name>();
+        registry.write().register::<GeneratedTile>();
+        world.insert_resource(registry);
+        world.insert_resource(Rules::headless());
+        world.insert_resource(OrientationMapping::default());
+
+        let road = Tile::new(2, Orientation::East);
+        let waves = [
+            HashSet::from([road]),
+            HashSet::from([road, Tile::new(1, Orientation::North)]),
+            HashSet::new(),
+        ];
+        for (x, tiles) in waves.into_iter().enumerate() {
+            world
+                .spawn()
+                .insert(TileSuperposition {
+                    tiles,
+                    dirty: false,
+                })
+                .insert(Coordinates::new(x as i32, 0))
+                .insert(Transform::from_xyz(x as f32, 0.0, 0.0));
+        }
+
+        let (scene, skipped) = export_scene(&mut world);
+        assert!(skipped == 2);
+        assert!(scene.entities.len() == 1);
+        assert!(scene.entities[0].components.len() == 3);
+        let serialized = scene
+            .serialize_ron(world.resource::<TypeRegistryArc>())
+            .unwrap();
+        assert!(serialized.contains("GeneratedTile"));
+        assert!(serialized.contains("bridge_wood.glb#Scene0 2@East (0:0)"));
+    }
+}
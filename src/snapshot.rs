@@ -0,0 +1,364 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::components::*;
+use crate::wcf::{declare, forbid, learn_source};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Saving and loading of the whole editing session
+pub struct SnapshotPlugin;
+
+impl Plugin for SnapshotPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(save_session.exclusive_system())
+            .add_system(load_session.exclusive_system());
+    }
+}
+
+const SESSION_PATH: &str = "session.ron";
+
+/// Wave of a generated map cell, with its backtracking history
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct CellSnapshot {
+    pub coordinates: Coordinates,
+    pub tiles: HashSet<Tile>,
+    pub history: Vec<HashSet<Tile>>,
+}
+
+/// Everything needed to resume a session: the generation in progress, its guesses, the
+/// rule map, the selected tile and the settings
+///
+/// The entities are referred to by their coordinates, as they are spawned again on
+/// every launch.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub tuning: Tuning,
+    pub rule_map: MapRule,
+    /// Prototype index and rotation of the selected tile
+    pub selection: (Option<usize>, i32),
+    pub cells: Vec<CellSnapshot>,
+    /// Guessed tile of each observed cell, the latest first
+    pub guesses: Vec<(Coordinates, Tile)>,
+}
+
+/// Capture the current session
+pub fn snapshot(world: &mut World) -> WorldSnapshot {
+    let tuning = world.resource::<Tuning>();
+    let (rule_width, rule_height) = (tuning.rule_map_width, tuning.rule_map_height);
+    let rule_map = MapRule::read(
+        world
+            .query_filtered::<(&OptionalTile, &Coordinates), With<RuleTileTag>>()
+            .iter(world),
+        rule_width,
+        rule_height,
+    );
+
+    let mut coordinates_of = HashMap::new();
+    let mut cells = Vec::new();
+    for (entity, wave, wave_history, coordinates) in world
+        .query::<(
+            Entity,
+            &TileSuperposition,
+            &TileSuperpositionHistory,
+            &Coordinates,
+        )>()
+        .iter(world)
+    {
+        coordinates_of.insert(entity, *coordinates);
+        cells.push(CellSnapshot {
+            coordinates: *coordinates,
+            tiles: wave.tiles.clone(),
+            history: wave_history.history.iter().cloned().collect(),
+        });
+    }
+    cells.sort_by_key(|cell| (cell.coordinates.x, cell.coordinates.y));
+
+    let guesses = world
+        .resource::<GuessHistory>()
+        .history
+        .iter()
+        .filter_map(|(entity, tile)| Some((*coordinates_of.get(entity)?, *tile)))
+        .collect();
+    let selection = world.resource::<TileSelection>();
+
+    WorldSnapshot {
+        tuning: world.resource::<Tuning>().clone(),
+        rule_map,
+        selection: (
+            selection
+                .prototype
+                .as_ref()
+                .map(|prototype| prototype.index),
+            selection.rotation,
+        ),
+        cells,
+        guesses,
+    }
+}
+
+/// Bring a session back, the generation continuing from the restored waves
+///
+/// The generated map must have the size, wrapping and mirroring of the snapshot, as
+/// changing them would start the generation over, and the rule map its size. Nothing is
+/// changed on error.
+pub fn restore(world: &mut World, snapshot: &WorldSnapshot) -> Result<(), String> {
+    let tuning = world.resource::<Tuning>();
+    let layout = |tuning: &Tuning| {
+        (
+            tuning.map_width,
+            tuning.map_height,
+            tuning.wrap,
+            tuning.mirror_rules,
+            tuning.rule_map_width,
+            tuning.rule_map_height,
+        )
+    };
+    if layout(tuning) != layout(&snapshot.tuning) {
+        return Err(format!(
+            "the session has a {}x{} map (wrap: {}, mirrored rules: {}) and a {}x{} rule map, set them first",
+            snapshot.tuning.map_width,
+            snapshot.tuning.map_height,
+            snapshot.tuning.wrap,
+            snapshot.tuning.mirror_rules,
+            snapshot.tuning.rule_map_width,
+            snapshot.tuning.rule_map_height
+        ));
+    }
+
+    let mut cells_query = world.query::<(
+        Entity,
+        &Coordinates,
+        &mut TileSuperposition,
+        &mut TileSuperpositionHistory,
+    )>();
+    let entities: HashMap<Coordinates, Entity> = cells_query
+        .iter(world)
+        .map(|(entity, coordinates, _, _)| (*coordinates, entity))
+        .collect();
+    if entities.len() != snapshot.cells.len()
+        || snapshot
+            .cells
+            .iter()
+            .any(|cell| !entities.contains_key(&cell.coordinates))
+    {
+        return Err("the session cells don't match the generated map".to_string());
+    }
+
+    // Settings and rules
+    *world.resource_mut::<Tuning>() = snapshot.tuning.clone();
+    for (mut tile, coordinates) in world
+        .query_filtered::<(&mut OptionalTile, &Coordinates), With<RuleTileTag>>()
+        .iter_mut(world)
+    {
+        let restored = snapshot
+            .rule_map
+            .map
+            .get(coordinates.x as usize)
+            .and_then(|line| line.get(coordinates.y as usize))
+            .cloned()
+            .unwrap_or_default();
+        if *tile != restored {
+            *tile = restored;
+        }
+    }
+    // Learnt here rather than by an update event, which would reset the waves
+    let mut rules = world.resource_mut::<Rules>();
+    rules.alloweds = learn_source(&rules, &snapshot.rule_map.map, snapshot.tuning.mirror_rules);
+    let declared = rules.declared.clone();
+    let prototypes = rules.prototypes.clone();
+    declare(
+        &mut rules.alloweds,
+        &declared,
+        &prototypes,
+        snapshot.tuning.mirror_rules,
+    );
+    let forbidden = rules.forbidden.clone();
+    forbid(&mut rules.alloweds, &forbidden);
+    let prototype = snapshot
+        .selection
+        .0
+        .and_then(|index| rules.prototypes.get(index))
+        .cloned();
+    *world.resource_mut::<TileSelection>() = TileSelection {
+        rotation: snapshot.selection.1,
+        prototype,
+    };
+
+    // Generation in progress
+    let restored: HashMap<Coordinates, &CellSnapshot> = snapshot
+        .cells
+        .iter()
+        .map(|cell| (cell.coordinates, cell))
+        .collect();
+    for (_, coordinates, mut wave, mut wave_history) in cells_query.iter_mut(world) {
+        let cell = restored[coordinates];
+        wave.tiles = cell.tiles.clone();
+        wave.dirty = false;
+        wave_history.history = cell.history.iter().cloned().collect();
+    }
+    world.resource_mut::<GuessHistory>().history = snapshot
+        .guesses
+        .iter()
+        .map(|(coordinates, tile)| (entities[coordinates], *tile))
+        .collect();
+
+    // The edits of the previous session can't be undone anymore
+    let mut rule_edits = world.resource_mut::<RuleEditHistory>();
+    rule_edits.undo.clear();
+    rule_edits.redo.clear();
+    world.resource_mut::<MapEditHistory>().undo.clear();
+    world.resource_mut::<FocusCell>().0 = None;
+    world.resource_mut::<GenerationState>().idle = false;
+    Ok(())
+}
+
+/// Save the session with F5
+fn save_session(world: &mut World) {
+    if !world.resource::<Input<KeyCode>>().just_pressed(KeyCode::F5) {
+        return;
+    }
+
+    let serialized = ron::ser::to_string_pretty(&snapshot(world), Default::default())
+        .map_err(|e| e.to_string())
+        .and_then(|serialized| std::fs::write(SESSION_PATH, serialized).map_err(|e| e.to_string()));
+    match serialized {
+        Ok(_) => info!("Saved the session to {}", SESSION_PATH),
+        Err(e) => warn!("Failed to save {}: {}", SESSION_PATH, e),
+    }
+}
+
+/// Load the session saved with F5 with F9
+fn load_session(world: &mut World) {
+    if !world.resource::<Input<KeyCode>>().just_pressed(KeyCode::F9) {
+        return;
+    }
+
+    let result = std::fs::read_to_string(SESSION_PATH)
+        .map_err(|e| e.to_string())
+        .and_then(|serialized| {
+            ron::from_str::<WorldSnapshot>(&serialized).map_err(|e| e.to_string())
+        })
+        .and_then(|snapshot| restore(world, &snapshot));
+    match result {
+        Ok(_) => info!("Loaded the session from {}", SESSION_PATH),
+        Err(e) => warn!("Failed to load {}: {}", SESSION_PATH, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// World with a 16x16 rule map and a 4x4 generated map, without any display
+    fn session_world() -> World {
+        let mut world = World::new();
+        let tuning = Tuning {
+            map_width: 4,
+            map_height: 4,
+            ..Default::default()
+        };
+        let rules = Rules::headless();
+        world.insert_resource(TileSelection::default());
+        world.insert_resource(GuessHistory::default());
+        world.insert_resource(RuleEditHistory::default());
+        world.insert_resource(MapEditHistory::default());
+        world.insert_resource(FocusCell::default());
+        world.insert_resource(GenerationState::default());
+        for x in 0..16 {
+            for y in 0..16 {
+                world
+                    .spawn()
+                    .insert(RuleTileTag)
+                    .insert(OptionalTile::default())
+                    .insert(Coordinates::new(x, y));
+            }
+        }
+        for x in 0..4 {
+            for y in 0..4 {
+                world
+                    .spawn()
+                    .insert(Coordinates::new(x, y))
+                    .insert(TileSuperposition::default())
+                    .insert(TileSuperpositionHistory::default());
+            }
+        }
+        world.insert_resource(tuning);
+        world.insert_resource(rules);
+        world
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let straight = Tile::new(1, Orientation::North);
+        let turn = Tile::new(2, Orientation::East);
+
+        // A session in progress
+        let mut world = session_world();
+        let mut rule_map = vec![vec![OptionalTile::default(); 16]; 16];
+        rule_map[0][0] = OptionalTile::new(Some(straight));
+        rule_map[1][0] = OptionalTile::new(Some(turn));
+        let mut cells = Vec::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                let guessed = x == 1 && y == 2;
+                cells.push(CellSnapshot {
+                    coordinates: Coordinates::new(x, y),
+                    tiles: if guessed {
+                        HashSet::from([turn])
+                    } else {
+                        HashSet::from([straight, turn])
+                    },
+                    history: if guessed {
+                        vec![HashSet::from([straight, turn])]
+                    } else {
+                        vec![]
+                    },
+                });
+            }
+        }
+        let session = WorldSnapshot {
+            tuning: Tuning {
+                map_width: 4,
+                map_height: 4,
+                tile_scale: 0.5,
+                ..Default::default()
+            },
+            rule_map: MapRule { map: rule_map },
+            selection: (Some(2), 3),
+            cells,
+            guesses: vec![(Coordinates::new(1, 2), turn)],
+        };
+
+        restore(&mut world, &session).unwrap();
+        assert!(snapshot(&mut world) == session);
+        assert!(world.resource::<Rules>().alloweds.contains_key(&turn));
+        assert!(!world.resource::<GenerationState>().idle);
+
+        // Through RON, and restored in a fresh world
+        let serialized = ron::to_string(&session).unwrap();
+        let deserialized: WorldSnapshot = ron::from_str(&serialized).unwrap();
+        assert!(deserialized == session);
+        let mut other_world = session_world();
+        restore(&mut other_world, &deserialized).unwrap();
+        assert!(snapshot(&mut other_world) == session);
+
+        // Not over a rule map of another size, which would lose some of its tiles
+        let mut wider = session.clone();
+        wider.tuning.rule_map_width = 20;
+        wider
+            .rule_map
+            .map
+            .resize(20, vec![OptionalTile::default(); 16]);
+        assert!(restore(&mut other_world, &wider).is_err());
+        assert!(snapshot(&mut other_world) == session);
+    }
+
+    #[test]
+    fn restore_needs_the_same_map() {
+        let mut world = session_world();
+        let mut session = snapshot(&mut world);
+        session.tuning.map_width = 8;
+        assert!(restore(&mut world, &session).is_err());
+        assert!(world.resource::<Tuning>().map_width == 4);
+    }
+}
@@ -1,8 +1,12 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
 
 use crate::components::*;
 use bevy::prelude::*;
-use rand::prelude::SliceRandom;
+use rand::{prelude::SliceRandom, rngs::StdRng, Rng, SeedableRng};
 use std::hash::Hash;
 
 pub struct WCFPlugin;
@@ -12,13 +16,98 @@ impl Plugin for WCFPlugin {
         app.add_system(observe_system)
             .add_system(collapse)
             .add_system(update_rules)
+            .add_system(update_rule_provenance)
+            .add_system(update_connectivity)
+            .add_system(regenerate)
+            .add_system(finalize)
+            .add_system(stamp_rule_map)
+            .add_system(set_tile)
+            .add_system(wake_on_orientation_locks)
             .add_system(prune_guess_history)
-            .add_system(backtrack);
+            .add_system(backtrack)
+            .add_system(reroll_last)
+            .add_system(update_stats)
+            .add_system(update_tile_histogram)
+            .add_system(auto_retry)
+            .add_system(report_propagation_profile)
+            .add_system_to_stage(CoreStage::PostUpdate, record_wave_traces);
     }
 }
 
+/// Rule map with the multi-cell tiles covering their footprint, part by part
+///
+/// Whatever was drawn under the footprint is ignored. The footprints running off the
+/// rule map are cut, their parts on the map still being learned.
+fn expand_footprints(
+    rule_tiles: &[Vec<OptionalTile>],
+    prototypes: &[Prototype],
+) -> Vec<Vec<OptionalTile>> {
+    let mut expanded = rule_tiles.to_vec();
+    for (x, line) in rule_tiles.iter().enumerate() {
+        for (y, tile) in line.iter().enumerate() {
+            let (tile, prototype) = match tile.tile.and_then(|tile| {
+                let prototype = prototypes.get(tile.prototype_index)?;
+                Some((tile, prototype))
+            }) {
+                Some((tile, prototype)) if tile.part == 0 => (tile, prototype),
+                _ => continue,
+            };
+            for part in 1..prototype.parts() {
+                let offset = prototype.part_offset(part, tile.orientation);
+                let (x, y) = (x as i32 + offset.x, y as i32 + offset.y);
+                if x < 0 || y < 0 {
+                    continue;
+                }
+                if let Some(covered) = expanded
+                    .get_mut(x as usize)
+                    .and_then(|line| line.get_mut(y as usize))
+                {
+                    *covered = OptionalTile::new(Some(Tile { part, ..tile }));
+                }
+            }
+        }
+    }
+    expanded
+}
+
+/// Whether the other parts of a multi-cell tile all have a cell, from the cell of `tile`
+///
+/// `step` gives the neighbour of a cell in an orientation, if any. The footprint is
+/// walked along the x axis first, then along the y axis.
+pub fn footprint_fits<C: Copy>(
+    prototypes: &[Prototype],
+    tile: &Tile,
+    cell: C,
+    step: impl Fn(C, Orientation) -> Option<C>,
+) -> bool {
+    let prototype = match prototypes.get(tile.prototype_index) {
+        Some(prototype) => prototype,
+        None => return true,
+    };
+    let origin = prototype.part_offset(tile.part, tile.orientation);
+    (0..prototype.parts()).all(|part| {
+        let offset = prototype.part_offset(part, tile.orientation);
+        let (dx, dy) = (offset.x - origin.x, offset.y - origin.y);
+        // Orientation::offset goes toward x+ to the West and y+ to the North
+        let horizontal = if dx > 0 {
+            Orientation::West
+        } else {
+            Orientation::East
+        };
+        let vertical = if dy > 0 {
+            Orientation::North
+        } else {
+            Orientation::South
+        };
+        std::iter::repeat_n(horizontal, dx.unsigned_abs() as usize)
+            .chain(std::iter::repeat_n(vertical, dy.unsigned_abs() as usize))
+            .try_fold(cell, &step)
+            .is_some()
+    })
+}
+
 /// Safe tile get from indexes
-fn get_tile_prototype(map: &Vec<Vec<OptionalTile>>, coordinates: &Coordinates) -> Option<Tile> {
+fn get_tile_prototype(map: &[Vec<OptionalTile>], coordinates: &Coordinates) -> Option<Tile> {
     if coordinates.x < 0 || coordinates.y < 0 {
         return None;
     }
@@ -27,16 +116,41 @@ fn get_tile_prototype(map: &Vec<Vec<OptionalTile>>, coordinates: &Coordinates) -
     tile.tile.clone()
 }
 
+/// Rotated tiles, without the fixed ones that can't be rotated
+fn rotate_tiles<'a>(
+    tiles: &'a HashSet<Tile>,
+    prototypes: &'a [Prototype],
+    rotation: i32,
+) -> impl Iterator<Item = Tile> + 'a {
+    tiles.iter().filter_map(move |tile| {
+        let prototype = &prototypes[tile.prototype_index];
+        if prototype.equivalences == Equivalences::Fixed && rotation != 0 {
+            return None;
+        }
+        Some(Tile {
+            part: tile.part,
+            ..prototype.make_rotated_tile(tile.orientation, rotation)
+        })
+    })
+}
+
 fn expand_with_rotations(
     constraints: &HashMap<Tile, Allowed>,
-    prototypes: &Vec<Prototype>,
+    prototypes: &[Prototype],
 ) -> HashMap<Tile, Allowed> {
     let mut expanded = HashMap::<Tile, Allowed>::new();
 
     for (tile, tile_constraints) in constraints.iter() {
         let prototype = &prototypes[tile.prototype_index];
         for tile_rotations in 0..Orientation::values().len() as i32 {
-            let rotated_tile = prototype.make_rotated_tile(tile.orientation, tile_rotations);
+            // Fixed tiles keep the orientation they were authored in
+            if prototype.equivalences == Equivalences::Fixed && tile_rotations != 0 {
+                continue;
+            }
+            let rotated_tile = Tile {
+                part: tile.part,
+                ..prototype.make_rotated_tile(tile.orientation, tile_rotations)
+            };
             let new_variant_constraints_entry = expanded.entry(rotated_tile).or_default();
 
             for (orientation, allowed_values) in tile_constraints.allowed.iter() {
@@ -44,12 +158,23 @@ fn expand_with_rotations(
                     .allowed
                     .entry(orientation.rotated(tile_rotations))
                     .or_default();
-                for allowed_tile in allowed_values.iter() {
-                    let prototype = &prototypes[allowed_tile.prototype_index];
-                    let rotated_allowed_tile =
-                        prototype.make_rotated_tile(allowed_tile.orientation, tile_rotations);
-                    new_constraints_entry.insert(rotated_allowed_tile);
-                }
+                new_constraints_entry.extend(rotate_tiles(
+                    allowed_values,
+                    prototypes,
+                    tile_rotations,
+                ));
+            }
+
+            for ((first, second), allowed_values) in tile_constraints.distant.iter() {
+                let rotated_path = (
+                    first.rotated(tile_rotations),
+                    second.rotated(tile_rotations),
+                );
+                new_variant_constraints_entry
+                    .distant
+                    .entry(rotated_path)
+                    .or_default()
+                    .extend(rotate_tiles(allowed_values, prototypes, tile_rotations));
             }
         }
     }
@@ -57,248 +182,3970 @@ fn expand_with_rotations(
     expanded
 }
 
-fn intersection<T: Eq + Hash>(a: HashSet<T>, b: &HashSet<T>) -> HashSet<T> {
-    a.into_iter().filter(|e| b.contains(e)).collect()
+fn mirror_tiles<'a>(
+    tiles: &'a HashSet<Tile>,
+    prototypes: &'a [Prototype],
+) -> impl Iterator<Item = Tile> + 'a {
+    tiles
+        .iter()
+        .filter_map(|tile| prototypes[tile.prototype_index].make_mirrored_tile(tile.orientation))
 }
 
-fn update_rules(
-    mut rules: ResMut<Rules>,
-    rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
-    mut event_reader: EventReader<RulesNeedUpdateEvent>,
-    mut tiles_query: Query<(Entity, &mut TileSuperposition, &Connectivity)>,
-) {
-    if !event_reader.is_empty() || rules.is_added() {
-        for _ in event_reader.iter() {}
-        // Rule change
+/// Add the mirror images of the constraints
+///
+/// Reflecting across the North-South axis swaps East and West. The constraints of a tile
+/// in a given orientation become the constraints of its mirror image in the mirrored
+/// orientation, and each allowed neighbour is replaced by its own mirror image.
+/// Chiral tiles have no mirror image, the constraints involving them are not mirrored.
+fn expand_with_mirror(
+    constraints: &HashMap<Tile, Allowed>,
+    prototypes: &[Prototype],
+) -> HashMap<Tile, Allowed> {
+    let mut expanded = constraints.clone();
 
-        // Read the rule map
-        let rule_width = 16;
-        let rule_height = 16;
-        let mut rule_tiles = vec![vec![OptionalTile::default(); rule_width]; rule_height];
-        for (tile, coordinates) in rules_query.iter() {
-            rule_tiles[coordinates.x as usize][coordinates.y as usize] = tile.clone();
-        }
-
-        // Store the rule connectivities as constraints
-        rules.alloweds = HashMap::<Tile, Allowed>::new();
-        for x in 0..rule_width {
-            for y in 0..rule_height {
-                let tile = &rule_tiles[x][y];
-                let coords = Coordinates::new(x as i32, y as i32);
-                if let Some(tile) = &tile.tile {
-                    let allowed = &mut rules.alloweds.entry(tile.clone()).or_default().allowed;
-
-                    for orientation in Orientation::values() {
-                        let neighbour_coords = orientation.offset(&coords);
-                        let neighbour_tile = get_tile_prototype(&rule_tiles, &neighbour_coords);
-                        if let Some(neighbour_tile) = neighbour_tile {
-                            allowed
-                                .entry(orientation)
-                                .or_default()
-                                .insert(neighbour_tile);
-                        }
-                    }
-                }
-            }
+    for (tile, tile_constraints) in constraints.iter() {
+        let mirrored_tile =
+            match prototypes[tile.prototype_index].make_mirrored_tile(tile.orientation) {
+                Some(mirrored_tile) => mirrored_tile,
+                None => continue,
+            };
+        let mirrored_constraints = expanded.entry(mirrored_tile).or_default();
+
+        for (orientation, allowed_values) in tile_constraints.allowed.iter() {
+            mirrored_constraints
+                .allowed
+                .entry(orientation.mirrored())
+                .or_default()
+                .extend(mirror_tiles(allowed_values, prototypes));
         }
-        rules.alloweds = expand_with_rotations(&rules.alloweds, &rules.prototypes);
 
-        // Reset to every possibilities on rule change
-        let mut possible_tiles = HashSet::new();
-        for tile in rules.alloweds.keys() {
-            possible_tiles.insert(tile.clone());
+        for ((first, second), allowed_values) in tile_constraints.distant.iter() {
+            mirrored_constraints
+                .distant
+                .entry((first.mirrored(), second.mirrored()))
+                .or_default()
+                .extend(mirror_tiles(allowed_values, prototypes));
         }
-        for (_, mut multi_tile_prototype, _) in tiles_query.iter_mut() {
-            multi_tile_prototype.tiles = possible_tiles.clone();
+    }
+
+    expanded
+}
+
+/// Find the tiles that can't have any neighbour in some orientations
+fn validate_rules(alloweds: &HashMap<Tile, Allowed>) -> RuleDiagnostics {
+    let mut dead_ends = Vec::new();
+    for (tile, allowed) in alloweds.iter() {
+        let orientations: Vec<Orientation> = Orientation::values()
+            .into_iter()
+            .filter(|orientation| match allowed.allowed.get(orientation) {
+                Some(tiles) => tiles.is_empty(),
+                None => true,
+            })
+            .collect();
+        if !orientations.is_empty() {
+            dead_ends.push((*tile, orientations));
+        }
+    }
+    dead_ends.sort_by_key(|(tile, _)| *tile);
+
+    RuleDiagnostics {
+        dead_ends,
+        asymmetries: verify_symmetry(alloweds),
+    }
+}
+
+/// Find the neighbours allowed in one direction but not in the opposite one
+///
+/// A allowing B to its North implies B allowing A to its South. Rules learned from
+/// the rule map are always symmetric, edited or imported ones may not be.
+fn verify_symmetry(alloweds: &HashMap<Tile, Allowed>) -> Vec<(Tile, Orientation, Tile)> {
+    let mut asymmetries = Vec::new();
+    for (tile, allowed) in alloweds.iter() {
+        for (orientation, neighbours) in allowed.allowed.iter() {
+            let opposite = orientation.rotated(2);
+            for neighbour in neighbours {
+                let allowed_back = alloweds
+                    .get(neighbour)
+                    .and_then(|neighbour_allowed| neighbour_allowed.get(&[opposite]));
+                if !matches!(allowed_back, Some(tiles) if tiles.contains(tile)) {
+                    asymmetries.push((*tile, *orientation, *neighbour));
+                }
+            }
         }
     }
+    asymmetries.sort();
+    asymmetries
 }
 
-fn observe_system(
-    mut query: Query<(
-        Entity,
-        &mut TileSuperposition,
-        &mut TileSuperpositionHistory,
-    )>,
-    mut history: ResMut<GuessHistory>,
+/// Add the adjacencies declared between palette tiles to the learned constraints
+///
+/// They are allowed both ways, and rotated and mirrored like the learned ones. Only the
+/// direct neighbours are affected, not the second ring.
+pub fn declare(
+    alloweds: &mut HashMap<Tile, Allowed>,
+    declared: &HashSet<(Tile, Orientation, Tile)>,
+    prototypes: &[Prototype],
+    mirror: bool,
 ) {
-    let mut rng = rand::thread_rng();
-    // Find the smallest > 1 entropy
-    let mut min_entropy_entities = Vec::new();
-    let mut min_entropy = usize::MAX;
+    let mut adjacencies = HashMap::<Tile, Allowed>::new();
+    for (tile, orientation, neighbour) in declared.iter() {
+        let adjacencies_both_ways = [
+            (tile, *orientation, neighbour),
+            (neighbour, orientation.rotated(2), tile),
+        ];
+        for (tile, orientation, neighbour) in adjacencies_both_ways {
+            adjacencies
+                .entry(*tile)
+                .or_default()
+                .allowed
+                .entry(orientation)
+                .or_default()
+                .insert(*neighbour);
+        }
+    }
 
-    for (entity, wave, _) in query.iter() {
-        if wave.dirty {
-            // ongoing propagation
-            return;
+    for (tile, allowed) in expand_variants(&adjacencies, prototypes, mirror) {
+        let learned = alloweds.entry(tile).or_default();
+        for (orientation, tiles) in allowed.allowed {
+            learned
+                .allowed
+                .entry(orientation)
+                .or_default()
+                .extend(tiles);
         }
-        let entropy = wave.tiles.len();
-        if entropy < min_entropy && entropy > 1 {
-            min_entropy = entropy;
-            min_entropy_entities.clear();
+    }
+}
+
+/// Remove the forbidden adjacencies from the learned constraints
+///
+/// The opposite adjacency is removed as well to keep the constraints symmetric.
+pub fn forbid(
+    alloweds: &mut HashMap<Tile, Allowed>,
+    forbidden: &HashSet<(Tile, Orientation, Tile)>,
+) {
+    for (tile, orientation, neighbour) in forbidden.iter() {
+        let adjacencies = [
+            (tile, *orientation, neighbour),
+            (neighbour, orientation.rotated(2), tile),
+        ];
+        for (tile, orientation, neighbour) in adjacencies {
+            if let Some(tiles) = alloweds
+                .get_mut(tile)
+                .and_then(|allowed| allowed.allowed.get_mut(&orientation))
+            {
+                tiles.remove(neighbour);
+            }
         }
+    }
+}
 
-        if entropy == min_entropy {
-            min_entropy_entities.push(entity);
+fn intersection<T: Eq + Hash>(a: HashSet<T>, b: &HashSet<T>) -> HashSet<T> {
+    a.into_iter().filter(|e| b.contains(e)).collect()
+}
+
+/// Possible tiles of a neighbour reached through `path`, if restricted by the propagating tiles
+fn constrain(
+    alloweds: &HashMap<Tile, Allowed>,
+    propagating: &HashSet<Tile>,
+    path: &[Orientation],
+    neighbour: &HashSet<Tile>,
+) -> Option<HashSet<Tile>> {
+    // Sum all the possible values for this neighbour given its own allowed values
+    let mut all_allowed_neighbour = HashSet::<Tile>::new();
+    for value in propagating {
+        // Skip cases where there is a contradiction
+        if let Some(allowed_list) = alloweds.get(value) {
+            if let Some(allowed_neighbour) = allowed_list.get(path) {
+                all_allowed_neighbour.extend(allowed_neighbour);
+            }
         }
     }
 
-    let min_entropy_entity = match min_entropy_entities.choose(&mut rng) {
-        Some(e) => *e,
-        // Solved or impossible
-        None => return,
+    // Intersect the previous list of allowed values with the new constraints
+    let new_allowed_values = intersection(all_allowed_neighbour, neighbour);
+    if &new_allowed_values != neighbour {
+        Some(new_allowed_values)
+    } else {
+        None
+    }
+}
+
+/// Constrain each neighbour wave reached through its path, keeping the neighbours order
+///
+/// The neighbours are constrained in parallel when enabled and built with the `parallel`
+/// feature. The results are collected in order, which keeps the generation deterministic.
+fn constrain_all(
+    alloweds: &HashMap<Tile, Allowed>,
+    propagating: &HashSet<Tile>,
+    neighbours: &[(&[Orientation], &HashSet<Tile>)],
+    parallel: bool,
+) -> Vec<Option<HashSet<Tile>>> {
+    let constrain_one = |(path, neighbour): &(&[Orientation], &HashSet<Tile>)| {
+        constrain(alloweds, propagating, path, neighbour)
     };
+    #[cfg(feature = "parallel")]
+    if parallel {
+        use rayon::prelude::*;
+        return neighbours.par_iter().map(constrain_one).collect();
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = parallel;
+    neighbours.iter().map(constrain_one).collect()
+}
 
-    let min_entropy_wave = query
-        .get_component_mut::<TileSuperposition>(min_entropy_entity)
-        .unwrap()
-        .clone();
+/// New waves of the neighbours restricted by the propagating tiles, without applying them
+///
+/// `wave` gives the current wave of a neighbour. Small wrapping maps can reach the same
+/// neighbour through several paths, its constraints are merged in its first occurrence.
+fn constrain_neighbours<'a, C: Copy + Eq>(
+    alloweds: &HashMap<Tile, Allowed>,
+    propagating: &HashSet<Tile>,
+    neighbours: &[(C, Vec<Orientation>)],
+    wave: impl Fn(C) -> &'a HashSet<Tile>,
+    parallel: bool,
+) -> Vec<(C, HashSet<Tile>)> {
+    let neighbour_waves: Vec<(&[Orientation], &HashSet<Tile>)> = neighbours
+        .iter()
+        .map(|(neighbour, path)| (path.as_slice(), wave(*neighbour)))
+        .collect();
+    let constrained_waves = constrain_all(alloweds, propagating, &neighbour_waves, parallel);
 
-    // Observe the tile with the smallest entropy
-    let min_entropy_tiles: Vec<&Tile> = min_entropy_wave.tiles.iter().collect();
-    let observed = *min_entropy_tiles.choose(&mut rng).unwrap().clone();
+    let mut restricted: Vec<(C, HashSet<Tile>)> = Vec::new();
+    for ((neighbour, _), new_allowed_values) in neighbours.iter().zip(constrained_waves) {
+        let new_allowed_values = match new_allowed_values {
+            Some(new_allowed_values) => new_allowed_values,
+            None => continue,
+        };
+        match restricted.iter_mut().find(|(cell, _)| cell == neighbour) {
+            Some((_, tiles)) => *tiles = intersection(new_allowed_values, tiles),
+            None => restricted.push((*neighbour, new_allowed_values)),
+        }
+    }
+    restricted
+}
 
-    // Save the current state and this guess in the tiles history
-    history.history.push_front((min_entropy_entity, observed));
-    for (_, wave, mut wave_history) in query.iter_mut() {
-        wave_history.history.push_front(wave.tiles.clone());
+/// Drop each constraint with a `relaxation` chance, leaving its neighbour unchanged
+///
+/// Nothing is drawn from `rng` when strict, to keep the strict generations unchanged.
+fn relax_constraints<C>(
+    constrained: &mut Vec<(C, HashSet<Tile>)>,
+    relaxation: f32,
+    rng: &mut impl Rng,
+) {
+    if relaxation <= 0.0 {
+        return;
     }
+    let relaxation = relaxation.min(1.0) as f64;
+    constrained.retain(|_| !rng.gen_bool(relaxation));
+}
 
-    // Observe the tile
-    let mut min_entropy_wave = query
-        .get_component_mut::<TileSuperposition>(min_entropy_entity)
-        .unwrap();
-    min_entropy_wave.tiles.clear();
-    min_entropy_wave.tiles.insert(observed.clone());
+/// Paths leading from a cell to the neighbours it constrains
+pub fn neighbour_paths(tuning: &Tuning) -> Vec<Vec<Orientation>> {
+    let mut paths: Vec<Vec<Orientation>> = Orientation::values()
+        .iter()
+        .map(|orientation| vec![*orientation])
+        .collect();
+    // The second ring includes the diagonals
+    let distant_paths = if tuning.second_ring {
+        Orientation::second_ring().to_vec()
+    } else if tuning.diagonals {
+        Orientation::diagonals().to_vec()
+    } else {
+        Vec::new()
+    };
+    paths.extend(
+        distant_paths
+            .into_iter()
+            .map(|(first, second)| vec![first, second]),
+    );
+    paths
+}
 
-    // Signal for propagation
-    min_entropy_wave.dirty = true;
+/// Neighbours at the end of each path, with the path leading to them
+///
+/// `step` gives the direct neighbour of a cell in an orientation, if any.
+pub fn find_neighbours<C: Copy>(
+    cell: C,
+    paths: &[Vec<Orientation>],
+    step: impl Fn(C, Orientation) -> Option<C>,
+) -> Vec<(C, Vec<Orientation>)> {
+    paths
+        .iter()
+        .filter_map(|path| {
+            path.iter()
+                .try_fold(cell, |current, orientation| step(current, *orientation))
+                .map(|neighbour| (neighbour, path.clone()))
+        })
+        .collect()
 }
 
-fn collapse(
-    rules: Res<Rules>,
-    tuning: Res<Tuning>,
-    mut query: Query<(Entity, &mut TileSuperposition, &Connectivity)>,
-) {
-    for _ in 0..tuning.collapse_per_frame {
-        // Find a dirty wave
-        let mut propagating_entity = Option::<Entity>::default();
-        for (entity, wave, _) in query.iter() {
-            if wave.dirty {
-                propagating_entity = Some(entity);
-                break;
+/// Neighbours of each cell as indexes in `cells`, with the path leading to them
+///
+/// `step` gives the direct neighbour of a cell in an orientation, if any.
+fn indexed_neighbours(
+    cells: &[Entity],
+    paths: &[Vec<Orientation>],
+    step: impl Fn(Entity, Orientation) -> Option<Entity>,
+) -> Vec<Vec<(usize, Vec<Orientation>)>> {
+    let indexes: HashMap<Entity, usize> = cells
+        .iter()
+        .enumerate()
+        .map(|(index, entity)| (*entity, index))
+        .collect();
+    cells
+        .iter()
+        .map(|entity| {
+            find_neighbours(*entity, paths, &step)
+                .into_iter()
+                .map(|(neighbour, path)| (indexes[&neighbour], path))
+                .collect()
+        })
+        .collect()
+}
+
+/// Whether placing `tile` at `coordinates` is consistent with the waves of its neighbours
+///
+/// Only the neighbours are checked, not the wave of the cell itself. Coordinates missing
+/// from `waves`, like the ones past the edges of the map, don't constrain the tile.
+pub fn is_legal(
+    rules: &Rules,
+    tuning: &Tuning,
+    waves: &HashMap<Coordinates, &HashSet<Tile>>,
+    coordinates: Coordinates,
+    tile: Tile,
+) -> bool {
+    let allowed = match rules.alloweds.get(&tile) {
+        Some(allowed) => allowed,
+        None => return false,
+    };
+    find_neighbours(
+        coordinates,
+        &neighbour_paths(tuning),
+        map_step(tuning, waves),
+    )
+    .iter()
+    .all(|(neighbour, path)| match allowed.get(path) {
+        Some(allowed_neighbour) => waves[neighbour]
+            .iter()
+            .any(|candidate| allowed_neighbour.contains(candidate)),
+        None => false,
+    })
+}
+
+/// Direct neighbour of a cell in the map described by `waves`
+fn map_step<'a>(
+    tuning: &'a Tuning,
+    waves: &'a HashMap<Coordinates, &HashSet<Tile>>,
+) -> impl Fn(Coordinates, Orientation) -> Option<Coordinates> + 'a {
+    move |current, orientation| {
+        let mut neighbour = orientation.offset(&current);
+        if tuning.wrap {
+            neighbour = neighbour.wrapped(tuning.map_width, tuning.map_height);
+        }
+        waves.contains_key(&neighbour).then_some(neighbour)
+    }
+}
+
+/// Cells whose wave would be restricted by placing `tile` at `start`
+///
+/// This is a dry run of the propagation, following the constraints transitively until
+/// no further restriction is possible. The waves are left untouched, and `start` itself
+/// is not included.
+pub fn constraint_closure(
+    rules: &Rules,
+    tuning: &Tuning,
+    waves: &HashMap<Coordinates, &HashSet<Tile>>,
+    start: Coordinates,
+    tile: Tile,
+) -> HashSet<Coordinates> {
+    let paths = neighbour_paths(tuning);
+    let step = map_step(tuning, waves);
+    let mut restricted = dry_run(
+        &rules.alloweds,
+        start,
+        tile,
+        |cell| find_neighbours(cell, &paths, &step),
+        |cell| waves[&cell],
+        None,
+        tuning.parallel_propagation,
+    );
+    restricted.remove(&start);
+    restricted.into_keys().collect()
+}
+
+/// Waves restricted by placing `tile` at `start`, without applying them
+///
+/// `neighbours` gives the neighbours of a cell with the path leading to them, and `wave`
+/// its current wave. The impossible cells are restricted to an empty wave, and are not
+/// propagated. The propagation stops after `max_steps` cells when given.
+fn dry_run<'a, C: Copy + Eq + Hash>(
+    alloweds: &HashMap<Tile, Allowed>,
+    start: C,
+    tile: Tile,
+    neighbours: impl Fn(C) -> Vec<(C, Vec<Orientation>)>,
+    wave: impl Fn(C) -> &'a HashSet<Tile>,
+    max_steps: Option<usize>,
+    parallel: bool,
+) -> HashMap<C, HashSet<Tile>> {
+    let mut restricted = HashMap::<C, HashSet<Tile>>::new();
+    restricted.insert(start, HashSet::from([tile]));
+    let mut dirty = vec![start];
+    let mut steps = 0;
+
+    while let Some(propagating) = dirty.pop() {
+        if Some(steps) == max_steps {
+            break;
+        }
+        steps += 1;
+
+        let propagating_wave = restricted[&propagating].clone();
+        let constrained = constrain_neighbours(
+            alloweds,
+            &propagating_wave,
+            &neighbours(propagating),
+            |cell| match restricted.get(&cell) {
+                Some(tiles) => tiles,
+                None => wave(cell),
+            },
+            parallel,
+        );
+        for (neighbour, tiles) in constrained {
+            if !tiles.is_empty() {
+                dirty.push(neighbour);
             }
+            restricted.insert(neighbour, tiles);
         }
+    }
+    restricted
+}
 
-        let propagating_entity = match propagating_entity {
-            Some(e) => e,
-            None => return,
-        };
+/// Number of candidates removed from the other cells by a dry run, `None` on contradiction
+fn removed_candidates<'a, C: Copy + Eq + Hash>(
+    start: C,
+    restricted: &HashMap<C, HashSet<Tile>>,
+    wave: impl Fn(C) -> &'a HashSet<Tile>,
+) -> Option<usize> {
+    restricted
+        .iter()
+        .filter(|(cell, _)| **cell != start)
+        .map(|(cell, tiles)| match tiles.is_empty() {
+            true => None,
+            false => Some(wave(*cell).len() - tiles.len()),
+        })
+        .sum()
+}
+
+/// Maximum number of cells propagated by the dry run of each candidate, for the lookahead
+const LOOKAHEAD_MAX_STEPS: usize = 64;
+
+/// Keep the candidate cells whose neighbours have the smallest summed entropy
+fn most_constrained<T: Copy>(candidates: &[T], neighbour_entropy: impl Fn(T) -> usize) -> Vec<T> {
+    let entropies: Vec<usize> = candidates
+        .iter()
+        .map(|candidate| neighbour_entropy(*candidate))
+        .collect();
+    let min_entropy = entropies.iter().min().copied().unwrap_or_default();
+    candidates
+        .iter()
+        .zip(entropies)
+        .filter(|(_, entropy)| *entropy == min_entropy)
+        .map(|(candidate, _)| *candidate)
+        .collect()
+}
+
+/// Pick the tile leaving the most candidates to the other cells, biased by the tile weights
+///
+/// `removed` gives the number of candidates removed from the other cells by a tile, `None`
+/// when it leads to a contradiction. The choice falls back on all the tiles when all of
+/// them lead to a contradiction.
+fn observe_with_lookahead(
+    tiles: &HashSet<Tile>,
+    rules: &Rules,
+    rng: &mut impl Rng,
+    removed: impl Fn(Tile) -> Option<usize>,
+) -> Tile {
+    let scores: Vec<(Tile, Option<usize>)> =
+        tiles.iter().map(|tile| (*tile, removed(*tile))).collect();
+    let best = scores.iter().filter_map(|(_, removed)| *removed).min();
+    let candidates: HashSet<Tile> = match best {
+        Some(best) => scores
+            .iter()
+            .filter(|(_, removed)| *removed == Some(best))
+            .map(|(tile, _)| *tile)
+            .collect(),
+        None => tiles.clone(),
+    };
+    observe(&candidates, rules, rng)
+}
+
+/// Pick one of the possible tiles, biased by the tile weights
+///
+/// The cells without any candidate are never observed, `tiles` can't be empty.
+fn observe(tiles: &HashSet<Tile>, rules: &Rules, rng: &mut impl Rng) -> Tile {
+    // Sorted for the seeded generations to be reproducible
+    let mut tiles: Vec<&Tile> = tiles.iter().collect();
+    tiles.sort();
+    match tiles.choose_weighted(rng, |tile| rules.weight(tile)) {
+        Ok(tile) => **tile,
+        // All the weights are null
+        Err(_) => **tiles.choose(rng).unwrap(),
+    }
+}
 
-        // Get all its allowed values and its connectivity
-        let (_, propagating_wave, propagating_connectivity) =
-            query.get(propagating_entity).unwrap();
+/// Graphviz graph of the direct adjacencies, with an edge to each allowed neighbour
+///
+/// Edges are labeled with the side of the neighbour. The tiles are sorted to keep the
+/// output stable across runs.
+pub fn rules_to_dot(alloweds: &HashMap<Tile, Allowed>) -> String {
+    let mut tiles: Vec<&Tile> = alloweds.keys().collect();
+    tiles.sort();
+
+    let mut dot = String::from("digraph rules {\n");
+    for tile in tiles.iter() {
+        dot += &format!("    \"{}\";\n", tile);
+    }
+    for tile in tiles {
+        for orientation in Orientation::values() {
+            let mut neighbours: Vec<&Tile> = match alloweds[tile].allowed.get(&orientation) {
+                Some(neighbours) => neighbours.iter().collect(),
+                None => continue,
+            };
+            neighbours.sort();
+            for neighbour in neighbours {
+                dot += &format!(
+                    "    \"{}\" -> \"{}\" [label=\"{:?}\"];\n",
+                    tile, neighbour, orientation
+                );
+            }
+        }
+    }
+    dot += "}\n";
+    dot
+}
 
-        let propagating_wave = propagating_wave.tiles.clone();
-        let propagating_connectivity = propagating_connectivity.connectivity.clone();
+/// Human readable list of the allowed neighbours of each tile, by orientation
+pub fn describe_rules(alloweds: &HashMap<Tile, Allowed>) -> String {
+    let sorted = |tiles: &HashSet<Tile>| {
+        let mut tiles: Vec<&Tile> = tiles.iter().collect();
+        tiles.sort();
+        let tiles: Vec<String> = tiles.iter().map(|tile| tile.to_string()).collect();
+        tiles.join(", ")
+    };
 
-        // Find its neighbours
+    let mut tiles: Vec<&Tile> = alloweds.keys().collect();
+    tiles.sort();
+    let mut description = String::new();
+    for tile in tiles {
+        let allowed = &alloweds[tile];
+        description += &format!("{}\n", tile);
         for orientation in Orientation::values() {
-            if let Some(neighbour) = propagating_connectivity.get(&orientation) {
-                let neighbour_wave = &mut query
-                    .get_component_mut::<TileSuperposition>(*neighbour)
-                    .unwrap();
+            let neighbours = allowed.allowed.get(&orientation);
+            let neighbours = neighbours.map(sorted).unwrap_or_default();
+            description += &format!("    {:?}: {}\n", orientation, neighbours);
+        }
+        for (first, second) in Orientation::second_ring() {
+            if let Some(neighbours) = allowed.distant.get(&(first, second)) {
+                description += &format!("    {:?}-{:?}: {}\n", first, second, sorted(neighbours));
+            }
+        }
+    }
+    description
+}
 
-                // Skip if the neighbour is already resolved or impossible
-                if neighbour_wave.tiles.len() <= 1 {
-                    continue;
-                }
+/// Learn the adjacency constraints from a rule map, including all the tile variants
+///
+/// The tiles whose prototype is not in the palette are ignored.
+pub fn learn_rules(
+    rule_tiles: &[Vec<OptionalTile>],
+    prototypes: &[Prototype],
+    mirror: bool,
+) -> HashMap<Tile, Allowed> {
+    let known = |tile: &Tile| {
+        prototypes
+            .get(tile.prototype_index)
+            .is_some_and(|prototype| tile.part < prototype.parts())
+    };
+    let rule_tiles = &expand_footprints(rule_tiles, prototypes);
+
+    // Store the rule connectivities as constraints
+    let mut alloweds = HashMap::<Tile, Allowed>::new();
+    for (x, line) in rule_tiles.iter().enumerate() {
+        for (y, tile) in line.iter().enumerate() {
+            let coords = Coordinates::new(x as i32, y as i32);
+            if let Some(tile) = tile.tile.filter(known) {
+                let constraints = alloweds.entry(tile).or_default();
 
-                // Sum all the possible values for this neighbour given its own allowed values
-                let mut all_allowed_neighbour = HashSet::<Tile>::new();
-                for value in &propagating_wave {
-                    // Skip cases where there is a contradiction
-                    if let Some(allowed_list) = rules.alloweds.get(value) {
-                        if let Some(allowed_neighbour) = allowed_list.allowed.get(&orientation) {
-                            all_allowed_neighbour.extend(allowed_neighbour);
-                        }
+                for orientation in Orientation::values() {
+                    let neighbour_coords = orientation.offset(&coords);
+                    let neighbour_tile =
+                        get_tile_prototype(rule_tiles, &neighbour_coords).filter(known);
+                    if let Some(neighbour_tile) = neighbour_tile {
+                        constraints
+                            .allowed
+                            .entry(orientation)
+                            .or_default()
+                            .insert(neighbour_tile);
                     }
                 }
 
-                // Intersect the previous list of allowed values with the new constraints
-                let new_allowed_values =
-                    intersection(all_allowed_neighbour, &mut neighbour_wave.tiles);
-
-                // If impacted, update the tile and mark it as dirty for propagation
-                if &new_allowed_values != &neighbour_wave.tiles {
-                    neighbour_wave.tiles = new_allowed_values;
-                    if !neighbour_wave.tiles.is_empty() {
-                        // Don't propagate impossibility
-                        neighbour_wave.dirty = true;
+                // Second ring, including the diagonals, only used for propagation when enabled
+                for (first, second) in Orientation::second_ring() {
+                    let neighbour_coords = second.offset(&first.offset(&coords));
+                    let neighbour_tile =
+                        get_tile_prototype(rule_tiles, &neighbour_coords).filter(known);
+                    if let Some(neighbour_tile) = neighbour_tile {
+                        constraints
+                            .distant
+                            .entry((first, second))
+                            .or_default()
+                            .insert(neighbour_tile);
                     }
                 }
             }
         }
+    }
+    expand_variants(&alloweds, prototypes, mirror)
+}
 
-        // Mark the entity as not dirty
-        let mut wave = query
-            .get_component_mut::<TileSuperposition>(propagating_entity)
-            .unwrap();
-        wave.dirty = false;
+/// Add the rotated variants of the constraints, and their mirror images if enabled
+fn expand_variants(
+    alloweds: &HashMap<Tile, Allowed>,
+    prototypes: &[Prototype],
+    mirror: bool,
+) -> HashMap<Tile, Allowed> {
+    let alloweds = expand_with_rotations(alloweds, prototypes);
+    if mirror {
+        expand_with_mirror(&alloweds, prototypes)
+    } else {
+        alloweds
     }
 }
 
-fn prune_guess_history(
-    mut query: Query<&mut TileSuperpositionHistory>,
-    mut history: ResMut<GuessHistory>,
-    tuning: Res<Tuning>,
-) {
-    if history.history.len() <= tuning.backtrack_history_size {
-        return;
+/// Adjacency constraints of the rules, from the rule map or from their adjacency list
+pub fn learn_source(
+    rules: &Rules,
+    rule_tiles: &[Vec<OptionalTile>],
+    mirror: bool,
+) -> HashMap<Tile, Allowed> {
+    match &rules.source {
+        RuleSource::ExampleMap => learn_rules(rule_tiles, &rules.prototypes, mirror),
+        RuleSource::AdjacencyList(adjacencies) => {
+            expand_variants(adjacencies, &rules.prototypes, mirror)
+        }
+    }
+}
+
+/// Read an adjacency list written by hand, a `tile side neighbour` adjacency per line
+///
+/// Tiles are referred to by the name of their prototype, optionally followed by their
+/// orientation, like `ground_pathBend@East`. They are North by default. The side is one of
+/// `N`, `E`, `S` and `W`, or their full name. Each adjacency is allowed both ways, and
+/// the empty lines and the ones starting with `#` are skipped.
+pub fn parse_adjacency_list(
+    text: &str,
+    prototypes: &[Prototype],
+) -> Result<HashMap<Tile, Allowed>, String> {
+    let orientation = |name: &str| match name {
+        "N" | "North" => Some(Orientation::North),
+        "E" | "East" => Some(Orientation::East),
+        "S" | "South" => Some(Orientation::South),
+        "W" | "West" => Some(Orientation::West),
+        _ => None,
+    };
+    let tile = |name: &str| {
+        let (prototype_name, orientation_name) = name.split_once('@').unwrap_or((name, "N"));
+        let prototype = prototypes
+            .iter()
+            .find(|prototype| prototype.name == prototype_name)
+            .ok_or_else(|| format!("unknown tile {}", prototype_name))?;
+        let orientation = orientation(orientation_name)
+            .ok_or_else(|| format!("unknown orientation {}", orientation_name))?;
+        Ok::<Tile, String>(prototype.make_tile(orientation))
+    };
+
+    let mut alloweds = HashMap::<Tile, Allowed>::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [from, side, to] => tile(from).and_then(|from| {
+                let side = orientation(side).ok_or_else(|| format!("unknown side {}", side))?;
+                Ok((from, side, tile(to)?))
+            }),
+            _ => Err("expected a tile, a side and a neighbour".to_string()),
+        };
+        let (from, side, to) = parsed.map_err(|e| format!("line {}: {}", number + 1, e))?;
+
+        let adjacencies = [(from, side, to), (to, side.rotated(2), from)];
+        for (tile, side, neighbour) in adjacencies {
+            alloweds
+                .entry(tile)
+                .or_default()
+                .allowed
+                .entry(side)
+                .or_default()
+                .insert(neighbour);
+        }
     }
+    Ok(alloweds)
+}
+
+/// Rule tiles teaching each direct adjacency learned by `learn_rules`
+///
+/// An adjacency is credited to the tile allowing the neighbour, its own variants and
+/// mirror images included.
+pub fn learn_provenance(
+    rule_tiles: &[Vec<OptionalTile>],
+    prototypes: &[Prototype],
+    mirror: bool,
+) -> HashMap<(Tile, Orientation, Tile), Vec<Coordinates>> {
+    let known = |tile: &Tile| {
+        prototypes
+            .get(tile.prototype_index)
+            .is_some_and(|prototype| tile.part < prototype.parts())
+    };
+    let rule_tiles = &expand_footprints(rule_tiles, prototypes);
 
-    history.history.pop_back();
+    let mut provenance = HashMap::<(Tile, Orientation, Tile), Vec<Coordinates>>::new();
+    for (x, line) in rule_tiles.iter().enumerate() {
+        for (y, tile) in line.iter().enumerate() {
+            let coords = Coordinates::new(x as i32, y as i32);
+            let tile = match tile.tile.filter(known) {
+                Some(tile) => tile,
+                None => continue,
+            };
+            // Expanded like the whole rule map, one adjacency at a time
+            let mut taught = HashMap::<Tile, Allowed>::new();
+            for orientation in Orientation::values() {
+                let neighbour_coords = orientation.offset(&coords);
+                if let Some(neighbour) =
+                    get_tile_prototype(rule_tiles, &neighbour_coords).filter(known)
+                {
+                    taught
+                        .entry(tile)
+                        .or_default()
+                        .allowed
+                        .insert(orientation, HashSet::from([neighbour]));
+                }
+            }
+            let taught = expand_with_rotations(&taught, prototypes);
+            let taught = match mirror {
+                true => expand_with_mirror(&taught, prototypes),
+                false => taught,
+            };
 
-    for mut tile_history in query.iter_mut() {
-        tile_history.history.pop_back();
+            let adjacencies: HashSet<(Tile, Orientation, Tile)> = taught
+                .iter()
+                .flat_map(|(tile, allowed)| {
+                    allowed
+                        .allowed
+                        .iter()
+                        .flat_map(move |(orientation, neighbours)| {
+                            neighbours
+                                .iter()
+                                .map(move |neighbour| (*tile, *orientation, *neighbour))
+                        })
+                })
+                .collect();
+            for adjacency in adjacencies {
+                provenance.entry(adjacency).or_default().push(coords);
+            }
+        }
     }
+    provenance
 }
 
-fn backtrack(
-    mut query: Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
-    mut history: ResMut<GuessHistory>,
+/// Record the provenance of the adjacencies again when the rules change, if enabled
+fn update_rule_provenance(
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
+    mut provenance: ResMut<RuleProvenance>,
 ) {
-    if history.history.len() == 0 {
+    if !tuning.record_rule_provenance {
+        if !provenance.adjacencies.is_empty() || provenance.selected.is_some() {
+            *provenance = RuleProvenance::default();
+        }
         return;
     }
-
-    // Find if a contradiction occured
-    let mut contradiction = false;
-    for (wave, _) in query.iter() {
-        if wave.tiles.len() == 0 {
-            contradiction = true;
-            break;
-        }
+    if !rules.is_changed() && !tuning.is_changed() {
+        return;
     }
 
-    if !contradiction {
+    let rule_tiles = MapRule::read(
+        rules_query.iter(),
+        tuning.rule_map_width,
+        tuning.rule_map_height,
+    )
+    .map;
+    let adjacencies = learn_provenance(&rule_tiles, &rules.prototypes, tuning.mirror_rules);
+    if adjacencies == provenance.adjacencies {
         return;
     }
+    provenance.adjacencies = adjacencies;
+    let selected = provenance.selected;
+    if selected.is_some_and(|selected| !provenance.adjacencies.contains_key(&selected)) {
+        provenance.selected = None;
+    }
+}
 
-    info!("Contradiction detected, backtracking");
+#[allow(clippy::too_many_arguments)]
+fn update_rules(
+    mut rules: ResMut<Rules>,
+    tuning: Res<Tuning>,
+    mut mirror_rules: Local<bool>,
+    mut diagnostics: ResMut<RuleDiagnostics>,
+    rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
+    spawned_rules_query: Query<(), Added<RuleTileTag>>,
+    mut event_reader: EventReader<RulesNeedUpdateEvent>,
+    mut tiles_query: Query<(Entity, &mut TileSuperposition, &Connectivity)>,
+    mut stats: ResMut<CollapseStats>,
+    banned: Res<BannedTiles>,
+    mut map_edits: ResMut<MapEditHistory>,
+    mut state: ResMut<GenerationState>,
+    mut rng: ResMut<GenerationRng>,
+    mut info: ResMut<GenerationInfo>,
+    mut warned_empty: Local<bool>,
+    mut frozen: ResMut<FrozenCells>,
+) {
+    // The rule map is spawned once the models are loaded
+    let rule_map_spawned = !spawned_rules_query.is_empty();
+    if !event_reader.is_empty() || rule_map_spawned || *mirror_rules != tuning.mirror_rules {
+        for _ in event_reader.iter() {}
+        *mirror_rules = tuning.mirror_rules;
+        // Rule change
 
-    // Restore the previous state
-    for (mut wave, mut wave_history) in query.iter_mut() {
-        // TODO: better link between the global and individual history
-        let previous = wave_history.history.pop_front().unwrap();
-        wave.tiles = previous;
-        wave.dirty = false;
+        let rule_tiles = MapRule::read(
+            rules_query.iter(),
+            tuning.rule_map_width,
+            tuning.rule_map_height,
+        )
+        .map;
+        let unknown = rule_tiles
+            .iter()
+            .flatten()
+            .filter_map(|tile| tile.tile)
+            .filter(|tile| rules.prototype(tile).is_none())
+            .count();
+        if unknown > 0 {
+            error!(
+                "{} rule tiles have no prototype in the palette and are ignored",
+                unknown
+            );
+        }
+        rules.alloweds = learn_source(&rules, &rule_tiles, tuning.mirror_rules);
+        let declared = rules.declared.clone();
+        let prototypes = rules.prototypes.clone();
+        declare(
+            &mut rules.alloweds,
+            &declared,
+            &prototypes,
+            tuning.mirror_rules,
+        );
+        let forbidden = rules.forbidden.clone();
+        forbid(&mut rules.alloweds, &forbidden);
+        match rules.alloweds.is_empty() {
+            true if !*warned_empty => {
+                warn!("No rules learned, paint the rule map to start the generation");
+                *warned_empty = true;
+            }
+            true => (),
+            false => *warned_empty = false,
+        }
+
+        // Warn about the tiles leading to contradictions
+        *diagnostics = validate_rules(&rules.alloweds);
+        if !diagnostics.dead_ends.is_empty() {
+            let dead_ends: Vec<String> = diagnostics
+                .dead_ends
+                .iter()
+                .map(|(tile, orientations)| {
+                    format!(
+                        "{} {:?} (no neighbour {:?})",
+                        tile.prototype_index, tile.orientation, orientations
+                    )
+                })
+                .collect();
+            warn!(
+                "{} tiles can't be surrounded, generation will likely fail: {}",
+                dead_ends.len(),
+                dead_ends.join(", ")
+            );
+        }
+        if !diagnostics.asymmetries.is_empty() {
+            let asymmetries: Vec<String> = diagnostics
+                .asymmetries
+                .iter()
+                .map(|(tile, orientation, neighbour)| {
+                    format!("{} allows {} to its {:?}", tile, neighbour, orientation)
+                })
+                .collect();
+            warn!(
+                "{} adjacencies are not allowed back: {}",
+                asymmetries.len(),
+                asymmetries.join(", ")
+            );
+        }
+
+        // Reset to every possibilities on rule change
+        let possible_tiles = rules.possible_tiles(&banned);
+        if possible_tiles.is_empty() && !rules.alloweds.is_empty() {
+            warn!("All the tiles are banned, generation will fail");
+        }
+        for (_, mut multi_tile_prototype, _) in tiles_query.iter_mut() {
+            multi_tile_prototype.tiles = possible_tiles.clone();
+        }
+        // Settled for the previous rules only
+        frozen.0.clear();
+        map_edits.undo.clear();
+        stats.backtracks = 0;
+        info.start(&mut rng, false);
+        state.idle = false;
     }
+}
 
-    // Exclude the bad guess
-    let (entity, bad_guess) = history.history.pop_front().unwrap();
-    let mut wave = query
-        .get_component_mut::<TileSuperposition>(entity)
+fn update_connectivity(
+    tuning: Res<Tuning>,
+    mut wrap: Local<Option<bool>>,
+    mut query: Query<(Entity, &Coordinates, &mut Connectivity)>,
+    mut event_writer: EventWriter<RegenerateEvent>,
+) {
+    if *wrap == Some(tuning.wrap) {
+        return;
+    }
+    let initial = wrap.is_none();
+    *wrap = Some(tuning.wrap);
+
+    let entities: HashMap<Coordinates, Entity> = query
+        .iter()
+        .map(|(entity, coordinates, _)| (*coordinates, entity))
+        .collect();
+
+    for (_, coordinates, mut connectivity) in query.iter_mut() {
+        *connectivity = Connectivity::new(
+            &entities,
+            coordinates,
+            tuning.map_width,
+            tuning.map_height,
+            tuning.wrap,
+        );
+    }
+
+    // Restart the generation with the new connectivity
+    if !initial {
+        event_writer.send(RegenerateEvent {});
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn regenerate(
+    rules: Res<Rules>,
+    banned: Res<BannedTiles>,
+    mut event_reader: EventReader<RegenerateEvent>,
+    mut query: Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
+    mut history: ResMut<GuessHistory>,
+    mut map_edits: ResMut<MapEditHistory>,
+    mut stats: ResMut<CollapseStats>,
+    mut state: ResMut<GenerationState>,
+    mut rng: ResMut<GenerationRng>,
+    mut info: ResMut<GenerationInfo>,
+    mut frozen: ResMut<FrozenCells>,
+) {
+    if event_reader.iter().count() == 0 {
+        return;
+    }
+
+    // Reset to every possibilities, without rebuilding the rules
+    let possible_tiles = rules.possible_tiles(&banned);
+    for (mut wave, mut wave_history) in query.iter_mut() {
+        wave.tiles = possible_tiles.clone();
+        wave.dirty = false;
+        wave_history.history.clear();
+    }
+    history.history.clear();
+    frozen.0.clear();
+    map_edits.undo.clear();
+    stats.backtracks = 0;
+    info.start(&mut rng, true);
+    state.idle = false;
+}
+
+/// Restart the generation with the rule map copied in the top left corner of the map
+///
+/// The copied tiles are set before any observation, so the backtracking never reverts
+/// them. The empty rule tiles are left to the generation.
+#[allow(clippy::too_many_arguments)]
+fn stamp_rule_map(
+    rules: Res<Rules>,
+    banned: Res<BannedTiles>,
+    mut event_reader: EventReader<StampRuleMapEvent>,
+    rules_query: Query<(&OptionalTile, &Coordinates), With<RuleTileTag>>,
+    mut query: Query<(
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+    )>,
+    mut history: ResMut<GuessHistory>,
+    mut map_edits: ResMut<MapEditHistory>,
+    mut stats: ResMut<CollapseStats>,
+    mut state: ResMut<GenerationState>,
+    mut rng: ResMut<GenerationRng>,
+    mut info: ResMut<GenerationInfo>,
+) {
+    if event_reader.iter().count() == 0 {
+        return;
+    }
+
+    let rule_tiles: HashMap<Coordinates, Tile> = rules_query
+        .iter()
+        .filter_map(|(tile, coordinates)| Some((*coordinates, tile.tile?)))
+        .collect();
+    let possible_tiles = rules.possible_tiles(&banned);
+    let mut skipped = 0;
+    for (mut wave, coordinates, mut wave_history) in query.iter_mut() {
+        wave_history.history.clear();
+        match rule_tiles.get(coordinates) {
+            Some(tile) if possible_tiles.contains(tile) => {
+                wave.tiles = HashSet::from([*tile]);
+                wave.dirty = true;
+            }
+            rule_tile => {
+                skipped += rule_tile.is_some() as usize;
+                wave.tiles = possible_tiles.clone();
+                wave.dirty = false;
+            }
+        }
+    }
+    if skipped > 0 {
+        warn!("{} banned rule tiles were not copied", skipped);
+    }
+    history.history.clear();
+    map_edits.undo.clear();
+    stats.backtracks = 0;
+    info.start(&mut rng, true);
+    state.idle = false;
+}
+
+/// Resolve every undecided cell at once, without randomness
+///
+/// Cells are visited by coordinates, each taking its lowest index candidate before
+/// propagating. The picks aren't recorded as guesses the backtracking could undo, so on
+/// contradiction the map is restored as it was before finalizing, and the generation
+/// carries on from there.
+///
+/// The cells are restricted like before the observations: to the border prototype on the
+/// edges, to the tiles whose footprint fits and that face the orientation locks, and to
+/// the prototypes within their budget before each pick.
+#[allow(clippy::too_many_arguments)]
+fn finalize(
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    mut event_reader: EventReader<FinalizeEvent>,
+    mut query: Query<(Entity, &mut TileSuperposition, &Coordinates, &Connectivity)>,
+    mut state: ResMut<GenerationState>,
+    budget: Res<TileBudget>,
+    locks: Query<&OrientationLock>,
+) {
+    if event_reader.iter().count() == 0 {
+        return;
+    }
+
+    // Cells are stored in a flat list ordered by coordinates, with their neighbours
+    let mut cells: Vec<(Entity, Coordinates)> = query
+        .iter()
+        .map(|(entity, _, coordinates, _)| (entity, *coordinates))
+        .collect();
+    cells.sort_by_key(|(_, coordinates)| (coordinates.x, coordinates.y));
+    let cells: Vec<Entity> = cells.into_iter().map(|(entity, _)| entity).collect();
+    let paths = neighbour_paths(&tuning);
+    let step = |entity: Entity, orientation: Orientation| {
+        query
+            .get_component::<Connectivity>(entity)
+            .ok()
+            .and_then(|connectivity| connectivity.connectivity.get(&orientation).copied())
+    };
+    let neighbours = indexed_neighbours(&cells, &paths, step);
+
+    let border_prototype = tuning.border_prototype.filter(|_| !tuning.wrap);
+    let mut waves = Vec::new();
+    let mut dirty = Vec::new();
+    for (index, entity) in cells.iter().enumerate() {
+        let wave = query.get_component::<TileSuperposition>(*entity).unwrap();
+        let mut tiles = wave.tiles.clone();
+        let on_border = Orientation::values()
+            .iter()
+            .any(|orientation| step(*entity, *orientation).is_none());
+        let off_border = |tile: &Tile| {
+            on_border && border_prototype.is_some_and(|border| tile.prototype_index != border)
+        };
+        let lock = locks.get(*entity).ok();
+        tiles.retain(|tile| {
+            !off_border(tile)
+                && footprint_fits(&rules.prototypes, tile, *entity, step)
+                && !matches!(lock, Some(lock) if !lock.allows(tile, &rules))
+        });
+        if wave.dirty || tiles.len() != wave.tiles.len() {
+            dirty.push(index);
+        }
+        waves.push(tiles);
+    }
+
+    // Remove the prototypes having spent their budget from the undecided cells, returning
+    // the restricted ones, or None when more tiles are resolved than allowed
+    let restrict_budget = |waves: &mut [HashSet<Tile>]| -> Option<Vec<usize>> {
+        if budget.0.is_empty() {
+            return Some(Vec::new());
+        }
+        let counts = TileHistogram::count(waves.iter()).counts;
+        let count = |index: &usize| counts.get(index).copied().unwrap_or(0);
+        if budget.0.iter().any(|(index, max)| count(index) > *max) {
+            return None;
+        }
+        let spent = |tile: &Tile| {
+            let max = budget.0.get(&tile.prototype_index);
+            max.is_some_and(|max| count(&tile.prototype_index) >= *max)
+        };
+        let mut restricted = Vec::new();
+        for (cell, wave) in waves.iter_mut().enumerate() {
+            if wave.len() > 1 && wave.iter().any(spent) {
+                wave.retain(|tile| !spent(tile));
+                restricted.push(cell);
+            }
+        }
+        Some(restricted)
+    };
+
+    // Finish the ongoing propagation before observing anything
+    let parallel = tuning.parallel_propagation;
+    let frozen = vec![false; waves.len()];
+    let mut consistent = propagate(
+        &mut waves,
+        dirty,
+        &neighbours,
+        &frozen,
+        &rules.alloweds,
+        parallel,
+    );
+    for cell in 0..waves.len() {
+        if !consistent {
+            break;
+        }
+        if waves[cell].len() <= 1 {
+            continue;
+        }
+        match restrict_budget(&mut waves) {
+            Some(restricted) if restricted.is_empty() => (),
+            Some(restricted) => {
+                consistent = propagate(
+                    &mut waves,
+                    restricted,
+                    &neighbours,
+                    &frozen,
+                    &rules.alloweds,
+                    parallel,
+                );
+                // Resolved by the restriction, or contradicting
+                if !consistent || waves[cell].len() <= 1 {
+                    continue;
+                }
+            }
+            None => {
+                consistent = false;
+                continue;
+            }
+        }
+        let lowest = *waves[cell].iter().min().unwrap();
+        waves[cell] = HashSet::from([lowest]);
+        consistent = propagate(
+            &mut waves,
+            vec![cell],
+            &neighbours,
+            &frozen,
+            &rules.alloweds,
+            parallel,
+        );
+    }
+    // The last picks may resolve more tiles than allowed
+    consistent = consistent && restrict_budget(&mut waves).is_some();
+    if !consistent {
+        warn!("Finalizing the map led to a contradiction, the map is left as it was");
+        return;
+    }
+
+    for (entity, tiles) in cells.iter().zip(waves) {
+        let mut wave = query
+            .get_component_mut::<TileSuperposition>(*entity)
+            .unwrap();
+        wave.tiles = tiles;
+        wave.dirty = false;
+    }
+    state.idle = false;
+}
+
+#[allow(clippy::too_many_arguments)]
+fn observe_system(
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    mut query: Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+        &Connectivity,
+    )>,
+    mut history: ResMut<GuessHistory>,
+    mut focus: ResMut<FocusCell>,
+    mut profile: ResMut<PropagationProfile>,
+    mut rng: ResMut<GenerationRng>,
+    state: Res<GenerationState>,
+    budget: Res<TileBudget>,
+    locks: Query<(Entity, &OrientationLock)>,
+) {
+    if state.idle {
+        return;
+    }
+    if tuning.is_changed() && tuning.wrap && tuning.border_prototype.is_some() {
+        warn!("The border prototype is ignored when wrapping around the map");
+    }
+    if let (Some(border_prototype), false) = (tuning.border_prototype, tuning.wrap) {
+        let restricted = restrict_border(&mut query, border_prototype);
+        if restricted {
+            // Observe once the border is propagated
+            return;
+        }
+    }
+    if restrict_footprints(&mut query, &rules) {
+        return;
+    }
+    if restrict_orientations(&mut query, &locks, &rules) {
+        return;
+    }
+    if restrict_budget(&mut query, &budget) {
+        return;
+    }
+    let rng = &mut rng.0;
+    // Find the undecided cell with the lowest score, by default the smallest > 1 entropy
+    let mut min_entropy_entities = Vec::new();
+    let mut min_score = f32::MAX;
+
+    for (entity, wave, coordinates, _, _) in query.iter() {
+        if wave.dirty {
+            // ongoing propagation
+            return;
+        }
+        let entropy = wave.tiles.len();
+        if entropy <= 1 {
+            // Resolved or impossible
+            continue;
+        }
+
+        let score = match tuning.collapse_strategy {
+            CollapseStrategy::MinEntropy => entropy as f32,
+            strategy => strategy.priority(coordinates, tuning.map_width, tuning.map_height),
+        };
+        if score < min_score {
+            min_score = score;
+            min_entropy_entities.clear();
+        }
+
+        if score == min_score {
+            min_entropy_entities.push(entity);
+        }
+    }
+
+    if tuning.constrained_tie_break {
+        min_entropy_entities = most_constrained(&min_entropy_entities, |entity| {
+            let connectivity = query.get_component::<Connectivity>(entity).unwrap();
+            connectivity
+                .connectivity
+                .values()
+                .filter_map(|neighbour| query.get_component::<TileSuperposition>(*neighbour).ok())
+                .map(|wave| wave.tiles.len())
+                .sum()
+        });
+    }
+
+    let min_entropy_entity = match min_entropy_entities.choose(rng) {
+        Some(e) => *e,
+        // Solved or impossible
+        None => {
+            if focus.0.is_some() {
+                focus.0 = None;
+            }
+            return;
+        }
+    };
+    focus.0 = Some(min_entropy_entity);
+
+    let min_entropy_wave = query
+        .get_component_mut::<TileSuperposition>(min_entropy_entity)
+        .unwrap()
+        .clone();
+
+    // Observe the tile with the smallest entropy
+    let observed = match tuning.lookahead_observation {
+        true => {
+            let paths = neighbour_paths(&tuning);
+            let neighbours = |entity: Entity| {
+                find_neighbours(entity, &paths, |entity, orientation| {
+                    let connectivity = query.get_component::<Connectivity>(entity).ok()?;
+                    connectivity.connectivity.get(&orientation).copied()
+                })
+            };
+            let wave = |entity: Entity| {
+                &query
+                    .get_component::<TileSuperposition>(entity)
+                    .unwrap()
+                    .tiles
+            };
+            observe_with_lookahead(&min_entropy_wave.tiles, &rules, rng, |tile| {
+                let restricted = dry_run(
+                    &rules.alloweds,
+                    min_entropy_entity,
+                    tile,
+                    neighbours,
+                    wave,
+                    Some(LOOKAHEAD_MAX_STEPS),
+                    tuning.parallel_propagation,
+                );
+                removed_candidates(min_entropy_entity, &restricted, wave)
+            })
+        }
+        false => observe(&min_entropy_wave.tiles, &rules, rng),
+    };
+
+    // Save the current state and this guess in the tiles history
+    history.history.push_front((min_entropy_entity, observed));
+    for (_, wave, _, mut wave_history, _) in query.iter_mut() {
+        wave_history.history.push_front(wave.tiles.clone());
+    }
+
+    // Observe the tile
+    let mut min_entropy_wave = query
+        .get_component_mut::<TileSuperposition>(min_entropy_entity)
         .unwrap();
-    wave.tiles.remove(&bad_guess);
+    min_entropy_wave.tiles.clear();
+    min_entropy_wave.tiles.insert(observed.clone());
+
+    // Signal for propagation
+    min_entropy_wave.dirty = true;
+    *profile = PropagationProfile::default();
+}
+
+/// Restrict the cells on the edges of the map to the tiles of a prototype
+///
+/// A cell is on the edge when it lacks a neighbour, which includes the cells along the
+/// holes of an irregular map.
+///
+/// Returns whether any cell was restricted, and needs to propagate.
+fn restrict_border(
+    query: &mut Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+        &Connectivity,
+    )>,
+    border_prototype: usize,
+) -> bool {
+    let mut restricted = false;
+    for (_, mut wave, _, _, connectivity) in query.iter_mut() {
+        // Missing neighbours, past the map edges or the ones cut out of irregular maps
+        let on_border = Orientation::values()
+            .iter()
+            .any(|orientation| !connectivity.connectivity.contains_key(orientation));
+        let outside_tiles = wave
+            .tiles
+            .iter()
+            .any(|tile| tile.prototype_index != border_prototype);
+        if on_border && outside_tiles {
+            wave.tiles
+                .retain(|tile| tile.prototype_index == border_prototype);
+            // Don't propagate impossibility
+            wave.dirty = !wave.tiles.is_empty();
+            restricted = true;
+        }
+    }
+    restricted
+}
+
+/// Remove the parts of multi-cell tiles whose footprint runs off the map from their cell
+///
+/// Returns whether any cell was restricted, and needs to propagate.
+fn restrict_footprints(
+    query: &mut Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+        &Connectivity,
+    )>,
+    rules: &Rules,
+) -> bool {
+    if rules
+        .prototypes
+        .iter()
+        .all(|prototype| prototype.footprint.is_empty())
+    {
+        return false;
+    }
+
+    let step = |entity: Entity, orientation: Orientation| {
+        query
+            .get_component::<Connectivity>(entity)
+            .ok()
+            .and_then(|connectivity| connectivity.connectivity.get(&orientation).copied())
+    };
+    let misfits: Vec<(Entity, Vec<Tile>)> = query
+        .iter()
+        .filter_map(|(entity, wave, _, _, _)| {
+            let misfits: Vec<Tile> = wave
+                .tiles
+                .iter()
+                .filter(|tile| !footprint_fits(&rules.prototypes, tile, entity, step))
+                .copied()
+                .collect();
+            (!misfits.is_empty()).then_some((entity, misfits))
+        })
+        .collect();
+
+    for (entity, tiles) in misfits.iter() {
+        let mut wave = query
+            .get_component_mut::<TileSuperposition>(*entity)
+            .unwrap();
+        for tile in tiles {
+            wave.tiles.remove(tile);
+        }
+        // Don't propagate impossibility
+        wave.dirty = !wave.tiles.is_empty();
+    }
+    !misfits.is_empty()
+}
+
+/// Restrict the cells with an orientation lock to the tiles facing it
+///
+/// Returns whether any cell was restricted, and needs to propagate. As it runs before
+/// every observation, the locks hold through the resets and the backtracking.
+fn restrict_orientations(
+    query: &mut Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+        &Connectivity,
+    )>,
+    locks: &Query<(Entity, &OrientationLock)>,
+    rules: &Rules,
+) -> bool {
+    let mut restricted = false;
+    for (entity, lock) in locks.iter() {
+        let mut wave = match query.get_component_mut::<TileSuperposition>(entity) {
+            Ok(wave) => wave,
+            Err(_) => continue,
+        };
+        if wave.tiles.iter().all(|tile| lock.allows(tile, rules)) {
+            continue;
+        }
+        wave.tiles.retain(|tile| lock.allows(tile, rules));
+        // Don't propagate impossibility
+        wave.dirty = !wave.tiles.is_empty();
+        restricted = true;
+    }
+    restricted
+}
+
+/// Remove the prototypes having spent their budget from the undecided cells
+///
+/// Returns whether any cell was restricted, and needs to propagate. When the last pass
+/// resolved more tiles of a prototype than its budget allows, only the cells resolved by
+/// this pass beyond the budget are emptied: the contradiction is left to the backtracking,
+/// which restores the other cells anyway.
+fn restrict_budget(
+    query: &mut Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+        &Connectivity,
+    )>,
+    budget: &TileBudget,
+) -> bool {
+    if budget.0.is_empty() {
+        return false;
+    }
+    let counts = TileHistogram::count(query.iter().map(|(_, wave, _, _, _)| &wave.tiles)).counts;
+    let spent = |index: &usize| {
+        let count = counts.get(index).copied().unwrap_or(0);
+        budget.0.get(index).map(|max| count.cmp(max))
+    };
+    if budget
+        .0
+        .keys()
+        .all(|index| spent(index) == Some(Ordering::Less))
+    {
+        return false;
+    }
+
+    // Resolved since the last observation, or since the start without any
+    let resolved_in_pass = |wave: &TileSuperposition, history: &TileSuperpositionHistory| {
+        wave.tiles.len() == 1 && !matches!(history.history.front(), Some(tiles) if tiles.len() == 1)
+    };
+    // The budget left to this pass, once the tiles resolved before are counted
+    let mut allowance = budget.0.clone();
+    for (_, wave, _, history, _) in query.iter() {
+        if wave.tiles.len() == 1 && !resolved_in_pass(wave, history) {
+            for tile in wave.tiles.iter().filter(|tile| tile.part == 0) {
+                if let Some(left) = allowance.get_mut(&tile.prototype_index) {
+                    *left = left.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    let mut restricted = false;
+    for (_, mut wave, _, history, _) in query.iter_mut() {
+        if wave.tiles.len() == 1 {
+            if !resolved_in_pass(&wave, &history) {
+                continue;
+            }
+            let over = wave.tiles.iter().any(|tile| {
+                tile.part == 0 && spent(&tile.prototype_index) == Some(Ordering::Greater)
+            });
+            if over {
+                let tile = *wave.tiles.iter().next().unwrap();
+                match allowance.get_mut(&tile.prototype_index) {
+                    Some(left) if *left > 0 => *left -= 1,
+                    _ => {
+                        wave.tiles.clear();
+                        restricted = true;
+                    }
+                }
+            }
+            continue;
+        }
+        let outside_budget =
+            |tile: &Tile| spent(&tile.prototype_index).is_some_and(Ordering::is_ge);
+        if wave.tiles.iter().any(outside_budget) {
+            wave.tiles.retain(|tile| !outside_budget(tile));
+            // Don't propagate impossibility
+            wave.dirty = !wave.tiles.is_empty();
+            restricted = true;
+        }
+    }
+    restricted
+}
+
+/// Resume the generation when an orientation lock is set
+fn wake_on_orientation_locks(
+    query: Query<(), Changed<OrientationLock>>,
+    mut state: ResMut<GenerationState>,
+) {
+    if !query.is_empty() && state.idle {
+        state.idle = false;
+    }
+}
+
+/// Collapse the cells to the requested tiles, once the ongoing propagation is over
+#[allow(clippy::too_many_arguments)]
+fn set_tile(
+    mut event_reader: EventReader<SetTileEvent>,
+    mut pending: Local<Vec<SetTileEvent>>,
+    mut query: Query<(
+        Entity,
+        &mut TileSuperposition,
+        &Coordinates,
+        &mut TileSuperpositionHistory,
+    )>,
+    mut history: ResMut<GuessHistory>,
+    mut map_edits: ResMut<MapEditHistory>,
+    tuning: Res<Tuning>,
+    mut state: ResMut<GenerationState>,
+) {
+    pending.extend(event_reader.iter().cloned());
+    if pending.is_empty() || query.iter().any(|(_, wave, _, _)| wave.dirty) {
+        return;
+    }
+
+    // One tile at a time, for the backtracking to exclude it alone
+    let event = pending.remove(0);
+    let entity = query
+        .iter()
+        .find(|(_, _, coordinates, _)| **coordinates == event.coordinates)
+        .map(|(entity, wave, _, _)| (entity, wave.tiles.contains(&event.tile)));
+    match entity {
+        None => warn!(
+            "No cell at {}:{} to set {}",
+            event.coordinates.x, event.coordinates.y, event.tile
+        ),
+        Some((_, false)) => warn!(
+            "{} is not possible at {}:{}",
+            event.tile, event.coordinates.x, event.coordinates.y
+        ),
+        Some((entity, true)) => {
+            map_edits.undo.push_front(
+                query
+                    .iter()
+                    .map(|(entity, wave, _, _)| (entity, wave.tiles.clone()))
+                    .collect(),
+            );
+            map_edits.undo.truncate(tuning.undo_history_size);
+
+            // Save the current state, the tile is reverted like a bad guess on contradiction
+            history.history.push_front((entity, event.tile));
+            for (_, wave, _, mut wave_history) in query.iter_mut() {
+                wave_history.history.push_front(wave.tiles.clone());
+            }
+
+            let mut wave = query
+                .get_component_mut::<TileSuperposition>(entity)
+                .unwrap();
+            wave.tiles = HashSet::from([event.tile]);
+            wave.dirty = true;
+            state.idle = false;
+        }
+    }
+}
+
+/// Maximum number of local resets per frame, when enabled
+const MAX_LOCAL_RESETS_PER_FRAME: usize = 10;
+
+#[allow(clippy::too_many_arguments)]
+fn collapse(
+    rules: Res<Rules>,
+    tuning: Res<Tuning>,
+    banned: Res<BannedTiles>,
+    mut query: Query<(Entity, &mut TileSuperposition, &Connectivity)>,
+    mut profile: ResMut<PropagationProfile>,
+    mut state: ResMut<GenerationState>,
+    history: Res<GuessHistory>,
+    mut rng: ResMut<GenerationRng>,
+    mut rules_events: EventReader<RulesNeedUpdateEvent>,
+    coordinates_query: Query<&Coordinates>,
+    mut frozen: ResMut<FrozenCells>,
+) {
+    let rules_changing = rules_events.iter().count() > 0;
+    if state.idle {
+        return;
+    }
+    // Nothing to generate until some rules are painted, see `update_rules`
+    if rules.alloweds.is_empty() && !rules_changing {
+        state.idle = true;
+        return;
+    }
+
+    let paths = neighbour_paths(&tuning);
+    let mut local_resets = 0;
+    let steps = match tuning.step_propagation {
+        true => 1,
+        false => tuning.collapse_per_frame,
+    };
+    for _ in 0..steps {
+        // Find a dirty wave
+        let mut propagating_entity = Option::<Entity>::default();
+        for (entity, wave, _) in query.iter() {
+            if wave.dirty {
+                propagating_entity = Some(entity);
+                break;
+            }
+        }
+
+        let propagating_entity = match propagating_entity {
+            Some(e) => e,
+            None => {
+                if !profile.complete && !profile.visits.is_empty() {
+                    profile.complete = true;
+                    frozen.0 = match tuning.freeze_settled {
+                        true => settled_entities(&query, &paths, &rules.alloweds),
+                        false => HashMap::new(),
+                    };
+                }
+
+                // Nothing left to observe or to backtrack, idle until the next change
+                let settled = query.iter().all(|(_, wave, _)| match wave.tiles.len() {
+                    0 => history.history.is_empty(),
+                    1 => true,
+                    _ => false,
+                });
+                if settled && !rules_changing {
+                    state.idle = true;
+                }
+                return;
+            }
+        };
+        // A new pass, started by an observation or by any other change of the waves
+        if profile.complete {
+            *profile = PropagationProfile::default();
+        }
+        // Only settled neighbours around, which the propagation would skip anyway
+        let mut wave = query
+            .get_component_mut::<TileSuperposition>(propagating_entity)
+            .unwrap();
+        let frozen_tile = frozen.0.get(&propagating_entity);
+        if wave.tiles.len() == 1 && matches!(frozen_tile, Some(tile) if wave.tiles.contains(tile)) {
+            wave.dirty = false;
+            continue;
+        }
+        *profile.visits.entry(propagating_entity).or_default() += 1;
+        let propagations: usize = profile.visits.values().sum();
+        if tuning.propagation_cap > 0 && propagations > tuning.propagation_cap {
+            let mut oscillating: Vec<(&Entity, &usize)> = profile.visits.iter().collect();
+            oscillating.sort_by(|a, b| b.1.cmp(a.1));
+            let hottest = *oscillating[0].0;
+            let oscillating: Vec<String> = oscillating
+                .iter()
+                .take(5)
+                .filter_map(|(entity, count)| {
+                    let coordinates = coordinates_query.get(**entity).ok()?;
+                    Some(format!("{}:{} ({})", coordinates.x, coordinates.y, count))
+                })
+                .collect();
+            warn!(
+                "The propagation doesn't settle after {} cell propagations, handled as a contradiction. Most propagated cells: {}",
+                propagations,
+                oscillating.join(", ")
+            );
+            profile.complete = true;
+            // Emptying the most propagated cell lets the backtracking undo the last guess
+            for (entity, mut wave, _) in query.iter_mut() {
+                wave.dirty = false;
+                if entity == hottest {
+                    wave.tiles.clear();
+                }
+            }
+            return;
+        }
+
+        // Get all its allowed values
+        let propagating_wave = query
+            .get_component::<TileSuperposition>(propagating_entity)
+            .unwrap()
+            .tiles
+            .clone();
+
+        // Find its neighbours, with the path leading to them
+        let neighbours = find_neighbours(propagating_entity, &paths, |entity, orientation| {
+            query
+                .get_component::<Connectivity>(entity)
+                .ok()
+                .and_then(|connectivity| connectivity.connectivity.get(&orientation).copied())
+        });
+
+        // Skip the neighbours already resolved or impossible
+        let neighbours: Vec<(Entity, Vec<Orientation>)> = neighbours
+            .into_iter()
+            .filter(|(neighbour, _)| {
+                let neighbour_wave = query.get_component::<TileSuperposition>(*neighbour);
+                neighbour_wave.unwrap().tiles.len() > 1
+            })
+            .collect();
+        profile.intersections += neighbours.len();
+        let mut constrained_waves = constrain_neighbours(
+            &rules.alloweds,
+            &propagating_wave,
+            &neighbours,
+            |neighbour| {
+                &query
+                    .get_component::<TileSuperposition>(neighbour)
+                    .unwrap()
+                    .tiles
+            },
+            tuning.parallel_propagation,
+        );
+        relax_constraints(
+            &mut constrained_waves,
+            tuning.constraint_relaxation,
+            &mut rng.0,
+        );
+
+        // If impacted, update the tile and mark it as dirty for propagation
+        let mut impossible_cells = Vec::new();
+        for (neighbour, new_allowed_values) in constrained_waves {
+            let mut neighbour_wave = query
+                .get_component_mut::<TileSuperposition>(neighbour)
+                .unwrap();
+            neighbour_wave.tiles = new_allowed_values;
+            if !neighbour_wave.tiles.is_empty() {
+                // Don't propagate impossibility
+                neighbour_wave.dirty = true;
+            } else {
+                impossible_cells.push(neighbour);
+            }
+        }
+
+        // Mark the entity as not dirty
+        let mut wave = query
+            .get_component_mut::<TileSuperposition>(propagating_entity)
+            .unwrap();
+        wave.dirty = false;
+
+        if !tuning.local_reset_on_contradiction {
+            continue;
+        }
+        for impossible_cell in impossible_cells {
+            if local_resets >= MAX_LOCAL_RESETS_PER_FRAME {
+                // Left to the backtracking, or to the next frame
+                break;
+            }
+            local_resets += 1;
+            local_reset(
+                &mut query,
+                &mut frozen,
+                impossible_cell,
+                &rules.possible_tiles(&banned),
+            );
+        }
+    }
+}
+
+/// Reset a cell and its neighbours to every possibilities, and propagate again around them
+///
+/// The surrounding cells are unfrozen, to propagate again even when keeping their tile.
+fn local_reset(
+    query: &mut Query<(Entity, &mut TileSuperposition, &Connectivity)>,
+    frozen: &mut FrozenCells,
+    cell: Entity,
+    possible_tiles: &HashSet<Tile>,
+) {
+    let neighbours = |query: &Query<(Entity, &mut TileSuperposition, &Connectivity)>, cell| {
+        query
+            .get_component::<Connectivity>(cell)
+            .map(|connectivity| connectivity.connectivity.values().copied().collect())
+            .unwrap_or_else(|_| Vec::new())
+    };
+
+    let mut reset_cells: Vec<Entity> = neighbours(query, cell);
+    reset_cells.push(cell);
+    for reset_cell in reset_cells.iter() {
+        let mut wave = query
+            .get_component_mut::<TileSuperposition>(*reset_cell)
+            .unwrap();
+        wave.tiles = possible_tiles.clone();
+    }
+
+    // The surrounding cells constrain the reset ones
+    for reset_cell in reset_cells {
+        for affected in neighbours(query, reset_cell) {
+            let mut wave = query
+                .get_component_mut::<TileSuperposition>(affected)
+                .unwrap();
+            if !wave.tiles.is_empty() {
+                wave.dirty = true;
+            }
+            frozen.0.remove(&affected);
+        }
+    }
+}
+
+/// Index of the guess that resolved the fewest cells, the oldest one on ties
+///
+/// `resolved` holds the number of resolved cells in the current state, then before
+/// each guess from the newest one. The newest guess may still be propagating and is
+/// never picked.
+fn least_progressing_guess(resolved: &[usize]) -> Option<usize> {
+    (1..resolved.len().saturating_sub(1))
+        .rev()
+        .min_by_key(|guess| resolved[*guess].saturating_sub(resolved[guess + 1]))
+}
+
+fn prune_guess_history(
+    mut query: Query<(&TileSuperposition, &mut TileSuperpositionHistory)>,
+    mut history: ResMut<GuessHistory>,
+    tuning: Res<Tuning>,
+) {
+    if history.history.len() <= tuning.backtrack_history_size {
+        return;
+    }
+
+    let mut trimmed = history.history.len() - 1;
+    if tuning.keep_progressing_guesses {
+        let mut resolved = vec![0; history.history.len() + 1];
+        for (wave, tile_history) in query.iter() {
+            let states = std::iter::once(&wave.tiles).chain(tile_history.history.iter());
+            for (state, tiles) in states.enumerate().take(resolved.len()) {
+                if tiles.len() == 1 {
+                    resolved[state] += 1;
+                }
+            }
+        }
+        // Merging a guess with the previous one, backtracking past it goes further back
+        if let Some(guess) = least_progressing_guess(&resolved) {
+            trimmed = guess;
+        }
+    }
+
+    history.history.remove(trimmed);
+
+    for (_, mut tile_history) in query.iter_mut() {
+        tile_history.history.remove(trimmed);
+    }
+}
+
+fn backtrack(
+    mut query: Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
+    mut history: ResMut<GuessHistory>,
+    mut stats: ResMut<CollapseStats>,
+    state: Res<GenerationState>,
+) {
+    if history.history.len() == 0 || state.idle {
+        return;
+    }
+
+    // Find if a contradiction occured
+    let mut contradiction = false;
+    for (wave, _) in query.iter() {
+        if wave.tiles.len() == 0 {
+            contradiction = true;
+            break;
+        }
+    }
+
+    if !contradiction {
+        return;
+    }
+
+    info!("Contradiction detected, backtracking");
+    stats.backtracks += 1;
+
+    // Exclude the bad guess
+    let (entity, bad_guess) = undo_last_guess(&mut query, &mut history).unwrap();
+    let mut wave = query
+        .get_component_mut::<TileSuperposition>(entity)
+        .unwrap();
+    wave.tiles.remove(&bad_guess);
+}
+
+/// Restore the waves as they were before the last guess, returning it
+fn undo_last_guess(
+    query: &mut Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
+    history: &mut GuessHistory,
+) -> Option<(Entity, Tile)> {
+    let guess = history.history.pop_front()?;
+    for (mut wave, mut wave_history) in query.iter_mut() {
+        // TODO: better link between the global and individual history
+        let previous = wave_history.history.pop_front().unwrap();
+        wave.tiles = previous;
+        wave.dirty = false;
+    }
+    Some(guess)
+}
+
+/// Observe the last observed cell again, without the tile it got
+///
+/// This is a manual backtracking step: the new guess is itself backtracked on a
+/// contradiction.
+#[allow(clippy::too_many_arguments)]
+fn reroll_last(
+    mut events: EventReader<RerollLastEvent>,
+    mut query: Query<(&mut TileSuperposition, &mut TileSuperpositionHistory)>,
+    rules: Res<Rules>,
+    mut history: ResMut<GuessHistory>,
+    mut focus: ResMut<FocusCell>,
+    mut rng: ResMut<GenerationRng>,
+    mut state: ResMut<GenerationState>,
+) {
+    if events.iter().count() == 0 {
+        return;
+    }
+    let (entity, guess) = match history.history.front() {
+        Some(guess) => *guess,
+        None => {
+            info!("Nothing observed to reroll");
+            return;
+        }
+    };
+    let previous = &query
+        .get_component::<TileSuperpositionHistory>(entity)
+        .unwrap()
+        .history;
+    let mut candidates = previous.front().cloned().unwrap_or_default();
+    candidates.remove(&guess);
+    if candidates.is_empty() {
+        info!("No other candidate to reroll {}", guess);
+        return;
+    }
+
+    undo_last_guess(&mut query, &mut history);
+    let observed = observe(&candidates, &rules, &mut rng.0);
+    info!("Rerolled {} into {}", guess, observed);
+
+    // Recorded like any observation, the previous guess not being a candidate anymore
+    query
+        .get_component_mut::<TileSuperposition>(entity)
+        .unwrap()
+        .tiles
+        .remove(&guess);
+    history.history.push_front((entity, observed));
+    for (wave, mut wave_history) in query.iter_mut() {
+        wave_history.history.push_front(wave.tiles.clone());
+    }
+    let mut wave = query
+        .get_component_mut::<TileSuperposition>(entity)
+        .unwrap();
+    wave.tiles = HashSet::from([observed]);
+    wave.dirty = true;
+    focus.0 = Some(entity);
+    state.idle = false;
+}
+
+/// Regenerate the map when the generation ends on a contradiction, up to the allowed attempts
+fn auto_retry(
+    mut retry: ResMut<AutoRetry>,
+    state: Res<GenerationState>,
+    rules: Res<Rules>,
+    query: Query<&TileSuperposition>,
+    mut event_writer: EventWriter<RegenerateEvent>,
+) {
+    // Without any rule, the empty cells are no failure to retry
+    if !state.is_changed() || !state.idle || rules.alloweds.is_empty() {
+        return;
+    }
+
+    if !query.iter().any(|wave| wave.tiles.is_empty()) {
+        retry.attempts = 0;
+        return;
+    }
+    if retry.attempts >= retry.max_attempts {
+        if retry.max_attempts > 0 {
+            warn!("Generation failed after {} attempts", retry.attempts + 1);
+        }
+        retry.attempts = 0;
+        return;
+    }
+
+    retry.attempts += 1;
+    info!(
+        "Generation failed, retrying ({}/{})",
+        retry.attempts, retry.max_attempts
+    );
+    event_writer.send(RegenerateEvent {});
+}
+
+/// Record the waves changed during the frame, as a new replay step
+fn record_wave_traces(
+    tuning: Res<Tuning>,
+    mut replay: ResMut<WaveReplay>,
+    mut query: Query<(&TileSuperposition, &mut WaveTrace), Changed<TileSuperposition>>,
+) {
+    if tuning.wave_trace_length == 0 {
+        return;
+    }
+
+    let step = replay.recorded;
+    let mut recorded = false;
+    for (wave, mut trace) in query.iter_mut() {
+        recorded |= trace.record(step, &wave.tiles, tuning.wave_trace_length);
+    }
+    if recorded {
+        replay.recorded += 1;
+    }
+}
+
+fn update_stats(
+    query: Query<&TileSuperposition>,
+    mut stats: ResMut<CollapseStats>,
+    state: Res<GenerationState>,
+    mut was_idle: Local<bool>,
+) {
+    // One last update once idle
+    if state.idle && *was_idle {
+        return;
+    }
+    *was_idle = state.idle;
+
+    let mut total = 0;
+    let mut resolved = 0;
+    let mut contradictions = 0;
+    let mut entropy_sum = 0;
+    for wave in query.iter() {
+        total += 1;
+        entropy_sum += wave.tiles.len();
+        match wave.tiles.len() {
+            0 => contradictions += 1,
+            1 => resolved += 1,
+            _ => (),
+        }
+    }
+
+    stats.total = total;
+    stats.resolved = resolved;
+    stats.contradictions = contradictions;
+    stats.average_entropy = match total {
+        0 => 0.0,
+        _ => entropy_sum as f32 / total as f32,
+    };
+}
+
+/// Count the resolved tiles again when any wave changes or a cell is removed
+fn update_tile_histogram(
+    query: Query<&TileSuperposition>,
+    changed: Query<(), Changed<TileSuperposition>>,
+    removed: RemovedComponents<TileSuperposition>,
+    mut histogram: ResMut<TileHistogram>,
+) {
+    if changed.is_empty() && removed.iter().next().is_none() {
+        return;
+    }
+
+    let counted = TileHistogram::count(query.iter().map(|wave| &wave.tiles));
+    // Keep the change detection for actual changes
+    if *histogram != counted {
+        *histogram = counted;
+    }
+}
+
+/// Log the most visited cells once a propagation pass is over
+fn report_propagation_profile(
+    tuning: Res<Tuning>,
+    profile: Res<PropagationProfile>,
+    query: Query<&Coordinates>,
+) {
+    if !tuning.profile_propagation || !profile.is_changed() || !profile.complete {
+        return;
+    }
+
+    let mut visits: Vec<(&Entity, &usize)> = profile.visits.iter().collect();
+    visits.sort_by(|a, b| b.1.cmp(a.1));
+    let hottest: Vec<String> = visits
+        .iter()
+        .take(5)
+        .filter_map(|(entity, count)| {
+            let coordinates = query.get(**entity).ok()?;
+            Some(format!("{}:{} ({})", coordinates.x, coordinates.y, count))
+        })
+        .collect();
+    info!(
+        "Propagation pass: {} cells, {} visits, {} intersections, hottest: {}",
+        profile.visits.len(),
+        profile.visits.values().sum::<usize>(),
+        profile.intersections,
+        hottest.join(", ")
+    );
+}
+
+/// The generation ended with a cell without any possible tile
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contradiction;
+
+/// Options of the headless generation
+#[derive(Default, Clone, Copy)]
+pub struct SolveOptions {
+    /// Break the entropy ties toward the cells with the most constrained neighbours
+    pub constrained_tie_break: bool,
+    /// Constrain the neighbours in parallel, with the `parallel` feature
+    pub parallel: bool,
+    /// Observe the tiles leaving the most candidates to the other cells
+    pub lookahead: bool,
+    /// Leave the settled cells out of the propagation, for the same map with less work
+    pub freeze_settled: bool,
+}
+
+/// Result of a headless generation
+pub struct Solution {
+    pub map: Result<Vec<Vec<Tile>>, Contradiction>,
+    /// Number of contradictions met on the way
+    pub backtracks: usize,
+}
+
+/// Generate a whole map at once with the default options, see `solve_with`
+#[cfg(test)]
+pub fn solve(
+    rules: &Rules,
+    width: usize,
+    height: usize,
+    seed: u64,
+) -> Result<Vec<Vec<Tile>>, Contradiction> {
+    solve_with(rules, width, height, seed, SolveOptions::default()).map
+}
+
+/// Generate a whole map at once with the given options, outside of any Bevy app
+///
+/// Only the direct neighbours are constrained, and the map doesn't wrap.
+/// The result is indexed by `[x][y]`, like the rule map.
+pub fn solve_with(
+    rules: &Rules,
+    width: usize,
+    height: usize,
+    seed: u64,
+    options: SolveOptions,
+) -> Solution {
+    solve_with_rng(
+        rules,
+        width,
+        height,
+        &mut StdRng::seed_from_u64(seed),
+        options,
+    )
+}
+
+/// Generate a whole map at once, drawing every random choice from `rng`
+///
+/// The same sequence of random numbers always gives the same map, so tests can pass a
+/// mocked generator and expect an exact result.
+pub fn solve_with_rng(
+    rules: &Rules,
+    width: usize,
+    height: usize,
+    rng: &mut impl Rng,
+    options: SolveOptions,
+) -> Solution {
+    let mut backtracks = 0;
+    let map = run_solver(rules, width, height, rng, options, &mut backtracks);
+    Solution { map, backtracks }
+}
+
+fn run_solver(
+    rules: &Rules,
+    width: usize,
+    height: usize,
+    rng: &mut impl Rng,
+    options: SolveOptions,
+    backtracks: &mut usize,
+) -> Result<Vec<Vec<Tile>>, Contradiction> {
+    const MAX_BACKTRACKS: usize = 1000;
+
+    // Cells are stored in a flat list, with their neighbours
+    let index = |x: usize, y: usize| x * height + y;
+    let mut neighbours = vec![Vec::new(); width * height];
+    for x in 0..width {
+        for y in 0..height {
+            let coordinates = Coordinates::new(x as i32, y as i32);
+            for orientation in Orientation::values() {
+                let neighbour = orientation.offset(&coordinates);
+                if (0..width as i32).contains(&neighbour.x)
+                    && (0..height as i32).contains(&neighbour.y)
+                {
+                    neighbours[index(x, y)].push((
+                        index(neighbour.x as usize, neighbour.y as usize),
+                        vec![orientation],
+                    ));
+                }
+            }
+        }
+    }
+
+    let possible_tiles: HashSet<Tile> = rules.alloweds.keys().copied().collect();
+    let mut waves = vec![possible_tiles; width * height];
+    let mut history = Vec::new();
+
+    // Multi-cell tiles only where their whole footprint is on the map
+    let step = |cell: usize, orientation: Orientation| {
+        neighbours[cell]
+            .iter()
+            .find(|(_, path)| path[..] == [orientation])
+            .map(|(neighbour, _)| *neighbour)
+    };
+    let mut restricted = Vec::new();
+    for (cell, wave) in waves.iter_mut().enumerate() {
+        let entropy = wave.len();
+        wave.retain(|tile| footprint_fits(&rules.prototypes, tile, cell, step));
+        if wave.len() != entropy {
+            restricted.push(cell);
+        }
+    }
+    let mut frozen = vec![false; width * height];
+    if !propagate(
+        &mut waves,
+        restricted,
+        &neighbours,
+        &frozen,
+        &rules.alloweds,
+        options.parallel,
+    ) {
+        return Err(Contradiction);
+    }
+
+    loop {
+        // Find the smallest > 1 entropy
+        let mut min_entropy_cells = Vec::new();
+        let mut min_entropy = usize::MAX;
+        for (cell, wave) in waves.iter().enumerate() {
+            let entropy = wave.len();
+            if entropy <= 1 {
+                continue;
+            }
+            if entropy < min_entropy {
+                min_entropy = entropy;
+                min_entropy_cells.clear();
+            }
+            if entropy == min_entropy {
+                min_entropy_cells.push(cell);
+            }
+        }
+
+        if options.constrained_tie_break {
+            min_entropy_cells = most_constrained(&min_entropy_cells, |cell| {
+                neighbours[cell]
+                    .iter()
+                    .map(|(neighbour, _)| waves[*neighbour].len())
+                    .sum()
+            });
+        }
+
+        let cell = match min_entropy_cells.choose(rng) {
+            Some(cell) => *cell,
+            None => break,
+        };
+
+        // Observe the cell, saving the current state
+        let observed = match options.lookahead {
+            true => observe_with_lookahead(&waves[cell], rules, rng, |tile| {
+                let wave = |cell: usize| &waves[cell];
+                let restricted = dry_run(
+                    &rules.alloweds,
+                    cell,
+                    tile,
+                    |cell| neighbours[cell].clone(),
+                    wave,
+                    Some(LOOKAHEAD_MAX_STEPS),
+                    options.parallel,
+                );
+                removed_candidates(cell, &restricted, wave)
+            }),
+            false => observe(&waves[cell], rules, rng),
+        };
+        history.push((waves.clone(), frozen.clone(), cell, observed));
+        waves[cell] = HashSet::from([observed]);
+
+        // Propagate, restoring the previous state and excluding the bad guess on contradiction
+        let mut dirty = vec![cell];
+        while !propagate(
+            &mut waves,
+            dirty,
+            &neighbours,
+            &frozen,
+            &rules.alloweds,
+            options.parallel,
+        ) {
+            let (previous, previous_frozen, cell, bad_guess) =
+                history.pop().ok_or(Contradiction)?;
+            *backtracks += 1;
+            if *backtracks > MAX_BACKTRACKS {
+                return Err(Contradiction);
+            }
+            waves = previous;
+            frozen = previous_frozen;
+            waves[cell].remove(&bad_guess);
+            dirty = vec![cell];
+        }
+
+        if options.freeze_settled {
+            frozen = settled_cells(&waves, &neighbours, &rules.alloweds);
+        }
+    }
+
+    (0..width)
+        .map(|x| {
+            (0..height)
+                .map(|y| match waves[index(x, y)].iter().next() {
+                    Some(tile) => Ok(*tile),
+                    None => Err(Contradiction),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Resolved cells that no propagation can reach, as long as no other change is made
+///
+/// Their neighbours are all resolved and leave them as they are. The propagation skips
+/// them, so they must be found again after any change that is not a propagation.
+fn settled_cells(
+    waves: &[HashSet<Tile>],
+    neighbours: &[Vec<(usize, Vec<Orientation>)>],
+    alloweds: &HashMap<Tile, Allowed>,
+) -> Vec<bool> {
+    settled_cells_by(|cell| &waves[cell], neighbours, alloweds)
+}
+
+/// Same as `settled_cells`, reading the wave of each cell with `wave`
+fn settled_cells_by<'a>(
+    wave: impl Fn(usize) -> &'a HashSet<Tile>,
+    neighbours: &[Vec<(usize, Vec<Orientation>)>],
+    alloweds: &HashMap<Tile, Allowed>,
+) -> Vec<bool> {
+    let resolved = |cell: usize| wave(cell).len() == 1;
+    (0..neighbours.len())
+        .map(|cell| {
+            resolved(cell)
+                && neighbours[cell].iter().all(|(neighbour, _)| {
+                    resolved(*neighbour)
+                        && neighbours[*neighbour]
+                            .iter()
+                            .filter(|(back, _)| *back == cell)
+                            .all(|(_, path)| {
+                                constrain(alloweds, wave(*neighbour), path, wave(cell)).is_none()
+                            })
+                })
+        })
+        .collect()
+}
+
+/// Resolved cells of the generated map that no propagation can reach, see `settled_cells`
+fn settled_entities(
+    query: &Query<(Entity, &mut TileSuperposition, &Connectivity)>,
+    paths: &[Vec<Orientation>],
+    alloweds: &HashMap<Tile, Allowed>,
+) -> HashMap<Entity, Tile> {
+    let cells: Vec<Entity> = query.iter().map(|(entity, _, _)| entity).collect();
+    let neighbours = indexed_neighbours(&cells, paths, |entity, orientation| {
+        let connectivity = query.get_component::<Connectivity>(entity).ok()?;
+        connectivity.connectivity.get(&orientation).copied()
+    });
+    let wave_of = |entity: Entity| {
+        &query
+            .get_component::<TileSuperposition>(entity)
+            .unwrap()
+            .tiles
+    };
+    settled_cells_by(|cell| wave_of(cells[cell]), &neighbours, alloweds)
+        .into_iter()
+        .zip(&cells)
+        .filter(|(settled, _)| *settled)
+        .map(|(_, entity)| (*entity, *wave_of(*entity).iter().next().unwrap()))
+        .collect()
+}
+
+/// Propagate the constraints from the dirty cells, returns false on contradiction
+///
+/// The frozen cells are left out of the propagation.
+fn propagate(
+    waves: &mut [HashSet<Tile>],
+    mut dirty: Vec<usize>,
+    neighbours: &[Vec<(usize, Vec<Orientation>)>],
+    frozen: &[bool],
+    alloweds: &HashMap<Tile, Allowed>,
+    parallel: bool,
+) -> bool {
+    while let Some(cell) = dirty.pop() {
+        if waves[cell].is_empty() {
+            return false;
+        }
+        let cell_neighbours: Cow<[(usize, Vec<Orientation>)]> = match neighbours[cell]
+            .iter()
+            .any(|(neighbour, _)| frozen[*neighbour])
+        {
+            true => neighbours[cell]
+                .iter()
+                .filter(|(neighbour, _)| !frozen[*neighbour])
+                .cloned()
+                .collect(),
+            false => Cow::Borrowed(&neighbours[cell]),
+        };
+        let constrained_waves = constrain_neighbours(
+            alloweds,
+            &waves[cell],
+            &cell_neighbours,
+            |neighbour| &waves[neighbour],
+            parallel,
+        );
+
+        for (neighbour, new_allowed_values) in constrained_waves {
+            if new_allowed_values.is_empty() {
+                return false;
+            }
+            waves[neighbour] = new_allowed_values;
+            dirty.push(neighbour);
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::Events;
+    use std::collections::VecDeque;
+
+    fn make_rules(prototypes: Vec<Prototype>, rule_map: &[&[Option<usize>]]) -> Rules {
+        let rule_tiles: Vec<Vec<OptionalTile>> = rule_map
+            .iter()
+            .map(|line| {
+                line.iter()
+                    .map(|index| OptionalTile {
+                        tile: index.map(|index| Tile::new(index, Orientation::North)),
+                    })
+                    .collect()
+            })
+            .collect();
+        Rules {
+            alloweds: learn_rules(&rule_tiles, &prototypes, false),
+            forbidden: HashSet::new(),
+            declared: HashSet::new(),
+            prototypes,
+            source: RuleSource::ExampleMap,
+        }
+    }
+
+    fn prototype(index: usize, equivalences: Equivalences) -> Prototype {
+        Prototype::new(index, Handle::default(), equivalences, 1.0)
+    }
+
+    /// Check that every pair of neighbours is allowed by the rules, both ways
+    fn assert_valid(map: &[Vec<Tile>], rules: &Rules) {
+        for (x, line) in map.iter().enumerate() {
+            for (y, tile) in line.iter().enumerate() {
+                let coordinates = Coordinates::new(x as i32, y as i32);
+                for orientation in Orientation::values() {
+                    let neighbour = orientation.offset(&coordinates);
+                    let neighbour = map
+                        .get(neighbour.x as usize)
+                        .and_then(|line| line.get(neighbour.y as usize));
+                    if let Some(neighbour) = neighbour {
+                        let allowed = rules.alloweds[tile].get(&[orientation]).unwrap();
+                        assert!(
+                            allowed.contains(neighbour),
+                            "{:?} at {}:{} doesn't allow {:?} to its {:?}",
+                            tile,
+                            x,
+                            y,
+                            neighbour,
+                            orientation
+                        );
+                        let opposite = orientation.rotated(2);
+                        let allowed = rules.alloweds[neighbour].get(&[opposite]).unwrap();
+                        assert!(
+                            allowed.contains(tile),
+                            "{:?} doesn't allow {:?} at {}:{} to its {:?}",
+                            neighbour,
+                            tile,
+                            x,
+                            y,
+                            opposite
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn solve_checkerboard() {
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[
+                &[Some(0), Some(1), Some(0)],
+                &[Some(1), Some(0), Some(1)],
+                &[Some(0), Some(1), Some(0)],
+            ],
+        );
+        let map = solve(&rules, 8, 6, 42).unwrap();
+        assert_eq!(map.len(), 8);
+        assert_eq!(map[0].len(), 6);
+        assert_valid(&map, &rules);
+    }
+
+    #[test]
+    fn solve_oriented_tiles() {
+        // A road (1) bordered by its sides (2) in the grass (0)
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::HalfTurn),
+                prototype(2, Equivalences::None),
+            ],
+            &[
+                &[Some(0), Some(0), Some(0), Some(0)],
+                &[Some(2), Some(2), Some(2), Some(2)],
+                &[Some(1), Some(1), Some(1), Some(1)],
+                &[Some(1), Some(1), Some(1), Some(1)],
+            ],
+        );
+        for seed in 0..5 {
+            let map = solve(&rules, 10, 10, seed).unwrap();
+            assert_valid(&map, &rules);
+        }
+    }
+
+    #[test]
+    fn solve_multi_cell_tiles() {
+        // A bridge (1) over two cells, in the grass (0)
+        let bridge = Prototype {
+            footprint: vec![Coordinates::new(1, 0)],
+            ..prototype(1, Equivalences::HalfTurn)
+        };
+        let rules = make_rules(
+            vec![prototype(0, Equivalences::QuarterTurn), bridge.clone()],
+            &[
+                &[Some(0), Some(0), Some(0)],
+                &[Some(0), Some(1), Some(0)],
+                &[Some(0), Some(0), Some(0)],
+                &[Some(0), Some(0), Some(0)],
+            ],
+        );
+        // Never symmetric, as the footprint rotates with the tile
+        assert!(rules.alloweds.len() == 1 + 2 * 4);
+
+        let mut bridges = 0;
+        for seed in 0..10 {
+            let map = solve(&rules, 6, 6, seed).unwrap();
+            assert_valid(&map, &rules);
+            for (x, line) in map.iter().enumerate() {
+                for (y, tile) in line.iter().enumerate() {
+                    if tile.prototype_index != 1 {
+                        continue;
+                    }
+                    // Each part has the other one where the footprint puts it
+                    let other = 1 - tile.part;
+                    let origin = bridge.part_offset(tile.part, tile.orientation);
+                    let offset = bridge.part_offset(other, tile.orientation);
+                    let other_x = (x as i32 + offset.x - origin.x) as usize;
+                    let other_y = (y as i32 + offset.y - origin.y) as usize;
+                    assert!(
+                        map[other_x][other_y]
+                            == Tile {
+                                part: other,
+                                ..*tile
+                            }
+                    );
+                    bridges += 1;
+                }
+            }
+        }
+        assert!(bridges > 0);
+    }
+
+    #[test]
+    fn observe_mocked_rng() {
+        use rand::rngs::mock::StepRng;
+
+        let mut rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+                prototype(2, Equivalences::QuarterTurn),
+            ],
+            &[&[Some(0), Some(1), Some(2)]],
+        );
+        let tiles: HashSet<Tile> = (0..3)
+            .map(|index| Tile::new(index, Orientation::North))
+            .collect();
+
+        // The lowest random number picks the first tile with a weight
+        assert_eq!(
+            observe(&tiles, &rules, &mut StepRng::new(0, 0)),
+            Tile::new(0, Orientation::North)
+        );
+        rules.prototypes[0].weight = 0.0;
+        assert_eq!(
+            observe(&tiles, &rules, &mut StepRng::new(0, 0)),
+            Tile::new(1, Orientation::North)
+        );
+        // The highest one picks the last
+        assert_eq!(
+            observe(&tiles, &rules, &mut StepRng::new(u64::MAX, 0)),
+            Tile::new(2, Orientation::North)
+        );
+
+        // The whole generation starts from the first cell and the first tile
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[
+                &[Some(0), Some(1), Some(0)],
+                &[Some(1), Some(0), Some(1)],
+                &[Some(0), Some(1), Some(0)],
+            ],
+        );
+        let solution = solve_with_rng(&rules, 4, 4, &mut StepRng::new(0, 0), default());
+        let map = solution.map.unwrap();
+        for (x, line) in map.iter().enumerate() {
+            for (y, tile) in line.iter().enumerate() {
+                let expected = (x + y) % 2;
+                assert_eq!(*tile, Tile::new(expected, Orientation::North));
+            }
+        }
+    }
+
+    #[test]
+    fn reroll_last_observation() {
+        let tiles: Vec<Tile> = (1..4)
+            .map(|index| Tile::new(index, Orientation::North))
+            .collect();
+        let all: HashSet<Tile> = tiles.iter().copied().collect();
+        let mut world = World::new();
+        world.insert_resource(Rules::headless());
+        world.insert_resource(GuessHistory::default());
+        world.insert_resource(FocusCell::default());
+        world.insert_resource(GenerationState { idle: true });
+        world.insert_resource(GenerationRng(StdRng::seed_from_u64(1)));
+        world.insert_resource(Events::<RerollLastEvent>::default());
+        let cell = |world: &mut World, tiles: &HashSet<Tile>| {
+            world
+                .spawn()
+                .insert(TileSuperposition {
+                    tiles: tiles.clone(),
+                    dirty: false,
+                })
+                .insert(TileSuperpositionHistory {
+                    history: VecDeque::from([all.clone()]),
+                })
+                .id()
+        };
+        // The first cell was observed into the first tile
+        let observed = cell(&mut world, &HashSet::from([tiles[0]]));
+        let other = cell(&mut world, &all);
+        world
+            .resource_mut::<GuessHistory>()
+            .history
+            .push_front((observed, tiles[0]));
+        let mut stage = SystemStage::single(reroll_last);
+
+        world
+            .resource_mut::<Events<RerollLastEvent>>()
+            .send(RerollLastEvent {});
+        stage.run(&mut world);
+        let wave = world.get::<TileSuperposition>(observed).unwrap();
+        let rerolled = *wave.tiles.iter().next().unwrap();
+        assert!(wave.tiles.len() == 1 && wave.dirty);
+        assert!(rerolled != tiles[0]);
+        assert!(world.resource::<GuessHistory>().history == [(observed, rerolled)]);
+        assert!(world.resource::<FocusCell>().0 == Some(observed));
+        assert!(!world.resource::<GenerationState>().idle);
+        // Backtracking this guess wouldn't try the first tile again
+        let previous = &world
+            .get::<TileSuperpositionHistory>(observed)
+            .unwrap()
+            .history;
+        assert!(previous.len() == 1 && !previous[0].contains(&tiles[0]));
+        assert!(world.get::<TileSuperposition>(other).unwrap().tiles == all);
+
+        // The last candidate can't be rerolled
+        world
+            .resource_mut::<Events<RerollLastEvent>>()
+            .send(RerollLastEvent {});
+        stage.run(&mut world);
+        world
+            .resource_mut::<Events<RerollLastEvent>>()
+            .send(RerollLastEvent {});
+        stage.run(&mut world);
+        let history = world.resource::<GuessHistory>().history.clone();
+        world
+            .resource_mut::<Events<RerollLastEvent>>()
+            .send(RerollLastEvent {});
+        stage.run(&mut world);
+        assert!(world.resource::<GuessHistory>().history == history);
+        assert!(
+            world
+                .get::<TileSuperposition>(observed)
+                .unwrap()
+                .tiles
+                .len()
+                == 1
+        );
+    }
+
+    #[test]
+    fn empty_rules_stay_idle() {
+        let mut world = World::new();
+        let mut rules = Rules::headless();
+        rules.alloweds.clear();
+        world.insert_resource(rules);
+        world.insert_resource(Tuning::default());
+        world.insert_resource(BannedTiles::default());
+        world.insert_resource(PropagationProfile::default());
+        world.insert_resource(GenerationState::default());
+        world.insert_resource(GuessHistory::default());
+        world.insert_resource(GenerationRng(StdRng::seed_from_u64(1)));
+        world.insert_resource(Events::<RulesNeedUpdateEvent>::default());
+        world.insert_resource(FrozenCells::default());
+        let cell = world
+            .spawn()
+            .insert(TileSuperposition::default())
+            .insert(Connectivity::default())
+            .id();
+        let mut stage = SystemStage::single(collapse);
+
+        stage.run(&mut world);
+        assert!(world.resource::<GenerationState>().idle);
+        assert!(world
+            .get::<TileSuperposition>(cell)
+            .unwrap()
+            .tiles
+            .is_empty());
+        assert!(world.resource::<GuessHistory>().history.is_empty());
+    }
+
+    #[test]
+    fn propagation_cap() {
+        // No tile accepts any neighbour: every propagation empties the other cell,
+        // whose local reset marks both cells dirty again. The guess made on the west
+        // cell is backtracked once the pass is over the cap.
+        let tiles = [
+            Tile::new(0, Orientation::North),
+            Tile::new(1, Orientation::North),
+        ];
+        let mut rules = Rules::headless();
+        rules.alloweds = tiles
+            .iter()
+            .map(|tile| (*tile, Allowed::default()))
+            .collect();
+        let mut world = World::new();
+        world.insert_resource(rules);
+        world.insert_resource(Tuning {
+            local_reset_on_contradiction: true,
+            propagation_cap: 8,
+            ..default()
+        });
+        world.insert_resource(BannedTiles::default());
+        world.insert_resource(PropagationProfile::default());
+        world.insert_resource(GenerationState::default());
+        world.insert_resource(GenerationRng(StdRng::seed_from_u64(1)));
+        world.insert_resource(Events::<RulesNeedUpdateEvent>::default());
+        world.insert_resource(CollapseStats::default());
+        world.insert_resource(FrozenCells::default());
+        let all: HashSet<Tile> = tiles.iter().copied().collect();
+        let cell = |world: &mut World, x: i32| {
+            world
+                .spawn()
+                .insert(TileSuperposition {
+                    tiles: all.clone(),
+                    dirty: true,
+                })
+                .insert(TileSuperpositionHistory {
+                    history: VecDeque::from([all.clone()]),
+                })
+                .insert(Coordinates::new(x, 0))
+                .id()
+        };
+        let west = cell(&mut world, 0);
+        let east = cell(&mut world, 1);
+        world.entity_mut(west).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::East, east)]),
+        });
+        world.entity_mut(east).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::West, west)]),
+        });
+        world.insert_resource(GuessHistory {
+            history: VecDeque::from([(west, tiles[0])]),
+        });
+        let mut stage = SystemStage::single_threaded()
+            .with_system(collapse)
+            .with_system(backtrack.after(collapse));
+
+        for _ in 0..100 {
+            stage.run(&mut world);
+        }
+        // Stopped on the first propagation over the cap
+        let visits = &world.resource::<PropagationProfile>().visits;
+        assert!(visits.values().sum::<usize>() == 9);
+        assert!(world.resource::<CollapseStats>().backtracks == 1);
+        assert!(world.resource::<GuessHistory>().history.is_empty());
+        assert!(world.get::<TileSuperposition>(west).unwrap().tiles == HashSet::from([tiles[1]]));
+        for cell in [west, east] {
+            assert!(!world.get::<TileSuperposition>(cell).unwrap().dirty);
+        }
+
+        // The next propagation pass counts from zero, whatever started it
+        let allowed = Allowed {
+            allowed: HashMap::from([(Orientation::East, all.clone()), (Orientation::West, all)]),
+            ..default()
+        };
+        world.resource_mut::<Rules>().alloweds =
+            tiles.iter().map(|tile| (*tile, allowed.clone())).collect();
+        world.get_mut::<TileSuperposition>(west).unwrap().dirty = true;
+        world.resource_mut::<GenerationState>().idle = false;
+        stage.run(&mut world);
+        let profile = world.resource::<PropagationProfile>();
+        assert!(profile.visits == HashMap::from([(west, 1)]) && profile.complete);
+    }
+
+    /// World running the observations, without any cell
+    fn observing_world(rules: Rules) -> World {
+        let mut world = World::new();
+        world.insert_resource(rules);
+        world.insert_resource(Tuning::default());
+        world.insert_resource(GuessHistory::default());
+        world.insert_resource(FocusCell::default());
+        world.insert_resource(PropagationProfile::default());
+        world.insert_resource(GenerationRng(StdRng::seed_from_u64(1)));
+        world.insert_resource(GenerationState::default());
+        world.insert_resource(TileBudget::default());
+        world
+    }
+
+    fn observed_cell(world: &mut World, x: i32, tiles: &HashSet<Tile>) -> Entity {
+        world
+            .spawn()
+            .insert(TileSuperposition {
+                tiles: tiles.clone(),
+                dirty: false,
+            })
+            .insert(Coordinates::new(x, 0))
+            .insert(TileSuperpositionHistory::default())
+            .insert(Connectivity::default())
+            .id()
+    }
+
+    #[test]
+    fn border_along_holes() {
+        let tiles: HashSet<Tile> = [0, 1]
+            .iter()
+            .map(|index| Tile::new(*index, Orientation::North))
+            .collect();
+        let mut world = observing_world(checkerboard_rules());
+        world.insert_resource(Tuning {
+            border_prototype: Some(0),
+            ..default()
+        });
+        // 4x4 map with a hole next to the edge at 1:0
+        let mut entities = HashMap::new();
+        for x in 0..4 {
+            for y in 0..4 {
+                if (x, y) != (1, 0) {
+                    let entity = observed_cell(&mut world, x, &tiles);
+                    world.entity_mut(entity).insert(Coordinates::new(x, y));
+                    entities.insert(Coordinates::new(x, y), entity);
+                }
+            }
+        }
+        for (coordinates, entity) in entities.iter() {
+            let connectivity = Connectivity::new(&entities, coordinates, 4, 4, false);
+            world.entity_mut(*entity).insert(connectivity);
+        }
+        let mut stage = SystemStage::single(observe_system);
+
+        stage.run(&mut world);
+        let wave = |coordinates| {
+            world
+                .get::<TileSuperposition>(entities[&coordinates])
+                .unwrap()
+                .tiles
+                .clone()
+        };
+        let border = HashSet::from([Tile::new(0, Orientation::North)]);
+        assert!(wave(Coordinates::new(0, 0)) == border);
+        assert!(wave(Coordinates::new(1, 1)) == border);
+        assert!(wave(Coordinates::new(2, 2)) == tiles);
+    }
+
+    #[test]
+    fn tile_budget() {
+        let grass = Tile::new(0, Orientation::North);
+        let bridge = Tile::new(1, Orientation::North);
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[&[Some(0), Some(1)], &[Some(1), Some(0)]],
+        );
+        let mut world = observing_world(rules);
+        world.insert_resource(TileBudget(HashMap::from([(1, 1)])));
+        let both = HashSet::from([grass, bridge]);
+        let placed = observed_cell(&mut world, 0, &HashSet::from([bridge]));
+        let undecided = observed_cell(&mut world, 1, &both);
+        let mut stage = SystemStage::single(observe_system);
+
+        // The only bridge is placed, the other cells can't take one anymore
+        stage.run(&mut world);
+        let wave = world.get::<TileSuperposition>(undecided).unwrap();
+        assert!(wave.tiles == HashSet::from([grass]) && wave.dirty);
+        assert!(world.get::<TileSuperposition>(placed).unwrap().tiles.len() == 1);
+        assert!(world.resource::<GuessHistory>().history.is_empty());
+
+        // One bridge too many is a contradiction, only on the cell resolved by the last pass
+        for (cell, tiles) in [(placed, HashSet::from([bridge])), (undecided, both)] {
+            world
+                .get_mut::<TileSuperpositionHistory>(cell)
+                .unwrap()
+                .history
+                .push_front(tiles);
+        }
+        let mut wave = world.get_mut::<TileSuperposition>(undecided).unwrap();
+        wave.tiles = HashSet::from([bridge]);
+        wave.dirty = false;
+        stage.run(&mut world);
+        assert!(world.get::<TileSuperposition>(placed).unwrap().tiles == HashSet::from([bridge]));
+        assert!(world
+            .get::<TileSuperposition>(undecided)
+            .unwrap()
+            .tiles
+            .is_empty());
+    }
+
+    #[test]
+    fn orientation_lock() {
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::None),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[&[Some(0), Some(1)], &[Some(1), Some(0)]],
+        );
+        let all = rules.possible_tiles(&BannedTiles::default());
+        let mut world = observing_world(rules);
+        let locked = observed_cell(&mut world, 0, &all);
+        let free = observed_cell(&mut world, 1, &all);
+        world
+            .entity_mut(locked)
+            .insert(OrientationLock(Orientation::East));
+        let mut stage = SystemStage::single(observe_system);
+
+        // Restricted before any observation, the symmetric tile facing every way
+        stage.run(&mut world);
+        let wave = world.get::<TileSuperposition>(locked).unwrap();
+        let east = HashSet::from([
+            Tile::new(0, Orientation::East),
+            Tile::new(1, Orientation::North),
+        ]);
+        assert!(wave.tiles == east && wave.dirty);
+        assert!(world.get::<TileSuperposition>(free).unwrap().tiles == all);
+        assert!(world.resource::<GuessHistory>().history.is_empty());
+
+        // Held after a reset
+        world.get_mut::<TileSuperposition>(locked).unwrap().tiles = all.clone();
+        world.get_mut::<TileSuperposition>(locked).unwrap().dirty = false;
+        stage.run(&mut world);
+        assert!(world.get::<TileSuperposition>(locked).unwrap().tiles == east);
+    }
+
+    #[test]
+    fn relaxed_constraints() {
+        let constrained = || -> Vec<(usize, HashSet<Tile>)> {
+            (0..100).map(|cell| (cell, HashSet::new())).collect()
+        };
+        let relaxed = |relaxation: f32, seed: u64| {
+            let mut relaxed = constrained();
+            relax_constraints(&mut relaxed, relaxation, &mut StdRng::seed_from_u64(seed));
+            relaxed
+                .into_iter()
+                .map(|(cell, _)| cell)
+                .collect::<Vec<usize>>()
+        };
+
+        assert!(relaxed(0.0, 1).len() == 100);
+        assert!(relaxed(1.0, 1).is_empty());
+        let half = relaxed(0.5, 1);
+        assert!(half.len() > 20 && half.len() < 80);
+        assert!(half == relaxed(0.5, 1));
+        assert!(half != relaxed(0.5, 2));
+    }
+
+    #[test]
+    fn finalize_restores_on_contradiction() {
+        // No tile accepts any neighbour, the first pick empties the other cell
+        let tiles: HashSet<Tile> = [0, 1]
+            .iter()
+            .map(|index| Tile::new(*index, Orientation::North))
+            .collect();
+        let mut rules = Rules::headless();
+        rules.alloweds = tiles
+            .iter()
+            .map(|tile| (*tile, Allowed::default()))
+            .collect();
+        let mut world = World::new();
+        world.insert_resource(rules);
+        world.insert_resource(Tuning::default());
+        world.insert_resource(GenerationState { idle: true });
+        world.insert_resource(Events::<FinalizeEvent>::default());
+        world.insert_resource(TileBudget::default());
+        let cell = |world: &mut World, x: i32| {
+            world
+                .spawn()
+                .insert(TileSuperposition {
+                    tiles: tiles.clone(),
+                    dirty: false,
+                })
+                .insert(Coordinates::new(x, 0))
+                .id()
+        };
+        let west = cell(&mut world, 0);
+        let east = cell(&mut world, 1);
+        world.entity_mut(west).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::East, east)]),
+        });
+        world.entity_mut(east).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::West, west)]),
+        });
+        let mut stage = SystemStage::single(finalize);
+
+        world
+            .resource_mut::<Events<FinalizeEvent>>()
+            .send(FinalizeEvent {});
+        stage.run(&mut world);
+        for cell in [west, east] {
+            assert!(world.get::<TileSuperposition>(cell).unwrap().tiles == tiles);
+        }
+        assert!(world.resource::<GenerationState>().idle);
+    }
+
+    #[test]
+    fn finalize_restrictions() {
+        // Any tile next to any other, on a line of 4 cells
+        let tiles = [
+            Tile::new(0, Orientation::North),
+            Tile::new(1, Orientation::North),
+        ];
+        let all: HashSet<Tile> = tiles.iter().copied().collect();
+        let allowed = Allowed {
+            allowed: Orientation::values()
+                .iter()
+                .map(|orientation| (*orientation, all.clone()))
+                .collect(),
+            ..default()
+        };
+        let mut rules = Rules::headless();
+        rules.alloweds = tiles.iter().map(|tile| (*tile, allowed.clone())).collect();
+        let finalized = |tuning: Tuning, budget: TileBudget| {
+            let mut world = World::new();
+            world.insert_resource(rules.clone());
+            world.insert_resource(tuning);
+            world.insert_resource(budget);
+            world.insert_resource(GenerationState { idle: true });
+            world.insert_resource(Events::<FinalizeEvent>::default());
+            let mut entities = HashMap::new();
+            for x in 0..4 {
+                let entity = world
+                    .spawn()
+                    .insert(TileSuperposition {
+                        tiles: all.clone(),
+                        dirty: false,
+                    })
+                    .insert(Coordinates::new(x, 0))
+                    .id();
+                entities.insert(Coordinates::new(x, 0), entity);
+            }
+            for (coordinates, entity) in entities.iter() {
+                let connectivity = Connectivity::new(&entities, coordinates, 4, 1, false);
+                world.entity_mut(*entity).insert(connectivity);
+            }
+            world
+                .resource_mut::<Events<FinalizeEvent>>()
+                .send(FinalizeEvent {});
+            SystemStage::single(finalize).run(&mut world);
+            (0..4)
+                .map(|x| {
+                    let entity = entities[&Coordinates::new(x, 0)];
+                    world
+                        .get::<TileSuperposition>(entity)
+                        .unwrap()
+                        .tiles
+                        .clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        let only = |tile: Tile| HashSet::from([tile]);
+
+        // The lowest index tile everywhere, unless spent or off the border
+        let unrestricted = finalized(Tuning::default(), TileBudget::default());
+        assert!(unrestricted == vec![only(tiles[0]); 4]);
+        let budgeted = finalized(Tuning::default(), TileBudget(HashMap::from([(0, 1)])));
+        assert!(budgeted[0] == only(tiles[0]));
+        assert!(budgeted[1..] == vec![only(tiles[1]); 3]);
+        let border = Tuning {
+            border_prototype: Some(1),
+            ..default()
+        };
+        assert!(finalized(border.clone(), TileBudget::default()) == vec![only(tiles[1]); 4]);
+
+        // Left as it was when the border is over budget
+        let over_budget = finalized(border, TileBudget(HashMap::from([(1, 2)])));
+        assert!(over_budget == vec![all; 4]);
+    }
+
+    #[test]
+    fn solve_is_reproducible() {
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::None),
+            ],
+            &[
+                &[Some(0), Some(0), Some(1)],
+                &[Some(0), Some(1), Some(0)],
+                &[Some(1), Some(0), Some(0)],
+            ],
+        );
+        assert_eq!(solve(&rules, 6, 6, 7), solve(&rules, 6, 6, 7));
+    }
+
+    #[test]
+    fn solve_default_rules() {
+        let mut rules = Rules::headless();
+        rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &rules.prototypes,
+            false,
+        );
+        for seed in 0..8 {
+            let map = solve(&rules, 16, 16, seed).unwrap();
+            assert_valid(&map, &rules);
+        }
+    }
+
+    #[test]
+    fn resized_map_keeps_the_seed() {
+        let mut rules = Rules::headless();
+        rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &rules.prototypes,
+            false,
+        );
+        let mut rng = GenerationRng::default();
+        let mut info = GenerationInfo {
+            seed: 5,
+            attempt: 0,
+        };
+        info.start(&mut rng, false);
+        // A first map drawing from the generator, before resizing
+        let _ = solve_with_rng(&rules, 8, 8, &mut rng.0, default());
+
+        info.restart(&mut rng);
+        let resized = solve_with_rng(&rules, 12, 6, &mut rng.0, default()).map;
+        info.restart(&mut rng);
+        assert_eq!(
+            resized,
+            solve_with_rng(&rules, 12, 6, &mut rng.0, default()).map
+        );
+        // Like a launch with the same seed at that size
+        assert_eq!(resized, solve_with(&rules, 12, 6, 5, default()).map);
+    }
+
+    #[test]
+    fn constrained_tie_break_reduces_backtracks() {
+        // The default rules hardly ever contradict, up to 32x32 maps, leaving nothing to
+        // reduce. The tie-break must still give valid maps with them.
+        let mut default_rules = Rules::headless();
+        default_rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &default_rules.prototypes,
+            false,
+        );
+        let options = SolveOptions {
+            constrained_tie_break: true,
+            ..default()
+        };
+        for seed in 0..4 {
+            let map = solve_with(&default_rules, 16, 16, seed, options).map;
+            assert_valid(&map.unwrap(), &default_rules);
+        }
+
+        let rules = contradicting_rules();
+        let backtracks = |constrained_tie_break| {
+            let options = SolveOptions {
+                constrained_tie_break,
+                ..default()
+            };
+            (0..20)
+                .map(|seed| {
+                    let solution = solve_with(&rules, 8, 8, seed, options);
+                    assert_valid(&solution.map.unwrap(), &rules);
+                    solution.backtracks
+                })
+                .sum::<usize>()
+        };
+        let (uniform, constrained) = (backtracks(false), backtracks(true));
+        assert!(
+            constrained < uniform,
+            "{} backtracks with the tie-break, {} without",
+            constrained,
+            uniform
+        );
+    }
+
+    /// Small rule set prone to contradictions
+    fn contradicting_rules() -> Rules {
+        make_rules(
+            vec![
+                prototype(0, Equivalences::None),
+                prototype(1, Equivalences::HalfTurn),
+                prototype(2, Equivalences::QuarterTurn),
+                prototype(3, Equivalences::QuarterTurn),
+            ],
+            &[
+                &[Some(3), Some(2), Some(1), Some(2)],
+                &[Some(3), Some(1), Some(2), Some(2)],
+                &[Some(0), Some(3), Some(2), Some(3)],
+                &[Some(0), Some(1), Some(0), Some(1)],
+            ],
+        )
+    }
+
+    #[test]
+    fn lookahead_reduces_backtracks() {
+        // The default rules hardly ever contradict, up to 32x32 maps, leaving nothing to
+        // reduce. The lookahead must still give valid maps with them.
+        let mut default_rules = Rules::headless();
+        default_rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &default_rules.prototypes,
+            false,
+        );
+        let options = SolveOptions {
+            lookahead: true,
+            ..default()
+        };
+        for seed in 0..4 {
+            let map = solve_with(&default_rules, 8, 8, seed, options).map;
+            assert_valid(&map.unwrap(), &default_rules);
+        }
+
+        let rules = contradicting_rules();
+        let backtracks = |lookahead| {
+            let options = SolveOptions {
+                lookahead,
+                ..default()
+            };
+            (0..20)
+                .map(|seed| {
+                    let solution = solve_with(&rules, 8, 8, seed, options);
+                    assert_valid(&solution.map.unwrap(), &rules);
+                    solution.backtracks
+                })
+                .sum::<usize>()
+        };
+        let (uniform, lookahead) = (backtracks(false), backtracks(true));
+        assert!(
+            lookahead < uniform,
+            "{} backtracks with the lookahead, {} without",
+            lookahead,
+            uniform
+        );
+    }
+
+    /// Without the feature, both would run the serial propagation
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_propagation_matches_serial() {
+        let mut rules = Rules::headless();
+        rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &rules.prototypes,
+            false,
+        );
+        for seed in 0..4 {
+            let solve_map = |parallel| {
+                let options = SolveOptions {
+                    parallel,
+                    ..default()
+                };
+                solve_with(&rules, 16, 16, seed, options).map
+            };
+            assert_eq!(solve_map(true), solve_map(false), "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn freezing_preserves_output() {
+        let mut rules = Rules::headless();
+        rules.alloweds = learn_rules(
+            &MapRule::default_map().unwrap().map,
+            &rules.prototypes,
+            false,
+        );
+        let contradicting = contradicting_rules();
+        for (rules, size) in [(&rules, 16), (&contradicting, 8)] {
+            for seed in 0..4 {
+                let solve_map = |freeze_settled| {
+                    let options = SolveOptions {
+                        freeze_settled,
+                        ..default()
+                    };
+                    let solution = solve_with(rules, size, size, seed, options);
+                    (solution.map, solution.backtracks)
+                };
+                assert_eq!(solve_map(true), solve_map(false), "seed {}", seed);
+
+                let mut frozen = generate(rules, 8, seed, true);
+                let mut unfrozen = generate(rules, 8, seed, false);
+                assert!(!frozen.resource::<FrozenCells>().0.is_empty());
+                let backtracks = |world: &World| world.resource::<CollapseStats>().backtracks;
+                assert_eq!(backtracks(&frozen), backtracks(&unfrozen), "seed {}", seed);
+                assert!(generated_map(&mut frozen) == generated_map(&mut unfrozen));
+            }
+        }
+    }
+
+    /// Run the generation systems over a square map until they idle
+    fn generate(rules: &Rules, size: i32, seed: u64, freeze_settled: bool) -> World {
+        let possible_tiles = rules.possible_tiles(&BannedTiles::default());
+        let mut world = observing_world(rules.clone());
+        world.insert_resource(Tuning {
+            freeze_settled,
+            ..default()
+        });
+        world.insert_resource(GenerationRng(StdRng::seed_from_u64(seed)));
+        world.insert_resource(BannedTiles::default());
+        world.insert_resource(FrozenCells::default());
+        world.insert_resource(CollapseStats::default());
+        world.insert_resource(Events::<RulesNeedUpdateEvent>::default());
+        let mut entities = HashMap::new();
+        for x in 0..size {
+            for y in 0..size {
+                let entity = observed_cell(&mut world, x, &possible_tiles);
+                world.entity_mut(entity).insert(Coordinates::new(x, y));
+                entities.insert(Coordinates::new(x, y), entity);
+            }
+        }
+        for (coordinates, entity) in entities.iter() {
+            let size = size as usize;
+            let connectivity = Connectivity::new(&entities, coordinates, size, size, false);
+            world.entity_mut(*entity).insert(connectivity);
+        }
+        let mut stage = SystemStage::single_threaded()
+            .with_system(observe_system)
+            .with_system(collapse.after(observe_system))
+            .with_system(backtrack.after(collapse));
+
+        for _ in 0..10_000 {
+            if world.resource::<GenerationState>().idle {
+                break;
+            }
+            stage.run(&mut world);
+        }
+        world
+    }
+
+    fn generated_map(world: &mut World) -> HashMap<Coordinates, HashSet<Tile>> {
+        world
+            .query::<(&Coordinates, &TileSuperposition)>()
+            .iter(world)
+            .map(|(coordinates, wave)| (*coordinates, wave.tiles.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn frozen_cells_leave_the_frontier() {
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[&[Some(0), Some(1), Some(0)]],
+        );
+        let tile = |index| Tile::new(index, Orientation::North);
+        let mut world = observing_world(rules);
+        world.insert_resource(BannedTiles::default());
+        world.insert_resource(Events::<RulesNeedUpdateEvent>::default());
+        let west = observed_cell(&mut world, 0, &HashSet::from([tile(0)]));
+        let east = observed_cell(&mut world, 1, &HashSet::from([tile(1)]));
+        world.entity_mut(west).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::East, east)]),
+        });
+        world.entity_mut(east).insert(Connectivity {
+            connectivity: HashMap::from([(Orientation::West, west)]),
+        });
+        let mut stage = SystemStage::single(collapse);
+
+        // A settled cell made dirty again, by setting the tile it already has
+        for (frozen, visited) in [(tile(0), false), (tile(1), true)] {
+            world.insert_resource(FrozenCells(HashMap::from([(west, frozen)])));
+            world.insert_resource(PropagationProfile::default());
+            world.get_mut::<TileSuperposition>(west).unwrap().dirty = true;
+            world.resource_mut::<GenerationState>().idle = false;
+            stage.run(&mut world);
+            assert!(!world.get::<TileSuperposition>(west).unwrap().dirty);
+            let profile = world.resource::<PropagationProfile>();
+            assert!(profile.visits.contains_key(&west) == visited);
+        }
+    }
+
+    #[test]
+    fn frozen_cells_see_changes() {
+        // Alternating tiles on a line of 4 cells
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[&[Some(0), Some(1), Some(0)]],
+        );
+        let tile = |index| Tile::new(index, Orientation::North);
+        let neighbours: Vec<Vec<(usize, Vec<Orientation>)>> = (0..4)
+            .map(|cell: usize| {
+                let mut neighbours = Vec::new();
+                if cell > 0 {
+                    neighbours.push((cell - 1, vec![Orientation::South]));
+                }
+                if cell < 3 {
+                    neighbours.push((cell + 1, vec![Orientation::North]));
+                }
+                neighbours
+            })
+            .collect();
+        let mut waves = vec![
+            HashSet::from([tile(0)]),
+            HashSet::from([tile(1)]),
+            HashSet::from([tile(0)]),
+            HashSet::from([tile(0), tile(1)]),
+        ];
+        let frozen = settled_cells(&waves, &neighbours, &rules.alloweds);
+        assert_eq!(frozen, vec![true, true, false, false]);
+
+        // A change next to a frozen cell unfreezes it, and the propagation reaches it
+        waves[0] = HashSet::from([tile(1)]);
+        let frozen = settled_cells(&waves, &neighbours, &rules.alloweds);
+        assert_eq!(frozen, vec![false, false, false, false]);
+        assert!(!propagate(
+            &mut waves,
+            vec![0],
+            &neighbours,
+            &frozen,
+            &rules.alloweds,
+            false
+        ));
+    }
+
+    #[test]
+    fn symmetric_learned_rules() {
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::None),
+                prototype(2, Equivalences::HalfTurn),
+            ],
+            &[
+                &[Some(0), Some(1), Some(2)],
+                &[Some(1), Some(2), Some(0)],
+                &[Some(2), Some(0), Some(1)],
+            ],
+        );
+        assert_eq!(verify_symmetry(&rules.alloweds), Vec::new());
+    }
+
+    #[test]
+    fn north_on_screen() {
+        let prototypes = vec![
+            prototype(0, Equivalences::None),
+            prototype(1, Equivalences::None),
+        ];
+        let center = Coordinates::new(1, 1);
+        let north_of_center = |mapping: &OrientationMapping, towards: Vec3| {
+            // Paint a tile next to another one, in the given world direction
+            let mut rule_tiles = vec![vec![OptionalTile::default(); 3]; 3];
+            rule_tiles[1][1] = OptionalTile::new(Some(Tile::new(0, Orientation::North)));
+            let target = mapping.translation(&center, 3, 3) + towards;
+            for (x, line) in rule_tiles.iter_mut().enumerate() {
+                for (y, tile) in line.iter_mut().enumerate() {
+                    let coordinates = Coordinates::new(x as i32, y as i32);
+                    if mapping.translation(&coordinates, 3, 3) == target {
+                        *tile = OptionalTile::new(Some(Tile::new(1, Orientation::North)));
+                    }
+                }
+            }
+            let alloweds = learn_rules(&rule_tiles, &prototypes, false);
+            alloweds[&Tile::new(0, Orientation::North)]
+                .allowed
+                .get(&Orientation::North)
+                .is_some_and(|allowed| allowed.contains(&Tile::new(1, Orientation::North)))
+        };
+
+        for reversed in [false, true] {
+            let towards_camera = OrientationMapping {
+                reversed,
+                ..Default::default()
+            };
+            assert!(north_of_center(&towards_camera, Vec3::Z));
+            assert!(!north_of_center(&towards_camera, -Vec3::Z));
+            let away = OrientationMapping {
+                reversed,
+                north: AxisMapping::NegativeZ,
+                ..Default::default()
+            };
+            assert!(north_of_center(&away, -Vec3::Z));
+            assert!(!north_of_center(&away, Vec3::Z));
+        }
+    }
+
+    #[test]
+    fn adjacency_list() {
+        let mut prototypes = vec![
+            prototype(0, Equivalences::QuarterTurn),
+            prototype(1, Equivalences::None),
+        ];
+        prototypes[0].name = "grass".to_string();
+        prototypes[1].name = "path_end".to_string();
+        let grass = Tile::new(0, Orientation::North);
+        let path_end = Tile::new(1, Orientation::North);
+
+        let text = "# Grass everywhere\n\ngrass N grass\ngrass E grass\n  path_end@North S grass\n";
+        let adjacencies = parse_adjacency_list(text, &prototypes).unwrap();
+        let allowed = |tile: Tile, side| adjacencies[&tile].allowed[&side].clone();
+        assert!(allowed(path_end, Orientation::South) == HashSet::from([grass]));
+        // Allowed back
+        assert!(allowed(grass, Orientation::North) == HashSet::from([grass, path_end]));
+        assert!(allowed(grass, Orientation::West) == HashSet::from([grass]));
+
+        // Turned into rules like a learned rule map
+        let rules = Rules {
+            alloweds: HashMap::new(),
+            forbidden: HashSet::new(),
+            declared: HashSet::new(),
+            prototypes,
+            source: RuleSource::AdjacencyList(adjacencies),
+        };
+        let alloweds = learn_source(&rules, &[], false);
+        let east_end = Tile::new(1, Orientation::East);
+        assert!(alloweds[&east_end].allowed[&Orientation::West] == HashSet::from([grass]));
+        assert!(alloweds[&grass].allowed[&Orientation::East].contains(&east_end));
+
+        // Referring to a missing tile
+        let errors = [
+            ("grass N rock", "line 1: unknown tile rock"),
+            (
+                "grass\ngrass N",
+                "line 1: expected a tile, a side and a neighbour",
+            ),
+            ("grass N grass\ngrass up grass", "line 2: unknown side up"),
+            ("grass@Up N grass", "line 1: unknown orientation Up"),
+        ];
+        for (text, error) in errors {
+            assert_eq!(
+                parse_adjacency_list(text, &rules.prototypes).unwrap_err(),
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn rule_provenance() {
+        let prototypes = vec![
+            prototype(0, Equivalences::QuarterTurn),
+            prototype(1, Equivalences::None),
+        ];
+        let tile = |index: Option<usize>| OptionalTile {
+            tile: index.map(|index| Tile::new(index, Orientation::North)),
+        };
+        let rule_tiles = vec![
+            vec![tile(Some(0)), tile(Some(1))],
+            vec![tile(Some(0)), tile(None)],
+        ];
+        let provenance = learn_provenance(&rule_tiles, &prototypes, false);
+
+        // Every learned adjacency has a provenance, and only those
+        let alloweds = learn_rules(&rule_tiles, &prototypes, false);
+        let learned: HashSet<(Tile, Orientation, Tile)> = alloweds
+            .iter()
+            .flat_map(|(tile, allowed)| {
+                allowed
+                    .allowed
+                    .iter()
+                    .flat_map(move |(orientation, neighbours)| {
+                        neighbours
+                            .iter()
+                            .map(move |neighbour| (*tile, *orientation, *neighbour))
+                    })
+            })
+            .collect();
+        assert!(provenance.keys().copied().collect::<HashSet<_>>() == learned);
+
+        let grass = Tile::new(0, Orientation::North);
+        let north = Tile::new(1, Orientation::North);
+        let taught_by = |adjacency| {
+            let mut coordinates = provenance[&adjacency].clone();
+            coordinates.sort_by_key(|coordinates| (coordinates.x, coordinates.y));
+            coordinates
+        };
+        // Both grass tiles allow grass on one side, rotated into all the sides
+        assert!(taught_by((grass, Orientation::East, grass)).len() == 2);
+        assert!(taught_by((grass, Orientation::North, north)) == [Coordinates::new(0, 0)]);
+        assert!(
+            taught_by((Tile::new(1, Orientation::East), Orientation::West, grass))
+                == [Coordinates::new(0, 1)]
+        );
+    }
+
+    #[test]
+    fn asymmetric_rules() {
+        let grass = Tile::new(0, Orientation::North);
+        let water = Tile::new(1, Orientation::North);
+        let mut alloweds = HashMap::<Tile, Allowed>::new();
+        // Grass allows water to its North, water allows nothing to its South
+        alloweds
+            .entry(grass)
+            .or_default()
+            .allowed
+            .insert(Orientation::North, HashSet::from([water]));
+        alloweds
+            .entry(water)
+            .or_default()
+            .allowed
+            .insert(Orientation::South, HashSet::new());
+        assert_eq!(
+            verify_symmetry(&alloweds),
+            vec![(grass, Orientation::North, water)]
+        );
+
+        // Allowed back
+        alloweds
+            .get_mut(&water)
+            .unwrap()
+            .allowed
+            .insert(Orientation::South, HashSet::from([grass]));
+        assert_eq!(verify_symmetry(&alloweds), Vec::new());
+    }
+
+    /// Rules between `count` prototypes, allowing each pair of tiles with a `density` probability
+    fn dense_rules(count: usize, density: f64, seed: u64) -> Rules {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let tiles: Vec<Tile> = (0..count)
+            .map(|index| Tile::new(index, Orientation::North))
+            .collect();
+        let mut alloweds: HashMap<Tile, Allowed> = tiles
+            .iter()
+            .map(|tile| (*tile, Allowed::default()))
+            .collect();
+        for tile in tiles.iter() {
+            for orientation in [Orientation::North, Orientation::East] {
+                for neighbour in tiles.iter() {
+                    if !rng.gen_bool(density) {
+                        continue;
+                    }
+                    let allowed = alloweds.get_mut(tile).unwrap();
+                    allowed
+                        .allowed
+                        .entry(orientation)
+                        .or_default()
+                        .insert(*neighbour);
+                    let allowed = alloweds.get_mut(neighbour).unwrap();
+                    let opposite = orientation.rotated(2);
+                    allowed.allowed.entry(opposite).or_default().insert(*tile);
+                }
+            }
+        }
+        Rules {
+            alloweds,
+            forbidden: HashSet::new(),
+            declared: HashSet::new(),
+            prototypes: (0..count)
+                .map(|index| prototype(index, Equivalences::QuarterTurn))
+                .collect(),
+            source: RuleSource::ExampleMap,
+        }
+    }
+
+    /// Performance baseline of the headless generation
+    ///
+    /// Run with `cargo test --release stress -- --ignored --nocapture`. The number of
+    /// prototypes is read from `STRESS_PROTOTYPES`, 50 by default.
+    #[test]
+    #[ignore]
+    fn stress() {
+        let count = std::env::var("STRESS_PROTOTYPES")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(50);
+        let rules = dense_rules(count, 0.7, 0);
+        // Every guess keeps a snapshot of the whole map, larger maps run out of memory
+        for size in [8, 16, 32] {
+            let start = std::time::Instant::now();
+            let solution = solve_with(&rules, size, size, 0, SolveOptions::default());
+            let elapsed = start.elapsed().as_secs_f64();
+            println!(
+                "{} prototypes, {}x{}: {:.0} cells/s, {} backtracks{}",
+                count,
+                size,
+                size,
+                (size * size) as f64 / elapsed,
+                solution.backtracks,
+                match solution.map {
+                    Ok(_) => "",
+                    Err(_) => ", failed",
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn solve_contradictory_rules() {
+        // Each tile only has a single neighbour, none can be in a corner
+        let rules = make_rules(
+            vec![
+                prototype(0, Equivalences::None),
+                prototype(1, Equivalences::None),
+            ],
+            &[&[Some(0)], &[Some(1)]],
+        );
+        assert_eq!(solve(&rules, 3, 3, 0), Err(Contradiction));
+    }
+
+    fn checkerboard_rules() -> Rules {
+        make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::QuarterTurn),
+            ],
+            &[
+                &[Some(0), Some(1), Some(0)],
+                &[Some(1), Some(0), Some(1)],
+                &[Some(0), Some(1), Some(0)],
+            ],
+        )
+    }
+
+    #[test]
+    fn unknown_prototypes_are_ignored() {
+        let rules = make_rules(
+            vec![prototype(0, Equivalences::QuarterTurn)],
+            &[&[Some(0), Some(5)], &[Some(0), Some(0)]],
+        );
+        assert!(rules.alloweds.keys().all(|tile| tile.prototype_index == 0));
+        assert!(rules
+            .alloweds
+            .values()
+            .flat_map(|allowed| allowed.allowed.values().flatten())
+            .all(|tile| tile.prototype_index == 0));
+    }
+
+    #[test]
+    fn forbidden_adjacencies() {
+        let mut rules = checkerboard_rules();
+        let zero = Tile::new(0, Orientation::North);
+        let one = Tile::new(1, Orientation::North);
+        rules.forbidden.insert((zero, Orientation::North, one));
+        let forbidden = rules.forbidden.clone();
+        forbid(&mut rules.alloweds, &forbidden);
+
+        assert!(!rules.alloweds[&zero].allowed[&Orientation::North].contains(&one));
+        assert!(!rules.alloweds[&one].allowed[&Orientation::South].contains(&zero));
+        assert!(rules.alloweds[&zero].allowed[&Orientation::East].contains(&one));
+        assert_eq!(verify_symmetry(&rules.alloweds), Vec::new());
+    }
+
+    #[test]
+    fn declared_adjacencies() {
+        let grass = Tile::new(0, Orientation::North);
+        let road = Tile::new(1, Orientation::North);
+        let mut rules = make_rules(
+            vec![
+                prototype(0, Equivalences::QuarterTurn),
+                prototype(1, Equivalences::None),
+            ],
+            &[&[Some(0), Some(0)], &[Some(0), Some(0)]],
+        );
+        rules.declared.insert((grass, Orientation::East, road));
+        let declared = rules.declared.clone();
+        declare(&mut rules.alloweds, &declared, &rules.prototypes, false);
+
+        assert!(rules.alloweds[&grass].allowed[&Orientation::East].contains(&road));
+        assert!(rules.alloweds[&road].allowed[&Orientation::West].contains(&grass));
+        // The learned adjacencies are kept, and the road is rotated like a learned tile
+        assert!(rules.alloweds[&grass].allowed[&Orientation::North].contains(&grass));
+        let turned_road = Tile::new(1, Orientation::East);
+        assert!(rules.alloweds[&grass].allowed[&Orientation::South].contains(&turned_road));
+        assert_eq!(verify_symmetry(&rules.alloweds), Vec::new());
+    }
+
+    #[test]
+    fn constraint_closure_is_a_dry_run() {
+        let rules = checkerboard_rules();
+        let tuning = Tuning::default();
+        let waves = make_waves(&[
+            &[&[0, 1], &[0, 1], &[0, 1]],
+            &[&[0, 1], &[0, 1], &[0, 1]],
+            &[&[0, 1], &[1], &[0, 1]],
+        ]);
+        let waves: HashMap<Coordinates, &HashSet<Tile>> =
+            waves.iter().map(|(c, tiles)| (*c, tiles)).collect();
+
+        // The checkerboard spreads to every undecided cell
+        let closure = constraint_closure(
+            &rules,
+            &tuning,
+            &waves,
+            Coordinates::new(0, 0),
+            Tile::new(0, Orientation::North),
+        );
+        let expected: HashSet<Coordinates> = waves
+            .iter()
+            .filter(|(coordinates, tiles)| {
+                tiles.len() > 1 && **coordinates != Coordinates::new(0, 0)
+            })
+            .map(|(coordinates, _)| *coordinates)
+            .collect();
+        assert_eq!(closure, expected);
+        assert_eq!(waves[&Coordinates::new(1, 1)].len(), 2);
+    }
+
+    /// Waves of a map, indexed by `[x][y]`, with the given candidate prototypes
+    fn make_waves(map: &[&[&[usize]]]) -> HashMap<Coordinates, HashSet<Tile>> {
+        let mut waves = HashMap::new();
+        for (x, line) in map.iter().enumerate() {
+            for (y, candidates) in line.iter().enumerate() {
+                let tiles = candidates
+                    .iter()
+                    .map(|index| Tile::new(*index, Orientation::North))
+                    .collect();
+                waves.insert(Coordinates::new(x as i32, y as i32), tiles);
+            }
+        }
+        waves
+    }
+
+    #[test]
+    fn legal_placements() {
+        let rules = checkerboard_rules();
+        let tuning = Tuning::default();
+        let waves = make_waves(&[
+            &[&[0, 1], &[1], &[0, 1]],
+            &[&[1], &[0, 1], &[0, 1]],
+            &[&[0, 1], &[0, 1], &[0, 1]],
+        ]);
+        let waves = waves.iter().map(|(c, tiles)| (*c, tiles)).collect();
+        let center = Coordinates::new(1, 1);
+
+        // Two of its neighbours are already 1
+        assert!(is_legal(
+            &rules,
+            &tuning,
+            &waves,
+            center,
+            Tile::new(0, Orientation::North)
+        ));
+        assert!(!is_legal(
+            &rules,
+            &tuning,
+            &waves,
+            center,
+            Tile::new(1, Orientation::North)
+        ));
+        // Unknown tile
+        assert!(!is_legal(
+            &rules,
+            &tuning,
+            &waves,
+            center,
+            Tile::new(2, Orientation::North)
+        ));
+    }
+
+    #[test]
+    fn legal_placements_on_edges() {
+        let rules = checkerboard_rules();
+        let waves = make_waves(&[
+            &[&[0, 1], &[0, 1], &[0, 1]],
+            &[&[0, 1], &[0, 1], &[0, 1]],
+            &[&[1], &[0, 1], &[0, 1]],
+        ]);
+        let waves = waves.iter().map(|(c, tiles)| (*c, tiles)).collect();
+        let corner = Coordinates::new(0, 0);
+        let tile = Tile::new(1, Orientation::North);
+
+        // Nothing past the edge of the map
+        let tuning = Tuning::default();
+        assert!(is_legal(&rules, &tuning, &waves, corner, tile));
+
+        // The opposite edge is a neighbour when wrapping around
+        let tuning = Tuning {
+            wrap: true,
+            map_width: 3,
+            map_height: 3,
+            ..default()
+        };
+        assert!(!is_legal(&rules, &tuning, &waves, corner, tile));
+        assert!(is_legal(
+            &rules,
+            &tuning,
+            &waves,
+            corner,
+            Tile::new(0, Orientation::North)
+        ));
+    }
 }